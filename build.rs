@@ -0,0 +1,26 @@
+//! Passes the locked versions of the clipboard and WebSocket crates on to
+//! `copy-sync doctor`.
+
+use std::fs;
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for (name, var) in [
+        ("arboard", "ARBOARD_VERSION"),
+        ("tungstenite", "TUNGSTENITE_VERSION"),
+    ] {
+        let package = format!("name = \"{}\"", name);
+        let version = lock
+            .split("[[package]]")
+            .filter(|block| block.lines().any(|line| line.trim() == package))
+            .find_map(|block| {
+                block
+                    .lines()
+                    .find_map(|line| line.trim().strip_prefix("version = "))
+                    .map(|version| version.trim_matches('"').to_string())
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        println!("cargo:rustc-env={}={}", var, version);
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One line of the log. Both ends of a sync hash the same bytes, so a sent
+/// entry and its received ones share `sha256`.
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// milliseconds since the Unix epoch
+    timestamp: u64,
+    direction: Direction,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    /// bytes of the content: the text, the RGBA pixels, or the file names and
+    /// contents as they travel
+    size: usize,
+    sha256: String,
+    /// only with `--audit-content`, and only for text, HTML and RTF
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+}
+
+/// `--audit-log`: a JSON line per item sent or received, appended to a file.
+pub struct AuditLog {
+    file: Mutex<File>,
+    with_content: bool,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, with_content: bool) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            with_content,
+        })
+    }
+
+    pub fn record(&self, direction: Direction, kind: &str, content: &[u8]) {
+        let text = match kind {
+            "text" | "html" | "rtf" if self.with_content => std::str::from_utf8(content).ok(),
+            _ => None,
+        };
+        let entry = Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            direction,
+            kind,
+            size: content.len(),
+            sha256: Sha256::digest(content)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            content: text,
+        };
+        let mut line = serde_json::to_vec(&entry).unwrap();
+        line.push(b'\n');
+        // one write per line keeps lines whole when another process appends too
+        if let Err(err) = self.file.lock().unwrap().write_all(&line) {
+            warn!("write audit log error: {:?}", err);
+        }
+    }
+}
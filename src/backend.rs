@@ -0,0 +1,509 @@
+use std::{
+    borrow::Cow,
+    fmt, fs,
+    future::Future,
+    io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::SystemTime,
+};
+
+use arboard::{Clipboard, Error, ImageData};
+use futures_channel::oneshot;
+use serde::{Deserialize, Deserializer};
+
+use crate::codec::{decode_png, encode_png};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const RTF_SIGNATURE: &[u8] = b"{\\rtf";
+
+/// What the client treats as the clipboard.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub enum ClipboardBackend {
+    /// the desktop clipboard
+    #[default]
+    System,
+    /// a file holding UTF-8 text, RTF or a PNG image, for machines without a
+    /// desktop
+    File(PathBuf),
+    /// memory only the embedding program sees, never the OS clipboard
+    Memory(MemoryClipboard),
+}
+
+impl FromStr for ClipboardBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            None if value == "system" => Ok(ClipboardBackend::System),
+            Some(("file", path)) if !path.is_empty() => {
+                Ok(ClipboardBackend::File(PathBuf::from(path)))
+            }
+            _ => Err("expected system or file:<path>".to_string()),
+        }
+    }
+}
+
+impl ClipboardBackend {
+    /// Whether [`Board::set_rtf`] can succeed, so a receiver knows to fall
+    /// back to the plain text instead.
+    pub fn holds_rtf(&self) -> bool {
+        matches!(self, ClipboardBackend::File(_))
+    }
+
+    /// Whether [`Board::set_text_bytes`] can succeed, or the text has to go
+    /// on the clipboard as UTF-8.
+    pub fn holds_raw_text(&self) -> bool {
+        !matches!(self, ClipboardBackend::System)
+    }
+}
+
+impl fmt::Display for ClipboardBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClipboardBackend::System => write!(f, "system"),
+            ClipboardBackend::File(path) => write!(f, "file:{}", path.display()),
+            ClipboardBackend::Memory(_) => write!(f, "memory"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ClipboardBackend {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn unknown(err: io::Error) -> Error {
+    Error::Unknown {
+        description: err.to_string(),
+    }
+}
+
+/// A file standing in for the clipboard. It is only re-read once its mtime
+/// changes.
+pub struct FileBoard {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    content: Vec<u8>,
+}
+
+impl FileBoard {
+    fn content(&mut self) -> Result<&[u8], Error> {
+        let modified = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.modified = None;
+                self.content.clear();
+                return Ok(&self.content);
+            }
+            Err(err) => return Err(unknown(err)),
+        };
+        if modified.is_none() || modified != self.modified {
+            self.content = fs::read(&self.path).map_err(unknown)?;
+            self.modified = modified;
+        }
+        Ok(&self.content)
+    }
+
+    fn write(&mut self, content: Vec<u8>) -> Result<(), Error> {
+        fs::write(&self.path, &content).map_err(unknown)?;
+        self.modified = fs::metadata(&self.path)
+            .ok()
+            .and_then(|metadata| metadata.modified().ok());
+        self.content = content;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+enum Content {
+    Text(String),
+    /// text that isn't valid UTF-8
+    RawText(Vec<u8>),
+    Image(ImageData<'static>),
+    /// fails every read, like a system clipboard that went away
+    #[cfg(test)]
+    Unreadable,
+}
+
+/// A clipboard held in memory, for embedding the client where it shouldn't
+/// touch the OS clipboard. Clones share the content, so the embedding program
+/// keeps one to see what peers sent and to copy things itself:
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use copy_sync::backend::{ClipboardBackend, MemoryClipboard};
+///
+/// let clipboard = MemoryClipboard::default();
+/// let client = copy_sync::Client::builder()
+///     .addr("ws://host:5120")
+///     .clipboard_backend(ClipboardBackend::Memory(clipboard.clone()))
+///     .spawn()?;
+/// clipboard.set_text("sent to every peer");
+/// // ...
+/// println!("{:?}", clipboard.text());
+/// client.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryClipboard(Arc<Mutex<Option<Content>>>);
+
+impl PartialEq for MemoryClipboard {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MemoryClipboard {}
+
+impl MemoryClipboard {
+    fn content(&self) -> Option<Content> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn put(&self, content: Option<Content>) {
+        *self.0.lock().unwrap() = content;
+    }
+
+    pub fn text(&self) -> Option<String> {
+        match self.content()? {
+            Content::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn set_text(&self, text: impl Into<String>) {
+        self.put(Some(Content::Text(text.into())));
+    }
+
+    pub fn image(&self) -> Option<ImageData<'static>> {
+        match self.content()? {
+            Content::Image(image) => Some(image),
+            _ => None,
+        }
+    }
+
+    /// `bytes` are RGBA, 4 per pixel.
+    pub fn set_image(&self, image: ImageData<'static>) {
+        self.put(Some(Content::Image(image)));
+    }
+
+    pub fn clear(&self) {
+        self.put(None);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_unreadable(&self) {
+        self.put(Some(Content::Unreadable));
+    }
+}
+
+/// The clipboard behind `--clipboard-backend`, with the subset of the
+/// `arboard` API the client uses.
+pub enum Board {
+    System(Clipboard),
+    File(FileBoard),
+    Memory(MemoryClipboard),
+}
+
+impl Board {
+    pub fn open(backend: &ClipboardBackend) -> Result<Board, Error> {
+        match backend {
+            ClipboardBackend::System => Clipboard::new().map(Board::System),
+            ClipboardBackend::File(path) => Ok(Board::File(FileBoard {
+                path: path.clone(),
+                modified: None,
+                content: Vec::new(),
+            })),
+            ClipboardBackend::Memory(memory) => Ok(Board::Memory(memory.clone())),
+        }
+    }
+
+    pub fn get_text(&mut self) -> Result<String, Error> {
+        match self {
+            Board::System(clipboard) => clipboard.get_text(),
+            Board::File(file) => {
+                let content = file.content()?;
+                if content.starts_with(PNG_SIGNATURE) {
+                    return Err(Error::ContentNotAvailable);
+                }
+                String::from_utf8(content.to_vec()).map_err(|_| Error::ConversionFailure)
+            }
+            Board::Memory(memory) => match memory.content() {
+                Some(Content::Text(text)) => Ok(text),
+                Some(Content::RawText(_)) => Err(Error::ConversionFailure),
+                #[cfg(test)]
+                Some(Content::Unreadable) => Err(Error::Unknown {
+                    description: "unreadable".to_string(),
+                }),
+                _ => Err(Error::ContentNotAvailable),
+            },
+        }
+    }
+
+    pub fn get_image(&mut self) -> Result<ImageData<'static>, Error> {
+        match self {
+            Board::System(clipboard) => clipboard.get_image(),
+            Board::File(file) => {
+                let content = file.content()?;
+                if !content.starts_with(PNG_SIGNATURE) {
+                    return Err(Error::ContentNotAvailable);
+                }
+                let (width, height, bytes) = decode_png(content).ok_or(Error::ConversionFailure)?;
+                Ok(ImageData {
+                    width,
+                    height,
+                    bytes: Cow::from(bytes),
+                })
+            }
+            Board::Memory(memory) => memory.image().ok_or(Error::ContentNotAvailable),
+        }
+    }
+
+    /// Whether a password manager marked the content as a secret, which only
+    /// the system clipboard can carry.
+    pub fn is_concealed(&self) -> bool {
+        matches!(self, Board::System(_)) && crate::conceal::is_concealed()
+    }
+
+    /// The PRIMARY selection, which only the system clipboard has, and only
+    /// on X11 and Wayland.
+    pub fn get_primary_text(&mut self) -> Result<String, Error> {
+        match self {
+            #[cfg(all(
+                unix,
+                not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+            ))]
+            Board::System(clipboard) => {
+                use arboard::{GetExtLinux, LinuxClipboardKind};
+                clipboard
+                    .get()
+                    .clipboard(LinuxClipboardKind::Primary)
+                    .text()
+            }
+            _ => Err(Error::ClipboardNotSupported),
+        }
+    }
+
+    pub fn set_primary_text(&mut self, text: &str) -> Result<(), Error> {
+        match self {
+            #[cfg(all(
+                unix,
+                not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+            ))]
+            Board::System(clipboard) => {
+                use arboard::{LinuxClipboardKind, SetExtLinux};
+                clipboard
+                    .set()
+                    .clipboard(LinuxClipboardKind::Primary)
+                    .text(text)
+            }
+            _ => Err(Error::ClipboardNotSupported),
+        }
+    }
+
+    /// Text that isn't valid UTF-8, which only the file backend can hold.
+    pub fn get_text_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        match self {
+            Board::System(_) => Err(Error::ContentNotAvailable),
+            Board::File(file) => {
+                let content = file.content()?;
+                if content.is_empty() || content.starts_with(PNG_SIGNATURE) {
+                    return Err(Error::ContentNotAvailable);
+                }
+                Ok(content.to_vec())
+            }
+            Board::Memory(memory) => match memory.content() {
+                Some(Content::RawText(bytes)) => Ok(bytes),
+                _ => Err(Error::ContentNotAvailable),
+            },
+        }
+    }
+
+    pub fn get_html(&mut self) -> Result<String, Error> {
+        match self {
+            Board::System(clipboard) => clipboard.get().html(),
+            Board::File(_) | Board::Memory(_) => Err(Error::ContentNotAvailable),
+        }
+    }
+
+    /// arboard has no RTF support, so only the file backend yields any.
+    pub fn get_rtf(&mut self) -> Result<String, Error> {
+        match self {
+            Board::File(file) => {
+                let content = file.content()?;
+                if !content.starts_with(RTF_SIGNATURE) {
+                    return Err(Error::ContentNotAvailable);
+                }
+                String::from_utf8(content.to_vec()).map_err(|_| Error::ConversionFailure)
+            }
+            Board::System(_) | Board::Memory(_) => Err(Error::ContentNotAvailable),
+        }
+    }
+
+    pub fn get_file_list(&mut self) -> Result<Vec<PathBuf>, Error> {
+        match self {
+            Board::System(clipboard) => clipboard.get().file_list(),
+            Board::File(_) | Board::Memory(_) => Err(Error::ContentNotAvailable),
+        }
+    }
+
+    pub fn set_text(&mut self, text: &str) -> Result<(), Error> {
+        match self {
+            Board::System(clipboard) => clipboard.set_text(text),
+            Board::File(file) => file.write(text.as_bytes().to_vec()),
+            Board::Memory(memory) => {
+                memory.set_text(text);
+                Ok(())
+            }
+        }
+    }
+
+    /// The system clipboard only holds UTF-8 text, it fails with
+    /// `ConversionFailure`.
+    pub fn set_text_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        match self {
+            Board::System(_) => Err(Error::ConversionFailure),
+            Board::File(file) => file.write(bytes.to_vec()),
+            Board::Memory(memory) => {
+                memory.put(Some(Content::RawText(bytes.to_vec())));
+                Ok(())
+            }
+        }
+    }
+
+    /// The file and memory backends can't hold HTML, they fail with
+    /// `ConversionFailure`.
+    pub fn set_html(&mut self, html: &str, alt_text: Option<&str>) -> Result<(), Error> {
+        match self {
+            Board::System(clipboard) => clipboard.set_html(html, alt_text),
+            Board::File(_) | Board::Memory(_) => Err(Error::ConversionFailure),
+        }
+    }
+
+    /// Only the file backend can hold RTF, the others fail with
+    /// `ConversionFailure`.
+    pub fn set_rtf(&mut self, rtf: &str) -> Result<(), Error> {
+        match self {
+            Board::File(file) => file.write(rtf.as_bytes().to_vec()),
+            Board::System(_) | Board::Memory(_) => Err(Error::ConversionFailure),
+        }
+    }
+
+    pub fn set_image(&mut self, image: ImageData) -> Result<(), Error> {
+        match self {
+            Board::System(clipboard) => clipboard.set_image(image),
+            Board::File(file) => match encode_png(image.width, image.height, &image.bytes) {
+                Ok(png) => file.write(png),
+                Err(_) => Err(Error::ConversionFailure),
+            },
+            Board::Memory(memory) => {
+                memory.set_image(image.to_owned_img());
+                Ok(())
+            }
+        }
+    }
+
+    /// The file and memory backends can't hold a file list, they fail with
+    /// `ConversionFailure`.
+    pub fn set_file_list(&mut self, paths: &[PathBuf]) -> Result<(), Error> {
+        match self {
+            Board::System(clipboard) => clipboard.set().file_list(paths),
+            Board::File(_) | Board::Memory(_) => Err(Error::ConversionFailure),
+        }
+    }
+
+    pub fn clear(&mut self) -> Result<(), Error> {
+        match self {
+            Board::System(clipboard) => clipboard.clear(),
+            Board::File(file) => file.write(Vec::new()),
+            Board::Memory(memory) => {
+                memory.clear();
+                Ok(())
+            }
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce(Result<&mut Board, Error>) + Send>;
+
+enum Request {
+    Run(Job),
+    /// drop the handle so the next job opens the clipboard again
+    Reopen,
+}
+
+/// The one thread that opens and uses the clipboard. Jobs run in the order
+/// they were sent, so reads and writes never overlap, and macOS, which wants
+/// the pasteboard used from a single thread, gets every call from the same
+/// one. The thread ends with its last handle.
+#[derive(Clone)]
+pub struct ClipboardThread {
+    requests: mpsc::Sender<Request>,
+}
+
+impl ClipboardThread {
+    pub fn spawn(backend: ClipboardBackend) -> ClipboardThread {
+        let (requests, queue) = mpsc::channel();
+        thread::Builder::new()
+            .name("clipboard".to_string())
+            .spawn(move || {
+                let mut board = None;
+                for request in queue {
+                    match request {
+                        Request::Run(job) => {
+                            let opened = match board.take() {
+                                Some(opened) => Ok(opened),
+                                None => Board::open(&backend),
+                            };
+                            match opened {
+                                Ok(mut opened) => {
+                                    job(Ok(&mut opened));
+                                    board = Some(opened);
+                                }
+                                Err(err) => job(Err(err)),
+                            }
+                        }
+                        Request::Reopen => board = None,
+                    }
+                }
+            })
+            .expect("Failed to start the clipboard thread");
+        ClipboardThread { requests }
+    }
+
+    /// Queues `job` on the clipboard thread right away, behind the jobs
+    /// queued before it. The future resolves to what the job returns, or to
+    /// the error opening the clipboard, without blocking the caller's thread.
+    pub fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut Board) -> T + Send + 'static,
+    ) -> impl Future<Output = Result<T, Error>> {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |board| {
+            let _ = reply.send(board.map(job));
+        });
+        let queued = self.requests.send(Request::Run(job)).is_ok();
+        async move {
+            let stopped = || Error::Unknown {
+                description: "clipboard thread stopped".to_string(),
+            };
+            if !queued {
+                return Err(stopped());
+            }
+            result.await.map_err(|_| stopped())?
+        }
+    }
+
+    /// Opens the clipboard anew for the next job, after a read failed in a
+    /// way that may have broken the handle.
+    pub fn reopen(&self) {
+        let _ = self.requests.send(Request::Reopen);
+    }
+}
@@ -0,0 +1,33 @@
+use rand::Rng;
+use tokio::time::Duration;
+
+/// Exponential reconnect delay with jitter, doubling from `initial` up to `max`.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        let initial = initial.min(max);
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    /// Returns the delay to wait before the next attempt and advances the backoff.
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.current;
+        self.current = (self.current * 2).min(self.max);
+        // up to 20% random jitter so clients don't reconnect in lockstep
+        let jitter = rand::thread_rng().gen_range(0.0..0.2);
+        base.mul_f64(1.0 + jitter).min(self.max)
+    }
+}
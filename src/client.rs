@@ -1,232 +1,3986 @@
-use arboard::{Clipboard, ImageData};
-use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
-use futures_channel::mpsc::UnboundedSender;
-use futures_util::{future::select, pin_mut, StreamExt};
-use serde::{Deserialize, Serialize};
-use std::io::prelude::*;
+use arboard::ImageData;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::ValueEnum;
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_util::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
 use std::{
     borrow::Cow,
-    sync::{Arc, Mutex},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    fmt,
+    fs::{self, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::net::TcpStream;
 use tokio::spawn;
-use tokio_tungstenite::{connect_async_with_config, MaybeTlsStream, WebSocketStream};
-use tungstenite::Message;
+use tokio::sync::{watch, Notify};
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+use tracing::{debug, error, info, warn};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig};
+use tungstenite::{http::StatusCode, Message};
 use ulid::Ulid;
+use url::Url;
 
-use crate::config::{RETRY_CONNECT_INTERVAL_IN_SECONDS, WEB_SOCKET_CONFIG};
-use crate::notify::notify;
+use crate::audit::{AuditLog, Direction};
+use crate::backend::{Board, ClipboardBackend, ClipboardThread};
+use crate::backoff::Backoff;
+use crate::codec::{
+    compress, decode_png, decompress, encode_png, Compression, ImageCodec,
+    DEFAULT_COMPRESSION_LEVEL,
+};
+use crate::config::{
+    default_download_dir, default_history_socket, default_inbox_socket, default_name,
+    web_socket_config, ACK_TIMEOUT_IN_SECONDS, DEFAULT_CONNECT_TIMEOUT_IN_SECONDS,
+    DEFAULT_EXCLUDE_PATTERNS, DEFAULT_HEARTBEAT_TIMEOUT_IN_SECONDS, DEFAULT_HISTORY_SIZE,
+    DEFAULT_IMAGE_NAME, DEFAULT_POLL_INTERVAL_IN_MILLISECONDS, HANDSHAKE_TIMEOUT_IN_SECONDS,
+    IMAGE_CHUNK_SIZE_IN_BYTES, IMAGE_CHUNK_TIMEOUT_IN_SECONDS, IMAGE_HEADER_ROOM_IN_BYTES,
+    INBOX_SIZE, INITIAL_RETRY_CONNECT_INTERVAL_IN_MILLISECONDS, MAX_IMAGE_CHUNKS,
+    MAX_IMAGE_SIZE_IN_BYTES, MAX_MESSAGE_SIZE_IN_BYTES, RETRY_CONNECT_INTERVAL_IN_SECONDS,
+    SET_CLIPBOARD_ATTEMPTS, SET_CLIPBOARD_RETRY_INTERVAL_IN_MILLISECONDS,
+    SHUTDOWN_TIMEOUT_IN_SECONDS,
+};
+use crate::control;
+use crate::crypto::{Cipher, Signer};
+use crate::history::{self, History, SharedHistory};
+use crate::inbox::{self, Inbox, Received, SharedInbox};
+use crate::longpoll;
+use crate::notify::{notify, preview};
+use crate::protocol::{
+    heartbeat, is_compatible, pack_frame, sign_frame, unpack_frame, verify_frame, with_seq, Ack,
+    ClipboardMessage, ClipboardMessageChunk, ClipboardMessageFile, ClipboardMessageFiles,
+    ClipboardMessageHtml, ClipboardMessageImage, ClipboardMessageImageText,
+    ClipboardMessagePayload, ClipboardMessageRawText, ClipboardMessageRtf, ClipboardMessageSealed,
+    ClipboardMessageText, Handshake, HandshakeAck, Role, Selection, TextEncoding, WireFormat,
+    PROTOCOL_MAJOR, PROTOCOL_VERSION,
+};
+use crate::runtime;
+use crate::shutdown::{Handle, Shutdown};
+use crate::tls::load_connector;
+use crate::transport::{
+    encode_frame, framed, read_frame, tcp_target, with_wire_format, FrameSink, FrameStream,
+};
+use crate::watch::{watch_clipboard, WatchMode};
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// only push the local clipboard
+    Send,
+    /// only apply updates from peers
+    Receive,
+    /// push and apply
+    Both,
+}
+
+impl SyncMode {
+    fn sends(self) -> bool {
+        !matches!(self, SyncMode::Receive)
+    }
+
+    fn receives(self) -> bool {
+        !matches!(self, SyncMode::Send)
+    }
+
+    /// What to tell the server, which then holds the client to it too.
+    fn role(self) -> Role {
+        match self {
+            SyncMode::Send => Role::Source,
+            SyncMode::Receive => Role::Sink,
+            SyncMode::Both => Role::Peer,
+        }
+    }
+}
+
+/// The selections `--selection` syncs, each applied to the same one on the
+/// receiving side. Only X11 and Wayland have a PRIMARY selection.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Selections {
+    /// what copy and paste use
+    #[default]
+    Clipboard,
+    /// the text last selected, plain text only
+    Primary,
+    Both,
+}
+
+impl Selections {
+    fn clipboard(self) -> bool {
+        !matches!(self, Selections::Primary)
+    }
+
+    fn primary(self) -> bool {
+        !matches!(self, Selections::Clipboard)
+    }
+}
+
+/// What happens to text over `--max-text-bytes`.
+#[derive(Clone, Copy, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextPolicy {
+    /// don't send it
+    #[default]
+    Skip,
+    /// send as much as fits, cut at a character boundary
+    Truncate,
+}
+
+const IMAGE_NAME_FIELDS: [&str; 4] = ["timestamp", "width", "height", "name"];
+
+/// `--image-name`: the file a received image is saved as, with `{timestamp}`,
+/// `{width}`, `{height}` and `{name}`, the sender's `--name`, filled in.
+#[derive(Clone)]
+pub struct ImageName(String);
+
+impl Default for ImageName {
+    fn default() -> Self {
+        ImageName(DEFAULT_IMAGE_NAME.to_string())
+    }
+}
+
+impl FromStr for ImageName {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.is_empty() || value.contains(['/', '\\']) {
+            return Err("expected a file name, not a path".to_string());
+        }
+        let mut rest = value;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                return Err("unclosed {".to_string());
+            };
+            let field = &rest[start + 1..start + end];
+            if !IMAGE_NAME_FIELDS.contains(&field) {
+                return Err(format!(
+                    "unknown field {{{}}}, expected {{timestamp}}, {{width}}, {{height}} or {{name}}",
+                    field
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(ImageName(value.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl ImageName {
+    fn render(&self, timestamp: u64, image: &ImageData, name: &str) -> String {
+        // a peer's name must not add path separators or dots to the file name
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.0
+            .replace("{timestamp}", &timestamp.to_string())
+            .replace("{width}", &image.width.to_string())
+            .replace("{height}", &image.height.to_string())
+            .replace("{name}", &name)
+    }
+}
+
+fn default_excludes() -> &'static [Regex] {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        DEFAULT_EXCLUDE_PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect()
+    })
+}
+
+/// The `--exclude-pattern` that `text` matches, if any.
+fn excluded_by<'a>(text: &str, options: &'a ClientOptions) -> Option<&'a Regex> {
+    let defaults = if options.default_excludes {
+        default_excludes()
+    } else {
+        &[]
+    };
+    options
+        .exclude_patterns
+        .iter()
+        .chain(defaults)
+        .find(|pattern| pattern.is_match(text))
+}
+
+/// The longest prefix of `text` within `max_bytes` that doesn't split a
+/// character.
+fn truncate(text: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[derive(Clone)]
+pub struct ClientOptions {
+    pub poll_interval: u64,
+    pub watch_mode: WatchMode,
+    pub token: Option<String>,
+    pub key: Option<String>,
+    /// sign sent frames with this key, and apply only frames signed with it
+    pub sign_key: Option<String>,
+    pub max_retry_interval: u64,
+    /// failed connects in a row after which [`start`] gives up, `None` to
+    /// keep trying
+    pub max_retries: Option<u32>,
+    /// seconds a connect may take, TLS and WebSocket handshakes included
+    pub connect_timeout: u64,
+    pub download_dir: PathBuf,
+    /// also write received images to `save_dir` as PNG
+    pub save_images: bool,
+    pub save_dir: PathBuf,
+    pub image_name: ImageName,
+    /// send an image together with the text the clipboard offers along with it
+    pub image_with_text: bool,
+    /// seconds in which an image already sent isn't sent again, even after
+    /// something else was copied in between
+    pub image_dedup_window: Option<u64>,
+    pub mode: SyncMode,
+    pub selection: Selections,
+    pub max_image_bytes: Option<usize>,
+    pub max_text_bytes: Option<usize>,
+    pub text_policy: TextPolicy,
+    pub text_only: bool,
+    /// skip what a password manager marked as concealed
+    pub password_strip: bool,
+    pub room: Option<String>,
+    pub heartbeat_timeout: u64,
+    pub image_codec: ImageCodec,
+    pub history_size: usize,
+    pub history_socket: PathBuf,
+    /// append a JSON line per item sent or received to this file
+    pub audit_log: Option<PathBuf>,
+    /// put text, HTML and RTF themselves in the audit log, not only their hash
+    pub audit_content: bool,
+    pub notify: bool,
+    pub notify_text: bool,
+    /// notify when the connection to the server comes up or goes down
+    pub notify_connection: bool,
+    pub debounce: u64,
+    /// ignore changes that only add or remove leading or trailing whitespace
+    pub normalize_text: bool,
+    /// text matching any of these is not sent
+    pub exclude_patterns: Vec<Regex>,
+    /// also skip [`DEFAULT_EXCLUDE_PATTERNS`]
+    pub default_excludes: bool,
+    pub clipboard_backend: ClipboardBackend,
+    pub compress: Compression,
+    /// 0 (fastest) to 9 (smallest)
+    pub compression: u32,
+    /// asked for in the handshake, JSON unless the server agrees
+    pub wire_format: WireFormat,
+    /// keep received items in the inbox instead of putting them on the clipboard
+    pub read_only_clipboard: bool,
+    pub inbox_socket: PathBuf,
+    /// where `copy-sync pause` and `copy-sync resume` reach the client, `None`
+    /// to not listen for them
+    pub control_socket: Option<PathBuf>,
+    /// average upload cap in kilobits per second, sends are delayed to stay under it
+    pub max_upload_kbps: Option<u64>,
+    /// times a frame the server hasn't acknowledged is sent again, `None` to
+    /// not number frames at all
+    pub retransmit: Option<u32>,
+    /// PEM CA that `wss://` servers must chain to, instead of the web roots
+    pub ca_cert: Option<PathBuf>,
+    /// SHA-256 fingerprint the `wss://` server certificate must have
+    pub pin_fingerprint: Option<String>,
+    /// shown in the notifications of peers receiving from this client
+    pub name: Option<String>,
+    pub web_socket: WebSocketConfig,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            poll_interval: DEFAULT_POLL_INTERVAL_IN_MILLISECONDS,
+            watch_mode: WatchMode::Poll,
+            token: None,
+            key: None,
+            sign_key: None,
+            max_retry_interval: RETRY_CONNECT_INTERVAL_IN_SECONDS,
+            max_retries: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT_IN_SECONDS,
+            download_dir: default_download_dir(),
+            save_images: false,
+            save_dir: default_download_dir(),
+            image_name: ImageName::default(),
+            image_with_text: false,
+            image_dedup_window: None,
+            mode: SyncMode::Both,
+            selection: Selections::default(),
+            max_image_bytes: None,
+            max_text_bytes: None,
+            text_policy: TextPolicy::default(),
+            text_only: false,
+            password_strip: false,
+            room: None,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT_IN_SECONDS,
+            image_codec: ImageCodec::default(),
+            history_size: DEFAULT_HISTORY_SIZE,
+            history_socket: default_history_socket(),
+            audit_log: None,
+            audit_content: false,
+            notify: true,
+            notify_text: false,
+            notify_connection: false,
+            debounce: 0,
+            normalize_text: false,
+            exclude_patterns: Vec::new(),
+            default_excludes: true,
+            clipboard_backend: ClipboardBackend::System,
+            compress: Compression::default(),
+            compression: DEFAULT_COMPRESSION_LEVEL,
+            wire_format: WireFormat::Json,
+            read_only_clipboard: false,
+            inbox_socket: default_inbox_socket(),
+            control_socket: None,
+            max_upload_kbps: None,
+            retransmit: None,
+            ca_cert: None,
+            pin_fingerprint: None,
+            name: default_name(),
+            web_socket: web_socket_config(None, None, None, false),
+        }
+    }
+}
+
+/// Embeds the client in another program:
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// let client = copy_sync::Client::builder()
+///     .addr("ws://host:5120")
+///     .room("desk")
+///     .spawn()?;
+/// // ...
+/// client.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client;
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            addrs: Vec::new(),
+            options: ClientOptions::default(),
+            manual: None,
+            status: None,
+            traffic: None,
+        }
+    }
+}
+
+pub struct ClientBuilder {
+    addrs: Vec<String>,
+    options: ClientOptions,
+    manual: Option<Arc<Notify>>,
+    status: Option<watch::Sender<ConnectionState>>,
+    traffic: Option<Arc<Traffic>>,
+}
+
+impl ClientBuilder {
+    /// Adds a server, later ones are failed over to in order.
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addrs.push(addr.into());
+        self
+    }
+
+    pub fn room(mut self, room: impl Into<String>) -> Self {
+        self.options.room = Some(room.into());
+        self
+    }
+
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.options.token = Some(token.into());
+        self
+    }
+
+    /// Passphrase for end-to-end encryption.
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.options.key = Some(key.into());
+        self
+    }
+
+    /// Syncs `backend` instead of the system clipboard.
+    pub fn clipboard_backend(mut self, backend: ClipboardBackend) -> Self {
+        self.options.clipboard_backend = backend;
+        self
+    }
+
+    /// Sends the clipboard only when `trigger` is notified, instead of on
+    /// every change.
+    pub fn manual(mut self, trigger: Arc<Notify>) -> Self {
+        self.manual = Some(trigger);
+        self
+    }
+
+    /// Reports every [`ConnectionState`] change through `status`.
+    pub fn status(mut self, status: watch::Sender<ConnectionState>) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Counts the bytes sent and received in `traffic`.
+    pub fn traffic(mut self, traffic: Arc<Traffic>) -> Self {
+        self.traffic = Some(traffic);
+        self
+    }
+
+    /// Listens for `copy-sync pause` and `copy-sync resume` on `path`.
+    pub fn control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.control_socket = Some(path.into());
+        self
+    }
+
+    /// Replaces everything set so far except the addresses.
+    pub fn options(mut self, options: ClientOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Starts syncing on the current runtime until the returned handle is
+    /// shut down.
+    pub fn spawn(self) -> io::Result<Handle> {
+        if self.addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no server address",
+            ));
+        }
+        // fails here rather than inside the spawned task
+        load_connector(
+            self.options.ca_cert.as_deref(),
+            self.options.pin_fingerprint.as_deref(),
+        )?;
+        if let Some(path) = &self.options.audit_log {
+            AuditLog::open(path, self.options.audit_content)?;
+        }
+        let status = self
+            .status
+            .unwrap_or_else(|| watch::channel(ConnectionState::Connecting).0);
+        let traffic = self.traffic.unwrap_or_default();
+        Ok(Handle::spawn(move |shutdown| async move {
+            // giving up is logged, and shows as the handle finishing
+            let _ = start(
+                self.addrs,
+                self.options,
+                self.manual,
+                status,
+                traffic,
+                shutdown,
+            )
+            .await;
+        }))
+    }
+}
+
+#[derive(Default)]
+enum ClipboardCache<'a> {
+    Text(String),
+    /// text that isn't valid UTF-8
+    RawText(Vec<u8>),
+    /// the image and its `image_hash`, so an unchanged image is told apart
+    /// without comparing every byte
+    Image(ImageData<'a>, u64),
+    Files(Vec<PathBuf>),
+    Html(String),
+    Rtf(String),
+    /// marked as a secret by a password manager, never sent
+    Concealed,
+    #[default]
+    Empty,
+}
+
+/// What the clipboard held when a connection ended, picked up by the next
+/// one so an unchanged clipboard isn't sent again after every reconnect.
+#[derive(Default)]
+struct Synced {
+    cache: ClipboardCache<'static>,
+    timestamp: u64,
+    primary: Option<String>,
+}
+
+/// A frame sent with `--retransmit` that the server hasn't acknowledged yet.
+struct Unacked {
+    /// as it was queued, before it was numbered and signed
+    frame: Message,
+    resent: u32,
+    /// when it last went out, `None` while it waits in the queue
+    sent: Option<Instant>,
+}
+
+/// Frames waiting for the server's [`Ack`], by seq. Every connection of a
+/// client shares it, so one resends what the connection before it lost.
+#[derive(Default)]
+struct Pending {
+    next_seq: u64,
+    frames: BTreeMap<u64, Unacked>,
+}
+
+impl Pending {
+    /// Numbers `frame` and keeps it until it is acknowledged.
+    fn track(&mut self, frame: Message) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.frames.insert(
+            seq,
+            Unacked {
+                frame,
+                resent: 0,
+                sent: None,
+            },
+        );
+        seq
+    }
+
+    fn sent(&mut self, seq: u64) {
+        if let Some(unacked) = self.frames.get_mut(&seq) {
+            unacked.sent = Some(Instant::now());
+        }
+    }
+
+    fn acked(&mut self, seq: u64) {
+        self.frames.remove(&seq);
+    }
+
+    /// Frames unacknowledged for [`ACK_TIMEOUT_IN_SECONDS`] that are due to go
+    /// out again, dropping the ones already resent `retransmit` times.
+    fn due(&mut self, retransmit: u32) -> Vec<(u64, Message)> {
+        let timeout = Duration::from_secs(ACK_TIMEOUT_IN_SECONDS);
+        let mut due = Vec::new();
+        self.frames.retain(|&seq, unacked| {
+            if unacked.sent.is_none_or(|sent| sent.elapsed() < timeout) {
+                return true;
+            }
+            if unacked.resent >= retransmit {
+                warn!(
+                    "Frame {} still unacknowledged after {} resends, giving up",
+                    seq, retransmit
+                );
+                return false;
+            }
+            unacked.resent += 1;
+            unacked.sent = None;
+            debug!("Resending unacknowledged frame {}", seq);
+            due.push((seq, unacked.frame.clone()));
+            true
+        });
+        due
+    }
+}
+
+struct ClientState {
+    cache: ClipboardCache<'static>,
+    id: String,
+    /// when the clipboard content was copied, 0 if unknown
+    timestamp: u64,
+    cipher: Option<Arc<Cipher>>,
+    signer: Option<Arc<Signer>>,
+    /// hash of the last update received from a peer, used to avoid echoing it back
+    last_applied: Option<u64>,
+    history: SharedHistory,
+    inbox: SharedInbox,
+    audit: Option<Arc<AuditLog>>,
+    /// set by `copy-sync pause`, nothing is sent or applied while it is
+    paused: Arc<AtomicBool>,
+    /// images still waiting for some of their chunks, by sender and image id
+    partial: HashMap<(Option<u64>, u64), PartialImage>,
+    /// `image_hash` of the images sent lately, for `--image-dedup-window`
+    sent_images: Vec<(u64, Instant)>,
+    /// the PRIMARY selection as last sent or applied
+    primary: Option<String>,
+    /// why the clipboard couldn't be read last time, so a failure that
+    /// persists is only logged once
+    read_failure: Option<String>,
+    clipboard: ClipboardThread,
+    pending: Arc<Mutex<Pending>>,
+}
+
+/// What every connection of a client shares.
+#[derive(Clone)]
+struct Shared {
+    cipher: Option<Arc<Cipher>>,
+    signer: Option<Arc<Signer>>,
+    history: SharedHistory,
+    inbox: SharedInbox,
+    audit: Option<Arc<AuditLog>>,
+    paused: Arc<AtomicBool>,
+    traffic: Arc<Traffic>,
+    synced: Arc<Mutex<Synced>>,
+    clipboard: ClipboardThread,
+    pending: Arc<Mutex<Pending>>,
+    /// set once the user was told about a message over `--max-message-size`,
+    /// which the server replays on every reconnect when it is the latest
+    oversized: Arc<AtomicBool>,
+}
+
+impl Shared {
+    /// For a client that keeps no history or inbox.
+    fn standalone(options: &ClientOptions) -> Shared {
+        Shared {
+            cipher: options
+                .key
+                .as_deref()
+                .map(|key| Arc::new(Cipher::from_passphrase(key))),
+            signer: options
+                .sign_key
+                .as_deref()
+                .map(|key| Arc::new(Signer::new(key))),
+            history: Arc::new(Mutex::new(History::new(0))),
+            inbox: Arc::new(Mutex::new(Inbox::new(0))),
+            audit: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            traffic: Arc::new(Traffic::default()),
+            synced: Arc::default(),
+            clipboard: ClipboardThread::spawn(options.clipboard_backend.clone()),
+            pending: Arc::default(),
+            oversized: Arc::default(),
+        }
+    }
+}
+
+struct PartialImage {
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    size: usize,
+    started: Instant,
+}
+
+impl ClientState {
+    fn new(shared: Shared) -> Self {
+        let synced = std::mem::take(&mut *shared.synced.lock().unwrap());
+        ClientState {
+            cache: synced.cache,
+            id: generate_ulid(),
+            timestamp: synced.timestamp,
+            cipher: shared.cipher,
+            signer: shared.signer,
+            last_applied: None,
+            history: shared.history,
+            inbox: shared.inbox,
+            audit: shared.audit,
+            paused: shared.paused,
+            partial: HashMap::new(),
+            sent_images: Vec::new(),
+            primary: synced.primary,
+            read_failure: None,
+            clipboard: shared.clipboard,
+            pending: shared.pending,
+        }
+    }
+
+    /// Leaves the cache to the next connection.
+    fn hand_over(&mut self, synced: &Mutex<Synced>) {
+        *synced.lock().unwrap() = Synced {
+            cache: std::mem::take(&mut self.cache),
+            timestamp: self.timestamp,
+            primary: self.primary.take(),
+        };
+    }
+
+    fn audit(&self, direction: Direction, kind: &str, content: &[u8]) {
+        if let Some(audit) = &self.audit {
+            audit.record(direction, kind, content);
+        }
+    }
+
+    fn is_echo(&self, hash: u64) -> bool {
+        self.last_applied == Some(hash) || self.inbox.lock().unwrap().was_pasted(hash)
+    }
+
+    /// Whether an image with `hash` went out less than `window` ago,
+    /// forgetting the ones sent before that.
+    fn sent_lately(&mut self, hash: u64, window: Duration) -> bool {
+        self.sent_images.retain(|(_, sent)| sent.elapsed() < window);
+        self.sent_images.iter().any(|(sent, _)| *sent == hash)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Files a chunk, and once every chunk of its image is in, returns the
+    /// header and the joined body.
+    fn add_chunk(
+        &mut self,
+        header: ClipboardMessageImage,
+        chunk: ClipboardMessageChunk,
+        body: &[u8],
+    ) -> Option<(ClipboardMessageImage, Vec<u8>)> {
+        if chunk.total > MAX_IMAGE_CHUNKS || chunk.index >= chunk.total {
+            warn!(
+                "invalid image chunk {} of {}, skipping message",
+                chunk.index, chunk.total
+            );
+            return None;
+        }
+        let key = (header.peer, chunk.id);
+        let partial = self.partial.entry(key).or_insert_with(|| PartialImage {
+            parts: vec![None; chunk.total],
+            missing: chunk.total,
+            size: 0,
+            started: Instant::now(),
+        });
+        if partial.parts.len() != chunk.total {
+            warn!("image chunks disagree on their count, dropping the image");
+            self.partial.remove(&key);
+            return None;
+        }
+        if partial.parts[chunk.index].is_none() {
+            partial.parts[chunk.index] = Some(body.to_vec());
+            partial.missing -= 1;
+            partial.size += body.len();
+        }
+        if partial.size > MAX_IMAGE_SIZE_IN_BYTES {
+            warn!(
+                "chunked image exceeds the {} bytes limit, dropping it",
+                MAX_IMAGE_SIZE_IN_BYTES
+            );
+            self.partial.remove(&key);
+            return None;
+        }
+        if partial.missing > 0 {
+            return None;
+        }
+        let partial = self.partial.remove(&key)?;
+        let body = partial.parts.into_iter().flatten().flatten().collect();
+        Some((header, body))
+    }
+
+    /// Drops images whose chunks stopped arriving.
+    fn discard_stale_chunks(&mut self) {
+        let timeout = Duration::from_secs(IMAGE_CHUNK_TIMEOUT_IN_SECONDS);
+        self.partial.retain(|(peer, _), partial| {
+            let alive = partial.started.elapsed() < timeout;
+            if !alive {
+                warn!(
+                    "dropping image from peer {:?}, {} of {} chunks missing",
+                    peer,
+                    partial.missing,
+                    partial.parts.len()
+                );
+            }
+            alive
+        });
+    }
+
+    /// Records a local copy made just now and returns its timestamp.
+    fn touch(&mut self) -> u64 {
+        self.timestamp = now_in_millis();
+        self.timestamp
+    }
+
+    /// Whether an update copied at `timestamp` lost to what the clipboard
+    /// already holds. Updates without one always win.
+    fn is_outdated(&self, timestamp: Option<u64>) -> bool {
+        timestamp.is_some_and(|timestamp| timestamp < self.timestamp)
+    }
+}
+
+fn content_hash<T: Hash + ?Sized>(content: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn text_hash(text: &str) -> u64 {
+    content_hash(&("text", text))
+}
+
+fn raw_text_hash(bytes: &[u8]) -> u64 {
+    content_hash(&("raw text", bytes))
+}
+
+fn html_hash(html: &str) -> u64 {
+    content_hash(&("html", html))
+}
+
+fn rtf_hash(rtf: &str) -> u64 {
+    content_hash(&("rtf", rtf))
+}
+
+fn image_hash(image: &ImageData) -> u64 {
+    content_hash(&("image", image.width, image.height, &image.bytes[..]))
+}
+
+/// Whether `image` holds 4 bytes per pixel. arboard reads and writes
+/// straight-alpha RGBA on every platform, converting from BGRA or
+/// premultiplied alpha itself, so that is already the canonical layout on
+/// the wire; only a buffer of the wrong size needs catching.
+fn is_rgba(image: &ImageData) -> bool {
+    image
+        .width
+        .checked_mul(image.height)
+        .and_then(|pixels| pixels.checked_mul(4))
+        == Some(image.bytes.len())
+}
+
+/// A view of `image` borrowing its pixels, so handing it to the clipboard
+/// doesn't copy them. `set_with_retry` may call `set_image` several times.
+fn borrow_image<'a>(image: &'a ImageData) -> ImageData<'a> {
+    ImageData {
+        width: image.width,
+        height: image.height,
+        bytes: Cow::Borrowed(&image.bytes),
+    }
+}
+
+fn files_hash(paths: &[PathBuf]) -> u64 {
+    content_hash(&("files", paths))
+}
+
+impl ClipboardCache<'_> {
+    fn hash(&self) -> Option<u64> {
+        match self {
+            ClipboardCache::Text(text) => Some(text_hash(text)),
+            ClipboardCache::RawText(bytes) => Some(raw_text_hash(bytes)),
+            ClipboardCache::Image(_, hash) => Some(*hash),
+            ClipboardCache::Files(paths) => Some(files_hash(paths)),
+            ClipboardCache::Html(html) => Some(html_hash(html)),
+            ClipboardCache::Rtf(rtf) => Some(rtf_hash(rtf)),
+            ClipboardCache::Concealed | ClipboardCache::Empty => None,
+        }
+    }
+}
+
+/// Where a text frame came from, as far as the frame says.
+struct Origin {
+    peer: Option<u64>,
+    name: Option<String>,
+    timestamp: Option<u64>,
+    selection: Option<Selection>,
+}
+
+fn now_in_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn seal(payload: ClipboardMessagePayload, cipher: Option<&Cipher>) -> ClipboardMessagePayload {
+    match cipher {
+        Some(cipher) => {
+            let plaintext = serde_json::to_vec(&payload).unwrap();
+            ClipboardMessagePayload::Sealed(ClipboardMessageSealed {
+                data: BASE64.encode(cipher.encrypt(&plaintext)),
+            })
+        }
+        None => payload,
+    }
+}
+
+fn serialize_clipboard_message(
+    payload: ClipboardMessagePayload,
+    cipher: Option<&Cipher>,
+    timestamp: u64,
+) -> String {
+    let message = ClipboardMessage {
+        payload: seal(payload, cipher),
+        peer: None,
+        name: None,
+        timestamp: Some(timestamp),
+        seq: None,
+        selection: None,
+    };
+
+    serde_json::to_string(&message).unwrap()
+}
+
+#[derive(Debug)]
+enum DecodeError {
+    Malformed(serde_json::Error),
+    /// well-formed JSON we don't understand, most likely a payload added in a
+    /// newer protocol version
+    Unsupported(serde_json::Error),
+    Decrypt,
+    MissingKey,
+    Unencrypted,
+}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_data() {
+            DecodeError::Unsupported(err)
+        } else {
+            DecodeError::Malformed(err)
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Malformed(err) => write!(f, "malformed message: {}", err),
+            DecodeError::Unsupported(err) => write!(f, "unsupported message: {}", err),
+            DecodeError::Decrypt => write!(f, "failed to decrypt message"),
+            DecodeError::MissingKey => write!(f, "received encrypted message but no --key is set"),
+            DecodeError::Unencrypted => write!(f, "received unencrypted message"),
+        }
+    }
+}
+
+/// Decodes a text frame, unsealing it when end-to-end encryption is enabled.
+fn deserialize_clipboard_message(
+    text: &str,
+    cipher: Option<&Cipher>,
+) -> Result<(ClipboardMessagePayload, Origin), DecodeError> {
+    let message: ClipboardMessage = serde_json::from_str(text)?;
+    let origin = Origin {
+        peer: message.peer,
+        name: message.name,
+        timestamp: message.timestamp,
+        selection: message.selection,
+    };
+    match (message.payload, cipher) {
+        (ClipboardMessagePayload::Sealed(sealed), Some(cipher)) => {
+            let data = BASE64
+                .decode(sealed.data)
+                .map_err(|_| DecodeError::Decrypt)?;
+            let plaintext = cipher.decrypt(&data).map_err(|_| DecodeError::Decrypt)?;
+            Ok((serde_json::from_slice(&plaintext)?, origin))
+        }
+        (ClipboardMessagePayload::Sealed(_), None) => Err(DecodeError::MissingKey),
+        (_, Some(_)) => Err(DecodeError::Unencrypted),
+        (payload, None) => Ok((payload, origin)),
+    }
+}
+
+static NEXT_IMAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Splits an image into frames carrying at most `chunk_size` bytes of body
+/// each. An image that fits in one
+/// frame goes out unchunked, the way older peers expect it.
+///
+/// With a `cipher` the encoded pixels are sealed as a whole before they are
+/// split, under the same key as text and with a fresh nonce per image at the
+/// front of the body, so only the header is left readable.
+fn image_frames(
+    mut header: ClipboardMessageImage,
+    bytes: &[u8],
+    cipher: Option<&Cipher>,
+    chunk_size: usize,
+) -> Vec<Message> {
+    let body = match cipher {
+        Some(cipher) => cipher.encrypt(bytes),
+        None => bytes.to_vec(),
+    };
+    if body.len() <= chunk_size {
+        return vec![Message::Binary(pack_frame(&header, &body))];
+    }
+    let id = NEXT_IMAGE_ID.fetch_add(1, Ordering::Relaxed);
+    let total = body.len().div_ceil(chunk_size);
+    body.chunks(chunk_size)
+        .enumerate()
+        .map(|(index, chunk)| {
+            header.chunk = Some(ClipboardMessageChunk { id, index, total });
+            Message::Binary(pack_frame(&header, chunk))
+        })
+        .collect()
+}
+
+/// Unpacks and decrypts an image frame. Chunks are held back in `state`
+/// until the last one of their image arrives.
+fn parse_image_frame(
+    frame: &[u8],
+    state: &mut ClientState,
+) -> Option<(ClipboardMessageImage, Vec<u8>)> {
+    let Some((header, body)) = unpack_frame::<ClipboardMessageImage>(frame) else {
+        warn!("malformed image frame, skipping message");
+        return None;
+    };
+    let (header, body) = match header.chunk {
+        Some(chunk) => state.add_chunk(header, chunk, body)?,
+        None => (header, body.to_vec()),
+    };
+    match state.cipher.as_deref() {
+        Some(cipher) => match cipher.decrypt(&body) {
+            Ok(body) => Some((header, body)),
+            Err(_) => {
+                warn!("decrypt image error, skipping message");
+                None
+            }
+        },
+        None => Some((header, body)),
+    }
+}
+
+fn send_frame(sender: &UnboundedSender<Message>, frame: Message, kind: &str) -> bool {
+    let len = frame.len();
+    match sender.unbounded_send(frame) {
+        Ok(_) => {
+            debug!("Sent {} ({} bytes)", kind, len);
+            true
+        }
+        Err(err) => {
+            warn!("send error: {:?}", err);
+            false
+        }
+    }
+}
+
+/// `--max-message-size`, which the server is expected to share.
+fn max_message_size(options: &ClientOptions) -> usize {
+    options
+        .web_socket
+        .max_message_size
+        .unwrap_or(MAX_MESSAGE_SIZE_IN_BYTES)
+}
+
+/// Whether `frame` is within [`max_message_size`]. One past it would make
+/// the server drop the connection, so it is reported instead of sent.
+fn fits(frame: &Message, kind: &str, options: &ClientOptions) -> bool {
+    let max = max_message_size(options);
+    if frame.len() <= max {
+        return true;
+    }
+    let detail = format!("{} bytes exceeds the {} bytes limit", frame.len(), max);
+    warn!("skip {}: {}", kind, detail);
+    alert(options, "Clipboard too large for copy-sync", &detail);
+    false
+}
+
+fn read_files(
+    paths: &[PathBuf],
+    compression: Compression,
+    level: u32,
+) -> io::Result<Vec<ClipboardMessageFile>> {
+    paths
+        .iter()
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_string_lossy().into_owned();
+            Some((name, path))
+        })
+        .map(|(name, path)| {
+            let content = BASE64.encode(compress(&fs::read(path)?, compression, level));
+            Ok(ClipboardMessageFile { name, content })
+        })
+        .collect()
+}
+
+/// The file names and contents of a files message, what the audit log
+/// hashes for it.
+fn files_content(files: &[ClipboardMessageFile]) -> Vec<u8> {
+    let mut content = Vec::new();
+    for file in files {
+        content.extend_from_slice(file.name.as_bytes());
+        content.push(0);
+        content.extend_from_slice(file.content.as_bytes());
+        content.push(0);
+    }
+    content
+}
+
+fn save_files(
+    files: Vec<ClipboardMessageFile>,
+    compression: Compression,
+    dir: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    runtime::prepare(dir)?;
+    fs::create_dir_all(dir)?;
+    let mut paths = Vec::new();
+    for file in files {
+        // only keep the final component so a peer can't write outside `dir`
+        let Some(name) = Path::new(&file.name).file_name() else {
+            warn!("skip file with invalid name: {:?}", file.name);
+            continue;
+        };
+        let Ok(content) = BASE64.decode(&file.content) else {
+            warn!("skip file with invalid content: {:?}", file.name);
+            continue;
+        };
+        let Some(content) = decompress(&content, compression) else {
+            warn!("skip file with invalid content: {:?}", file.name);
+            continue;
+        };
+        let (path, mut created) = create_unique(dir, &name.to_string_lossy())?;
+        created.write_all(&content)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Creates `name` in `dir`, adding `-1`, `-2`, ... before the extension
+/// rather than overwriting a file or following a link someone put there.
+fn create_unique(dir: &Path, name: &str) -> io::Result<(PathBuf, fs::File)> {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let mut attempt = 0;
+    loop {
+        let path = match attempt {
+            0 => dir.join(name),
+            _ => dir.join(format!("{}-{}{}", stem, attempt, extension)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Writes `image` to `--save-dir` as a PNG named after `--image-name`.
+fn save_image(
+    image: &ImageData,
+    from: Option<&str>,
+    options: &ClientOptions,
+) -> io::Result<PathBuf> {
+    runtime::prepare(&options.save_dir)?;
+    fs::create_dir_all(&options.save_dir)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let name = options.image_name.render(timestamp, image, sender(from));
+    let png = encode_png(image.width, image.height, &image.bytes)?;
+    let (path, mut file) = create_unique(&options.save_dir, &name)?;
+    file.write_all(&png)?;
+    Ok(path)
+}
+
+fn send_files(
+    paths: Vec<PathBuf>,
+    sender: &UnboundedSender<Message>,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) {
+    if let ClipboardCache::Files(cached) = &state.cache {
+        if cached == &paths {
+            return;
+        }
+    }
+    if state.is_paused() || state.is_echo(files_hash(&paths)) {
+        state.cache = ClipboardCache::Files(paths);
+        return;
+    }
+    let total_size: u64 = paths
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum();
+    let max = max_message_size(options);
+    if total_size > max as u64 {
+        let detail = format!("{} bytes exceeds the {} bytes limit", total_size, max);
+        warn!("skip files: {}", detail);
+        alert(options, "Clipboard too large for copy-sync", &detail);
+        state.cache = ClipboardCache::Files(paths);
+        return;
+    }
+    let files = match read_files(&paths, options.compress, options.compression) {
+        Ok(files) => files,
+        Err(err) => {
+            warn!("read files error: {:?}", err);
+            return;
+        }
+    };
+    if files.is_empty() {
+        return;
+    }
+    let content = files_content(&files);
+    let timestamp = state.touch();
+    let payload = serialize_clipboard_message(
+        ClipboardMessagePayload::Files(ClipboardMessageFiles {
+            files,
+            compression: options.compress,
+        }),
+        state.cipher.as_deref(),
+        timestamp,
+    );
+    let frame = Message::Text(payload);
+    if !fits(&frame, "files", options) {
+        state.cache = ClipboardCache::Files(paths);
+        return;
+    }
+    if !send_frame(sender, frame, "files") {
+        return;
+    }
+    state.audit(Direction::Sent, "files", &content);
+    state.cache = ClipboardCache::Files(paths);
+}
+
+fn send_html(
+    html: String,
+    alt_text: Option<String>,
+    sender: &UnboundedSender<Message>,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) {
+    if let ClipboardCache::Html(cached) = &state.cache {
+        if cached == &html {
+            return;
+        }
+    }
+    if state.is_paused() || state.is_echo(html_hash(&html)) {
+        state.cache = ClipboardCache::Html(html);
+        return;
+    }
+    // the plain text fallback is what the secret would be copied as
+    if let Some(pattern) = alt_text
+        .as_deref()
+        .and_then(|text| excluded_by(text, options))
+    {
+        debug!("skip html matching exclude pattern {}", pattern);
+        state.cache = ClipboardCache::Html(html);
+        return;
+    }
+    let timestamp = state.touch();
+    let payload = serialize_clipboard_message(
+        ClipboardMessagePayload::Html(ClipboardMessageHtml {
+            html: html.clone(),
+            alt_text,
+        }),
+        state.cipher.as_deref(),
+        timestamp,
+    );
+    let frame = Message::Text(payload);
+    if !fits(&frame, "html", options) {
+        state.cache = ClipboardCache::Html(html);
+        return;
+    }
+    if !send_frame(sender, frame, "html") {
+        return;
+    }
+    state.audit(Direction::Sent, "html", html.as_bytes());
+    state.cache = ClipboardCache::Html(html);
+}
+
+fn send_rtf(
+    rtf: String,
+    alt_text: Option<String>,
+    sender: &UnboundedSender<Message>,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) {
+    if let ClipboardCache::Rtf(cached) = &state.cache {
+        if cached == &rtf {
+            return;
+        }
+    }
+    if state.is_paused() || state.is_echo(rtf_hash(&rtf)) {
+        state.cache = ClipboardCache::Rtf(rtf);
+        return;
+    }
+    // RTF keeps its text readable, so without a fallback the source is checked
+    if let Some(pattern) = excluded_by(alt_text.as_deref().unwrap_or(&rtf), options) {
+        debug!("skip rtf matching exclude pattern {}", pattern);
+        state.cache = ClipboardCache::Rtf(rtf);
+        return;
+    }
+    let timestamp = state.touch();
+    let payload = serialize_clipboard_message(
+        ClipboardMessagePayload::Rtf(ClipboardMessageRtf {
+            rtf: rtf.clone(),
+            alt_text,
+        }),
+        state.cipher.as_deref(),
+        timestamp,
+    );
+    let frame = Message::Text(payload);
+    if !fits(&frame, "rtf", options) {
+        state.cache = ClipboardCache::Rtf(rtf);
+        return;
+    }
+    if !send_frame(sender, frame, "rtf") {
+        return;
+    }
+    state.audit(Direction::Sent, "rtf", rtf.as_bytes());
+    state.cache = ClipboardCache::Rtf(rtf);
+}
+
+fn send_raw_text(
+    bytes: Vec<u8>,
+    sender: &UnboundedSender<Message>,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) {
+    if let ClipboardCache::RawText(cached) = &state.cache {
+        if cached == &bytes {
+            return;
+        }
+    }
+    if state.is_paused() || state.is_echo(raw_text_hash(&bytes)) {
+        state.cache = ClipboardCache::RawText(bytes);
+        return;
+    }
+    if let Some(max_text_bytes) = options.max_text_bytes {
+        // cutting bytes of an unknown encoding could split a character
+        if bytes.len() > max_text_bytes {
+            info!(
+                "skip text by policy: {} bytes exceeds --max-text-bytes {}",
+                bytes.len(),
+                max_text_bytes
+            );
+            state.cache = ClipboardCache::RawText(bytes);
+            return;
+        }
+    }
+    let encoding = TextEncoding::detect(&bytes);
+    let timestamp = state.touch();
+    let payload = serialize_clipboard_message(
+        ClipboardMessagePayload::RawText(ClipboardMessageRawText {
+            data: BASE64.encode(&bytes),
+            encoding,
+        }),
+        state.cipher.as_deref(),
+        timestamp,
+    );
+    let frame = Message::Text(payload);
+    if !fits(&frame, "text", options) {
+        state.cache = ClipboardCache::RawText(bytes);
+        return;
+    }
+    if !send_frame(sender, frame, "text") {
+        return;
+    }
+    state.audit(Direction::Sent, "text", &bytes);
+    state.history.lock().unwrap().push(&encoding.decode(&bytes));
+    state.cache = ClipboardCache::RawText(bytes);
+}
+
+/// Tells peers the clipboard went from holding something to holding nothing.
+fn send_clear(sender: &UnboundedSender<Message>, state: &mut ClientState) {
+    // a password manager clearing its secret leaves the peers alone
+    if state.is_paused() || matches!(state.cache, ClipboardCache::Concealed) {
+        state.cache = ClipboardCache::Empty;
+    }
+    if matches!(state.cache, ClipboardCache::Empty) {
+        return;
+    }
+    let timestamp = state.touch();
+    let payload = serialize_clipboard_message(
+        ClipboardMessagePayload::Clear,
+        state.cipher.as_deref(),
+        timestamp,
+    );
+    if !send_frame(sender, Message::Text(payload), "clear") {
+        return;
+    }
+    state.audit(Direction::Sent, "clear", &[]);
+    state.cache = ClipboardCache::Empty;
+}
+
+/// What `sync_clipboard` found on the clipboard, read in one go on the
+/// clipboard thread.
+enum Snapshot {
+    Files(Vec<PathBuf>),
+    /// with the text next to it, for `--image-with-text`
+    Image(ImageData<'static>, Option<String>),
+    /// HTML or RTF with their plain text fallback
+    Html(String, Option<String>),
+    Rtf(String, Option<String>),
+    Text(String),
+    RawText(Vec<u8>),
+    Empty,
+    /// marked as a secret, for `--password-strip`
+    Concealed,
+    /// text that is neither UTF-8 nor readable as bytes
+    Unreadable(arboard::Error),
+    /// another program holds the clipboard, which says nothing about its content
+    Busy,
+    Failed(arboard::Error),
+}
+
+fn read_clipboard(
+    cb: &mut Board,
+    text_only: bool,
+    image_with_text: bool,
+    password_strip: bool,
+) -> Snapshot {
+    if password_strip && cb.is_concealed() {
+        return Snapshot::Concealed;
+    }
+    // text-only mode never reads anything but plain text
+    if !text_only {
+        if let Ok(paths) = cb.get_file_list() {
+            if !paths.is_empty() {
+                return Snapshot::Files(paths);
+            }
+        }
+        match cb.get_image() {
+            Ok(image) => {
+                let text = image_with_text
+                    .then(|| cb.get_text().ok())
+                    .flatten()
+                    .filter(|text| !text.is_empty());
+                return Snapshot::Image(image, text);
+            }
+            Err(arboard::Error::ContentNotAvailable) => {}
+            Err(arboard::Error::ClipboardOccupied) => return Snapshot::Busy,
+            Err(err) => return Snapshot::Failed(err),
+        }
+        if let Ok(html) = cb.get_html() {
+            return Snapshot::Html(html, cb.get_text().ok());
+        }
+        // HTML goes first: more receivers can paste it
+        if let Ok(rtf) = cb.get_rtf() {
+            // the file backend reads the RTF source back as text
+            let alt_text = cb.get_text().ok().filter(|text| text != &rtf);
+            return Snapshot::Rtf(rtf, alt_text);
+        }
+    }
+    match cb.get_text() {
+        Ok(text) if !text.is_empty() => Snapshot::Text(text),
+        Ok(_) | Err(arboard::Error::ContentNotAvailable) => Snapshot::Empty,
+        Err(arboard::Error::ConversionFailure) => match cb.get_text_bytes() {
+            Ok(bytes) => Snapshot::RawText(bytes),
+            Err(err) => Snapshot::Unreadable(err),
+        },
+        Err(arboard::Error::ClipboardOccupied) => Snapshot::Busy,
+        Err(err) => Snapshot::Failed(err),
+    }
+}
+
+fn send_image(
+    current: ImageData<'static>,
+    text: Option<String>,
+    sender: &UnboundedSender<Message>,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) {
+    let hash = image_hash(&current);
+    if let ClipboardCache::Image(image, cached) = &state.cache {
+        // equal hashes only need the full compare to rule out a collision
+        if *cached == hash && image.bytes == current.bytes {
+            return;
+        }
+    }
+    if state.is_paused() || state.is_echo(hash) {
+        state.cache = ClipboardCache::Image(current, hash);
+        return;
+    }
+    if let Some(window) = options.image_dedup_window {
+        if state.sent_lately(hash, Duration::from_secs(window)) {
+            debug!(
+                "skip image: the same {}x{} image was sent less than {}s ago",
+                current.width, current.height, window
+            );
+            state.cache = ClipboardCache::Image(current, hash);
+            return;
+        }
+    }
+    if !is_rgba(&current) {
+        warn!(
+            "clipboard image of {}x{} has {} bytes, expected 4 per pixel, not sending it",
+            current.width,
+            current.height,
+            current.bytes.len()
+        );
+        state.cache = ClipboardCache::Image(current, hash);
+        return;
+    }
+    let timestamp = state.touch();
+    let header = ClipboardMessageImage {
+        width: current.width,
+        height: current.height,
+        codec: options.image_codec,
+        compression: options.compress,
+        peer: None,
+        name: None,
+        timestamp: Some(timestamp),
+        chunk: None,
+        seq: None,
+    };
+    // compress image
+    let bytes = match options.image_codec {
+        ImageCodec::Raw => compress(&current.bytes, options.compress, options.compression),
+        ImageCodec::Png => match encode_png(current.width, current.height, &current.bytes) {
+            Ok(png) => png,
+            Err(err) => {
+                warn!("encode image error, not sending it: {}", err);
+                state.cache = ClipboardCache::Image(current, hash);
+                return;
+            }
+        },
+    };
+    if let Some(max_image_bytes) = options.max_image_bytes {
+        if bytes.len() > max_image_bytes {
+            debug!(
+                "skip image by policy: {} bytes exceeds --max-image-bytes {}",
+                bytes.len(),
+                max_image_bytes
+            );
+            state.cache = ClipboardCache::Image(current, hash);
+            return;
+        }
+    }
+    if let Some(text) = text {
+        match excluded_by(&text, options) {
+            Some(pattern) => debug!(
+                "text next to the image matches exclude pattern {}, sending the image alone",
+                pattern
+            ),
+            None => {
+                let payload = ClipboardMessagePayload::ImageText(ClipboardMessageImageText {
+                    width: current.width,
+                    height: current.height,
+                    codec: options.image_codec,
+                    compression: options.compress,
+                    data: BASE64.encode(&bytes),
+                    text,
+                });
+                let message =
+                    serialize_clipboard_message(payload, state.cipher.as_deref(), timestamp);
+                if message.len() <= max_message_size(options) {
+                    if !send_frame(sender, Message::Text(message), "image") {
+                        return;
+                    }
+                    state.audit(Direction::Sent, "image", &current.bytes);
+                    state.sent_images.push((hash, Instant::now()));
+                    state.cache = ClipboardCache::Image(current, hash);
+                    return;
+                }
+                debug!("image with text is too large for one message, sending the image alone");
+            }
+        }
+    }
+    // chunks leave room for their header within --max-message-size
+    let chunk_size = IMAGE_CHUNK_SIZE_IN_BYTES
+        .min(max_message_size(options).saturating_sub(IMAGE_HEADER_ROOM_IN_BYTES))
+        .max(1);
+    let frames = image_frames(header, &bytes, state.cipher.as_deref(), chunk_size);
+    let size: usize = frames.iter().map(Message::len).sum();
+    let too_large = if size > MAX_IMAGE_SIZE_IN_BYTES {
+        Some(format!(
+            "W: {} H: {}, {} bytes exceeds the {} bytes limit",
+            current.width, current.height, size, MAX_IMAGE_SIZE_IN_BYTES
+        ))
+    } else if frames.len() > MAX_IMAGE_CHUNKS {
+        // peers drop an image in that many pieces
+        Some(format!(
+            "W: {} H: {}, {} chunks of --max-message-size exceed the {} chunks limit",
+            current.width,
+            current.height,
+            frames.len(),
+            MAX_IMAGE_CHUNKS
+        ))
+    } else {
+        None
+    };
+    if let Some(detail) = too_large {
+        warn!("skip image: {}", detail);
+        alert(options, "Image too large for copy-sync", &detail);
+        // remember it so the same image is not reported on every tick
+        state.cache = ClipboardCache::Image(current, hash);
+        return;
+    }
+    for frame in frames {
+        if !send_frame(sender, frame, "image") {
+            return;
+        }
+    }
+    state.audit(Direction::Sent, "image", &current.bytes);
+    state.sent_images.push((hash, Instant::now()));
+    state.cache = ClipboardCache::Image(current, hash);
+}
+
+fn send_text(
+    current: String,
+    sender: &UnboundedSender<Message>,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) {
+    if let ClipboardCache::Text(text) = &state.cache {
+        // the original text still goes out, trimming only decides whether it changed
+        if text == &current || (options.normalize_text && text.trim() == current.trim()) {
+            return;
+        }
+    }
+    if state.is_paused() || state.is_echo(text_hash(&current)) {
+        state.cache = ClipboardCache::Text(current);
+        return;
+    }
+    if let Some(pattern) = excluded_by(&current, options) {
+        debug!("skip text matching exclude pattern {}", pattern);
+        state.cache = ClipboardCache::Text(current);
+        return;
+    }
+    let content = match options.max_text_bytes {
+        Some(max_text_bytes) if current.len() > max_text_bytes => match options.text_policy {
+            TextPolicy::Skip => {
+                info!(
+                    "skip text by policy: {} bytes exceeds --max-text-bytes {}",
+                    current.len(),
+                    max_text_bytes
+                );
+                state.cache = ClipboardCache::Text(current);
+                return;
+            }
+            TextPolicy::Truncate => {
+                info!(
+                    "truncate text by policy: {} bytes exceeds --max-text-bytes {}",
+                    current.len(),
+                    max_text_bytes
+                );
+                truncate(&current, max_text_bytes)
+            }
+        },
+        _ => current.as_str(),
+    };
+    let timestamp = state.touch();
+    let payload = serialize_clipboard_message(
+        ClipboardMessagePayload::Text(ClipboardMessageText {
+            content: content.to_string(),
+        }),
+        state.cipher.as_deref(),
+        timestamp,
+    );
+    let frame = Message::Text(payload);
+    if !fits(&frame, "text", options) {
+        state.cache = ClipboardCache::Text(current);
+        return;
+    }
+    if !send_frame(sender, frame, "text") {
+        return;
+    }
+    state.audit(Direction::Sent, "text", content.as_bytes());
+    state.history.lock().unwrap().push(&current);
+    state.cache = ClipboardCache::Text(current);
+}
+
+async fn sync_clipboard(
+    sender: &UnboundedSender<Message>,
+    state: &Arc<Mutex<ClientState>>,
+    options: &ClientOptions,
+) {
+    let (text_only, image_with_text) = (options.text_only, options.image_with_text);
+    let password_strip = options.password_strip;
+    // queued with the state locked, so the read comes after the writes of
+    // the updates applied so far
+    let (read, before) = {
+        let state = state.lock().unwrap();
+        let read = state
+            .clipboard
+            .run(move |cb| read_clipboard(cb, text_only, image_with_text, password_strip));
+        (read, state.id.clone())
+    };
+    let snapshot = match read.await {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            warn!("open clipboard error: {:?}", err);
+            return;
+        }
+    };
+    let mut state = state.lock().unwrap();
+    // an update applied during the read isn't a local change, and what
+    // was read may predate it
+    if state.id != before {
+        return;
+    }
+    let failure = match &snapshot {
+        Snapshot::Unreadable(err) => Some(format!(
+            "clipboard text is not UTF-8 and can't be read as bytes either: {:?}",
+            err
+        )),
+        Snapshot::Busy => Some("clipboard is busy, another program holds it".to_string()),
+        Snapshot::Failed(err) => Some(format!("read clipboard error: {:?}", err)),
+        _ => None,
+    };
+    if failure != state.read_failure {
+        match &failure {
+            Some(failure) => warn!("{}, not syncing until it can be read", failure),
+            None => info!("clipboard is readable again"),
+        }
+        state.read_failure = failure;
+    }
+    match snapshot {
+        Snapshot::Files(paths) => send_files(paths, sender, &mut state, options),
+        Snapshot::Image(image, text) => send_image(image, text, sender, &mut state, options),
+        Snapshot::Html(html, alt_text) => send_html(html, alt_text, sender, &mut state, options),
+        Snapshot::Rtf(rtf, alt_text) => send_rtf(rtf, alt_text, sender, &mut state, options),
+        Snapshot::Text(text) => send_text(text, sender, &mut state, options),
+        Snapshot::RawText(bytes) => send_raw_text(bytes, sender, &mut state, options),
+        Snapshot::Concealed => {
+            if !matches!(state.cache, ClipboardCache::Concealed) {
+                info!("skip clipboard content a password manager marked as concealed");
+                state.cache = ClipboardCache::Concealed;
+            }
+        }
+        // text-only mode can't tell an empty clipboard from an image
+        Snapshot::Empty if !options.text_only => send_clear(sender, &mut state),
+        Snapshot::Empty | Snapshot::Unreadable(_) | Snapshot::Busy => {}
+        // drop the handle so it gets re-created on the next tick
+        Snapshot::Failed(_) => state.clipboard.reopen(),
+    }
+    // once the clipboard has held something else, the update from a peer is
+    // no echo anymore: copying it again, after an image say, has to go out
+    if state.last_applied.is_some() && state.cache.hash() != state.last_applied {
+        state.last_applied = None;
+    }
+}
+
+/// Hash of what `sync_clipboard` would pick from the clipboard right now,
+/// comparable with [`ClipboardCache::hash`].
+fn fingerprint(cb: &mut Board, text_only: bool) -> Option<u64> {
+    if !text_only {
+        if let Ok(paths) = cb.get_file_list() {
+            if !paths.is_empty() {
+                return Some(files_hash(&paths));
+            }
+        }
+        if let Ok(image) = cb.get_image() {
+            return Some(image_hash(&image));
+        }
+        if let Ok(html) = cb.get_html() {
+            return Some(html_hash(&html));
+        }
+        if let Ok(rtf) = cb.get_rtf() {
+            return Some(rtf_hash(&rtf));
+        }
+    }
+    match cb.get_text() {
+        Ok(text) if !text.is_empty() => Some(text_hash(&text)),
+        Err(arboard::Error::ConversionFailure) => {
+            cb.get_text_bytes().ok().map(|bytes| raw_text_hash(&bytes))
+        }
+        _ => None,
+    }
+}
+
+/// Waits for the clipboard to stay unchanged for `--debounce` milliseconds,
+/// so a burst of copies goes out once, as its last value.
+async fn settle(
+    events: &mut Option<UnboundedReceiver<()>>,
+    state: &Mutex<ClientState>,
+    options: &ClientOptions,
+) {
+    let quiet = Duration::from_millis(options.debounce);
+    if let Some(receiver) = events {
+        // another event inside the quiet period means the burst isn't over
+        while let Ok(Some(())) = tokio::time::timeout(quiet, receiver.next()).await {}
+        return;
+    }
+    let clipboard = state.lock().unwrap().clipboard.clone();
+    let text_only = options.text_only;
+    let read = || async {
+        clipboard
+            .run(move |cb| fingerprint(cb, text_only))
+            .await
+            .ok()
+            .flatten()
+    };
+    let mut last = read().await;
+    if last == state.lock().unwrap().cache.hash() {
+        return;
+    }
+    loop {
+        tokio::time::sleep(quiet).await;
+        let current = read().await;
+        if current == last {
+            return;
+        }
+        last = current;
+    }
+}
+
+/// Sends clipboard changes as they are detected, or in manual mode only
+/// when `manual` is triggered by the hotkey.
+async fn check_clipboard(
+    sender: UnboundedSender<Message>,
+    state: Arc<Mutex<ClientState>>,
+    options: ClientOptions,
+    manual: Option<Arc<Notify>>,
+) {
+    let mut events = match options.watch_mode {
+        // native events only exist for the system clipboard, and don't tell
+        // about the PRIMARY selection
+        WatchMode::Event
+            if manual.is_none()
+                && options.clipboard_backend == ClipboardBackend::System
+                && !options.selection.primary() =>
+        {
+            Some(watch_clipboard())
+        }
+        _ => None,
+    };
+    let mut primary = options.selection.primary();
+    loop {
+        match (&manual, events.as_mut()) {
+            (Some(manual), _) => manual.notified().await,
+            (None, Some(receiver)) => {
+                if receiver.next().await.is_none() {
+                    warn!("clipboard events unavailable, falling back to polling");
+                    events = None;
+                    continue;
+                }
+            }
+            (None, None) => {
+                tokio::time::sleep(Duration::from_millis(options.poll_interval)).await;
+            }
+        }
+        if manual.is_none() && options.debounce > 0 {
+            settle(&mut events, &state, &options).await;
+        }
+        // the connection's write side is gone, nothing could be sent anymore
+        if sender.is_closed() {
+            debug!("send channel closed, stop checking the clipboard");
+            break;
+        }
+        if options.selection.clipboard() {
+            sync_clipboard(&sender, &state, &options).await;
+        }
+        if primary && !sync_primary(&sender, &state, &options).await {
+            warn!("there is no primary selection here, not syncing it");
+            primary = false;
+        }
+    }
+}
+
+/// Sends the PRIMARY selection if it changed since it was last sent or
+/// applied. Returns `false` where there is no such selection.
+async fn sync_primary(
+    sender: &UnboundedSender<Message>,
+    state: &Arc<Mutex<ClientState>>,
+    options: &ClientOptions,
+) -> bool {
+    let (read, before) = {
+        let state = state.lock().unwrap();
+        let read = state.clipboard.run(|board| board.get_primary_text());
+        (read, state.primary.clone())
+    };
+    let text = match read.await {
+        Ok(Ok(text)) => text,
+        Ok(Err(arboard::Error::ClipboardNotSupported)) => return false,
+        // empty or busy, or the clipboard itself failed and sync_clipboard says so
+        Ok(Err(_)) | Err(_) => return true,
+    };
+    let mut state = state.lock().unwrap();
+    // a peer's update applied during the read wins over what was read
+    if state.primary != before {
+        return true;
+    }
+    if text.is_empty() || state.primary.as_ref() == Some(&text) {
+        return true;
+    }
+    if state.is_paused() {
+        state.primary = Some(text);
+        return true;
+    }
+    if let Some(pattern) = excluded_by(&text, options) {
+        debug!(
+            "skip primary selection matching exclude pattern {}",
+            pattern
+        );
+        state.primary = Some(text);
+        return true;
+    }
+    if let Some(max_text_bytes) = options.max_text_bytes.filter(|max| text.len() > *max) {
+        debug!(
+            "skip primary selection: {} bytes exceeds --max-text-bytes {}",
+            text.len(),
+            max_text_bytes
+        );
+        state.primary = Some(text);
+        return true;
+    }
+    let payload = ClipboardMessagePayload::Text(ClipboardMessageText {
+        content: text.clone(),
+    });
+    let message = ClipboardMessage {
+        payload: seal(payload, state.cipher.as_deref()),
+        peer: None,
+        name: None,
+        timestamp: Some(now_in_millis()),
+        seq: None,
+        selection: Some(Selection::Primary),
+    };
+    let frame = Message::Text(serde_json::to_string(&message).unwrap());
+    if send_frame(sender, frame, "primary selection") {
+        state.audit(Direction::Sent, "text", text.as_bytes());
+    }
+    state.primary = Some(text);
+    true
+}
+
+/// Summarises a frame `sync_clipboard` would have sent.
+fn describe_frame(frame: &Message) -> String {
+    match frame {
+        Message::Text(text) => match deserialize_clipboard_message(text, None) {
+            Ok((ClipboardMessagePayload::Text(text), _)) => {
+                format!("text, {} chars", text.content.chars().count())
+            }
+            Ok((ClipboardMessagePayload::RawText(raw), _)) => {
+                format!("non-UTF-8 text, {} bytes encoded", raw.data.len())
+            }
+            Ok((ClipboardMessagePayload::Html(html), _)) => {
+                format!("html, {} bytes", html.html.len())
+            }
+            Ok((ClipboardMessagePayload::Rtf(rtf), _)) => {
+                format!("rtf, {} bytes", rtf.rtf.len())
+            }
+            Ok((ClipboardMessagePayload::ImageText(image), _)) => format!(
+                "image {}x{} with {} chars of text, {} bytes encoded",
+                image.width,
+                image.height,
+                image.text.chars().count(),
+                image.data.len()
+            ),
+            Ok((ClipboardMessagePayload::Files(files), _)) => {
+                let encoded: usize = files.files.iter().map(|file| file.content.len()).sum();
+                format!("{} files, {} bytes encoded", files.files.len(), encoded)
+            }
+            Ok((ClipboardMessagePayload::Clear, _)) => "clear".to_string(),
+            Ok((ClipboardMessagePayload::Sealed(_), _)) => "encrypted message".to_string(),
+            Err(err) => format!("unreadable message: {}", err),
+        },
+        Message::Binary(binary) => match unpack_frame::<ClipboardMessageImage>(binary) {
+            Some((header, body)) => format!(
+                "image {}x{}, {} bytes encoded",
+                header.width,
+                header.height,
+                body.len()
+            ),
+            None => "malformed image frame".to_string(),
+        },
+        frame => format!("{} bytes", frame.len()),
+    }
+}
+
+/// Runs the same clipboard detection as `connect`, but logs each change
+/// instead of sending it anywhere.
+pub async fn monitor(options: ClientOptions, mut shutdown: Shutdown) {
+    let (tx, mut rx) = futures_channel::mpsc::unbounded();
+    let shared = Shared::standalone(&options);
+    let state = Arc::new(Mutex::new(ClientState::new(shared)));
+    let watcher = spawn(check_clipboard(tx, state, options, None));
+    loop {
+        tokio::select! {
+            frame = rx.next() => match frame {
+                Some(frame) => info!("Detected {}", describe_frame(&frame)),
+                None => break,
+            },
+            _ = shutdown.wait() => break,
+        }
+    }
+    watcher.abort();
+}
+
+/// Shows a desktop notification unless `--no-notify` is set. A missing
+/// notification daemon only gets logged.
+fn alert(options: &ClientOptions, summary: &str, message: &str) {
+    if !options.notify {
+        return;
+    }
+    if let Err(err) = notify(summary, message) {
+        debug!("notification failed: {}", err);
+    }
+}
+
+fn generate_ulid() -> String {
+    let ulid = Ulid::new();
+    ulid.to_string()
+}
+
+/// Retries `set` with a doubling delay while another process holds the
+/// clipboard, giving up after [`SET_CLIPBOARD_ATTEMPTS`].
+fn set_with_retry(
+    mut set: impl FnMut() -> Result<(), arboard::Error>,
+) -> Result<(), arboard::Error> {
+    let mut delay = Duration::from_millis(SET_CLIPBOARD_RETRY_INTERVAL_IN_MILLISECONDS);
+    let mut attempt = 1;
+    loop {
+        match set() {
+            Err(arboard::Error::ClipboardOccupied) if attempt < SET_CLIPBOARD_ATTEMPTS => {
+                debug!("clipboard occupied, retrying in {:?}", delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A clipboard write [`apply_message`] queued. It is waited for once the
+/// state is unlocked, and may hand back what the cache should hold then.
+type PendingWrite = BoxFuture<'static, Option<ClipboardCache<'static>>>;
+
+/// Queues `set` on the clipboard thread with the retries of
+/// [`set_with_retry`]. The write only logs a failure, as `what`.
+fn write_clipboard(
+    clipboard: &ClipboardThread,
+    what: &'static str,
+    mut set: impl FnMut(&mut Board) -> Result<(), arboard::Error> + Send + 'static,
+) -> PendingWrite {
+    let written = clipboard.run(move |board| set_with_retry(|| set(board)));
+    async move {
+        if let Err(err) = written.await.and_then(|result| result) {
+            warn!("{} error: {:?}", what, err);
+        }
+        None
+    }
+    .boxed()
+}
+
+/// What notifications call a peer, its `--name` if it sent one.
+fn sender(name: Option<&str>) -> &str {
+    name.unwrap_or("copy-sync")
+}
+
+/// Files an item for `copy-sync inbox` instead of overwriting the clipboard.
+fn keep(state: &ClientState, item: Received, from: Option<&str>, options: &ClientOptions) {
+    let kind = match item {
+        Received::Text(_) => "text",
+        Received::Html { .. } => "html",
+        Received::Rtf { .. } => "rtf",
+        Received::Image(_) => "an image",
+        Received::Files(_) => "files",
+    };
+    debug!("read-only clipboard, keeping {} in the inbox", kind);
+    state.inbox.lock().unwrap().push(item);
+    alert(
+        options,
+        &format!("Received from {}", sender(from)),
+        &format!("{} is in the inbox, see copy-sync inbox", kind),
+    );
+}
+
+/// Puts an item from the inbox on the clipboard, marked so the client
+/// doesn't send it back to the peers it came from.
+async fn paste_received(
+    item: Received,
+    clipboard: ClipboardThread,
+    options: ClientOptions,
+    inbox: SharedInbox,
+) -> Result<(), String> {
+    let item = match item {
+        Received::Rtf {
+            alt_text: Some(text),
+            ..
+        } if !options.clipboard_backend.holds_rtf() => Received::Text(text),
+        item => item,
+    };
+    let hash = match &item {
+        Received::Text(text) => text_hash(text),
+        Received::Html { html, .. } => html_hash(html),
+        Received::Rtf { rtf, .. } => rtf_hash(rtf),
+        Received::Image(image) => image_hash(image),
+        Received::Files(paths) => files_hash(paths),
+    };
+    inbox.lock().unwrap().mark_pasted(hash);
+    clipboard
+        .run(move |board| {
+            set_with_retry(|| match &item {
+                Received::Text(text) => board.set_text(text),
+                Received::Html { html, alt_text } => board.set_html(html, alt_text.as_deref()),
+                Received::Rtf { rtf, .. } => board.set_rtf(rtf),
+                Received::Image(image) => board.set_image(borrow_image(image)),
+                Received::Files(paths) => board.set_file_list(paths),
+            })
+        })
+        .await
+        .and_then(|result| result)
+        .map_err(|err| format!("set clipboard error: {:?}", err))
+}
+
+fn apply_text(
+    content: String,
+    name: Option<&str>,
+    timestamp: u64,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) -> Option<PendingWrite> {
+    state.audit(Direction::Received, "text", content.as_bytes());
+    if options.read_only_clipboard {
+        state.history.lock().unwrap().push(&content);
+        keep(state, Received::Text(content), name, options);
+        return None;
+    }
+    let text = content.clone();
+    let write = write_clipboard(&state.clipboard, "set text", move |board| {
+        board.set_text(&text)
+    });
+    state.history.lock().unwrap().push(&content);
+    if options.notify_text {
+        alert(
+            options,
+            &format!("Received text from {}", sender(name)),
+            &preview(&content),
+        );
+    }
+    state.last_applied = Some(text_hash(&content));
+    state.cache = ClipboardCache::Text(content);
+    state.id = generate_ulid();
+    state.timestamp = timestamp;
+    Some(write)
+}
+
+/// Puts a peer's PRIMARY selection into ours. Only plain text is sent for
+/// it, and read-only clients leave it alone.
+fn apply_primary(
+    payload: ClipboardMessagePayload,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) -> Option<PendingWrite> {
+    if !options.selection.primary() || options.read_only_clipboard {
+        debug!("not syncing the primary selection, ignoring update");
+        return None;
+    }
+    let ClipboardMessagePayload::Text(payload) = payload else {
+        debug!("primary selection update isn't plain text, ignoring it");
+        return None;
+    };
+    state.audit(Direction::Received, "text", payload.content.as_bytes());
+    let text = payload.content.clone();
+    let write = write_clipboard(&state.clipboard, "set primary selection", move |board| {
+        board.set_primary_text(&text)
+    });
+    state.primary = Some(payload.content);
+    Some(write)
+}
+
+/// Decodes the pixels of an image frame or [`ClipboardMessageImageText`],
+/// `None` when they are malformed.
+fn decode_image(
+    width: usize,
+    height: usize,
+    codec: ImageCodec,
+    compression: Compression,
+    body: &[u8],
+) -> Option<ImageData<'static>> {
+    let image = match codec {
+        ImageCodec::Raw => match decompress(body, compression) {
+            Some(bytes) => ImageData {
+                width,
+                height,
+                bytes: Cow::from(bytes),
+            },
+            None => {
+                warn!("decompress image error, skipping message");
+                return None;
+            }
+        },
+        ImageCodec::Png => match decode_png(body) {
+            Some((width, height, bytes)) => ImageData {
+                width,
+                height,
+                bytes: Cow::from(bytes),
+            },
+            None => {
+                warn!("decode png error, skipping message");
+                return None;
+            }
+        },
+    };
+    if !is_rgba(&image) {
+        warn!(
+            "image of {}x{} has {} bytes, expected 4 per pixel, skipping message",
+            image.width,
+            image.height,
+            image.bytes.len()
+        );
+        return None;
+    }
+    Some(image)
+}
+
+fn apply_image(
+    image: ImageData<'static>,
+    name: Option<&str>,
+    timestamp: Option<u64>,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) -> Option<PendingWrite> {
+    state.audit(Direction::Received, "image", &image.bytes);
+    if options.save_images {
+        match save_image(&image, name, options) {
+            Ok(path) => info!("Saved image to {}", path.display()),
+            Err(err) => warn!("save image error: {:?}", err),
+        }
+    }
+    let (width, height) = (image.width, image.height);
+    if options.read_only_clipboard {
+        keep(state, Received::Image(image), name, options);
+        return None;
+    }
+    let hash = image_hash(&image);
+    // handed back so the cache keeps the pixels without a copy
+    let set = state.clipboard.run(move |board| {
+        let result = set_with_retry(|| board.set_image(borrow_image(&image)));
+        (result, image)
+    });
+    let write = async move {
+        let (result, image) = match set.await {
+            Ok(set) => set,
+            Err(err) => {
+                warn!("open clipboard error: {:?}", err);
+                return None;
+            }
+        };
+        if let Err(err) = result {
+            warn!("set image error: {:?}", err);
+        }
+        Some(ClipboardCache::Image(image, hash))
+    };
+    state.last_applied = Some(hash);
+    state.id = generate_ulid();
+    state.timestamp = timestamp.unwrap_or(0);
+    alert(
+        options,
+        &format!("Received image from {}", sender(name)),
+        &format!("W: {} H: {}", width, height),
+    );
+    Some(write.boxed())
+}
+
+/// Applies an update from a peer. The clipboard write is queued with the
+/// state locked, so a read queued after it sees the update, and waited for
+/// with the state unlocked.
+async fn handle_message(message: Message, state: Arc<Mutex<ClientState>>, options: &ClientOptions) {
+    let (write, applied) = {
+        let mut state = state.lock().unwrap();
+        let write = apply_message(message, &mut state, options);
+        (write, state.id.clone())
+    };
+    let Some(write) = write else {
+        return;
+    };
+    if let Some(cache) = write.await {
+        let mut state = state.lock().unwrap();
+        // unless another update came in meanwhile
+        if state.id == applied {
+            state.cache = cache;
+        }
+    }
+}
+
+fn apply_message(
+    message: Message,
+    state: &mut ClientState,
+    options: &ClientOptions,
+) -> Option<PendingWrite> {
+    debug!("Received {} bytes", message.len());
+    let is_update = matches!(message, Message::Text(_) | Message::Binary(_));
+    if is_update && !options.mode.receives() {
+        debug!("send-only mode, ignoring update");
+        return None;
+    }
+    state.discard_stale_chunks();
+    if is_update && state.is_paused() {
+        debug!("paused, ignoring update");
+        return None;
+    }
+    if is_update
+        && state
+            .signer
+            .as_deref()
+            .is_some_and(|signer| !verify_frame(&message, signer))
+    {
+        warn!("skipping unsigned message");
+        return None;
+    }
+    match message {
+        Message::Text(text) => {
+            let (payload, origin) =
+                match deserialize_clipboard_message(&text, state.cipher.as_deref()) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        warn!("skipping message: {}", err);
+                        return None;
+                    }
+                };
+            debug!("Message from peer {:?}", origin.peer);
+            // the selections change independently, timestamps included
+            if origin.selection == Some(Selection::Primary) {
+                return apply_primary(payload, state, options);
+            }
+            if !options.selection.clipboard() {
+                debug!("--selection primary, ignoring a clipboard update");
+                return None;
+            }
+            if state.is_outdated(origin.timestamp) {
+                debug!("skip update older than the clipboard");
+                return None;
+            }
+            let timestamp = origin.timestamp.unwrap_or(0);
+            match payload {
+                ClipboardMessagePayload::Text(payload) => apply_text(
+                    payload.content,
+                    origin.name.as_deref(),
+                    timestamp,
+                    state,
+                    options,
+                ),
+                ClipboardMessagePayload::RawText(payload) => {
+                    let Ok(bytes) = BASE64.decode(payload.data) else {
+                        warn!("malformed raw text, skipping message");
+                        return None;
+                    };
+                    state.audit(Direction::Received, "text", &bytes);
+                    let text = payload.encoding.decode(&bytes);
+                    state.history.lock().unwrap().push(&text);
+                    if options.read_only_clipboard {
+                        keep(state, Received::Text(text), origin.name.as_deref(), options);
+                        return None;
+                    }
+                    // the system clipboard gets the text as UTF-8 instead
+                    let write = if options.clipboard_backend.holds_raw_text() {
+                        let raw = bytes.clone();
+                        state.last_applied = Some(raw_text_hash(&bytes));
+                        state.cache = ClipboardCache::RawText(bytes);
+                        write_clipboard(&state.clipboard, "set text", move |board| {
+                            board.set_text_bytes(&raw)
+                        })
+                    } else {
+                        let utf8 = text.clone();
+                        state.last_applied = Some(text_hash(&text));
+                        state.cache = ClipboardCache::Text(text);
+                        write_clipboard(&state.clipboard, "set text", move |board| {
+                            board.set_text(&utf8)
+                        })
+                    };
+                    state.id = generate_ulid();
+                    state.timestamp = timestamp;
+                    Some(write)
+                }
+                ClipboardMessagePayload::Html(payload) => {
+                    state.audit(Direction::Received, "html", payload.html.as_bytes());
+                    if options.read_only_clipboard {
+                        let item = Received::Html {
+                            html: payload.html,
+                            alt_text: payload.alt_text,
+                        };
+                        keep(state, item, origin.name.as_deref(), options);
+                        return None;
+                    }
+                    let (html, alt_text) = (payload.html.clone(), payload.alt_text);
+                    let write = write_clipboard(&state.clipboard, "set html", move |board| {
+                        board.set_html(&html, alt_text.as_deref())
+                    });
+                    state.last_applied = Some(html_hash(&payload.html));
+                    state.cache = ClipboardCache::Html(payload.html);
+                    state.id = generate_ulid();
+                    state.timestamp = timestamp;
+                    Some(write)
+                }
+                ClipboardMessagePayload::Rtf(payload) => {
+                    state.audit(Direction::Received, "rtf", payload.rtf.as_bytes());
+                    if options.read_only_clipboard {
+                        let item = Received::Rtf {
+                            rtf: payload.rtf,
+                            alt_text: payload.alt_text,
+                        };
+                        keep(state, item, origin.name.as_deref(), options);
+                        return None;
+                    }
+                    // what lands on the clipboard is cached, so a fallback
+                    // isn't sent back as new text
+                    let (cache, write) = match payload.alt_text {
+                        Some(text) if !options.clipboard_backend.holds_rtf() => {
+                            let fallback = text.clone();
+                            let write =
+                                write_clipboard(&state.clipboard, "set rtf", move |board| {
+                                    board.set_text(&fallback)
+                                });
+                            (ClipboardCache::Text(text), write)
+                        }
+                        _ => {
+                            let rtf = payload.rtf.clone();
+                            let write =
+                                write_clipboard(&state.clipboard, "set rtf", move |board| {
+                                    board.set_rtf(&rtf)
+                                });
+                            (ClipboardCache::Rtf(payload.rtf), write)
+                        }
+                    };
+                    state.last_applied = cache.hash();
+                    state.cache = cache;
+                    state.id = generate_ulid();
+                    state.timestamp = timestamp;
+                    Some(write)
+                }
+                ClipboardMessagePayload::ImageText(payload) => {
+                    let name = origin.name.as_deref();
+                    // a text-only client has no use for the pixels
+                    if options.text_only {
+                        return apply_text(payload.text, name, timestamp, state, options);
+                    }
+                    let Ok(body) = BASE64.decode(&payload.data) else {
+                        warn!("malformed image, skipping message");
+                        return None;
+                    };
+                    let image = decode_image(
+                        payload.width,
+                        payload.height,
+                        payload.codec,
+                        payload.compression,
+                        &body,
+                    );
+                    match image {
+                        Some(image) => apply_image(image, name, origin.timestamp, state, options),
+                        None => apply_text(payload.text, name, timestamp, state, options),
+                    }
+                }
+                ClipboardMessagePayload::Files(payload) => {
+                    state.audit(Direction::Received, "files", &files_content(&payload.files));
+                    let paths =
+                        match save_files(payload.files, payload.compression, &options.download_dir)
+                        {
+                            Ok(paths) => paths,
+                            Err(err) => {
+                                warn!("save files error: {:?}", err);
+                                return None;
+                            }
+                        };
+                    if options.read_only_clipboard {
+                        keep(
+                            state,
+                            Received::Files(paths),
+                            origin.name.as_deref(),
+                            options,
+                        );
+                        return None;
+                    }
+                    let list = paths.clone();
+                    let write = write_clipboard(&state.clipboard, "set files", move |board| {
+                        board.set_file_list(&list)
+                    });
+                    state.last_applied = Some(files_hash(&paths));
+                    state.cache = ClipboardCache::Files(paths);
+                    state.id = generate_ulid();
+                    state.timestamp = timestamp;
+                    Some(write)
+                }
+                ClipboardMessagePayload::Clear => {
+                    state.audit(Direction::Received, "clear", &[]);
+                    if options.read_only_clipboard {
+                        debug!("read-only clipboard, ignoring clear");
+                        return None;
+                    }
+                    let write =
+                        write_clipboard(&state.clipboard, "clear clipboard", |board| board.clear());
+                    state.last_applied = None;
+                    state.cache = ClipboardCache::Empty;
+                    state.id = generate_ulid();
+                    state.timestamp = timestamp;
+                    Some(write)
+                }
+                ClipboardMessagePayload::Sealed(_) => {
+                    warn!("nested encrypted message, skipping message");
+                    None
+                }
+            }
+        }
+        Message::Binary(_) if !options.selection.clipboard() => {
+            debug!("--selection primary, ignoring an image");
+            None
+        }
+        Message::Binary(binary) => {
+            let (info, body) = parse_image_frame(&binary, state)?;
+            debug!("Image from peer {:?}", info.peer);
+            if state.is_outdated(info.timestamp) {
+                debug!("skip image older than the clipboard");
+                return None;
+            }
+            let image = decode_image(info.width, info.height, info.codec, info.compression, &body)?;
+            apply_image(image, info.name.as_deref(), info.timestamp, state, options)
+        }
+        Message::Ping(_) | Message::Pong(_) => None,
+        _ => {
+            debug!("unknown message: {}", message);
+            None
+        }
+    }
+}
+
+/// Bytes a client has sent and received, over every connection so far.
+#[derive(Default)]
+pub struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Traffic {
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    fn record_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn record_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Writes the queued frames to `write`, paced to `max_upload_kbps` on average
+/// when set. Nothing is dropped, a frame waits until the average allows it.
+/// Pings and pongs overtake waiting frames, so a long wait doesn't look like
+/// a dead connection to the server.
+///
+/// With `retransmit`, text and binary frames are numbered and kept in
+/// `pending` until the server acknowledges them, and sent again when it
+/// doesn't in time. Frames left there by an earlier connection go out first.
+async fn forward(
+    mut rx: UnboundedReceiver<Message>,
+    mut write: FrameSink,
+    traffic: Arc<Traffic>,
+    max_upload_kbps: Option<u64>,
+    signer: Option<Arc<Signer>>,
+    pending: Arc<Mutex<Pending>>,
+    retransmit: Option<u32>,
+) {
+    let bytes_per_second = max_upload_kbps.map(|kbps| (kbps * 1000 / 8).max(1));
+    let outgoing = |frame: Message, seq: Option<u64>| {
+        let frame = match seq {
+            Some(seq) => with_seq(frame, seq),
+            None => frame,
+        };
+        match &signer {
+            Some(signer) => sign_frame(frame, signer),
+            None => frame,
+        }
+    };
+    let mut queue = VecDeque::new();
+    {
+        let mut pending = pending.lock().unwrap();
+        if retransmit.is_some() {
+            for (&seq, unacked) in pending.frames.iter_mut() {
+                unacked.sent = None;
+                queue.push_back((outgoing(unacked.frame.clone(), Some(seq)), Some(seq)));
+            }
+        } else {
+            // this server doesn't acknowledge, they get one more try
+            for unacked in std::mem::take(&mut pending.frames).into_values() {
+                queue.push_back((outgoing(unacked.frame, None), None));
+            }
+        }
+    }
+    let mut resend = tokio::time::interval(Duration::from_secs(1));
+    let mut next_send = Instant::now();
+    let mut open = true;
+    while open || !queue.is_empty() {
+        // a close frame only has to wait for the frames before it
+        let ready = match queue.front() {
+            Some((Message::Close(_), _)) => Instant::now(),
+            _ => next_send,
+        };
+        let (frame, seq) = tokio::select! {
+            frame = rx.next(), if open => match frame {
+                Some(frame @ (Message::Ping(_) | Message::Pong(_))) => (frame, None),
+                Some(frame) => {
+                    let seq = match (&frame, retransmit) {
+                        (Message::Text(_) | Message::Binary(_), Some(_)) => {
+                            Some(pending.lock().unwrap().track(frame.clone()))
+                        }
+                        _ => None,
+                    };
+                    queue.push_back((outgoing(frame, seq), seq));
+                    continue;
+                }
+                None => {
+                    open = false;
+                    continue;
+                }
+            },
+            _ = tokio::time::sleep_until(ready), if !queue.is_empty() => {
+                let (frame, seq) = queue.pop_front().unwrap();
+                if let Some(bytes_per_second) = bytes_per_second {
+                    let pause = Duration::from_secs_f64(frame.len() as f64 / bytes_per_second as f64);
+                    next_send = Instant::now().max(next_send) + pause;
+                }
+                (frame, seq)
+            }
+            _ = resend.tick(), if retransmit.is_some() => {
+                let due = pending.lock().unwrap().due(retransmit.unwrap());
+                for (seq, frame) in due {
+                    queue.push_back((outgoing(frame, Some(seq)), Some(seq)));
+                }
+                continue;
+            }
+        };
+        let len = frame.len();
+        if let Err(err) = write.send(frame).await {
+            warn!("write error: {:?}", err);
+            return;
+        }
+        if let Some(seq) = seq {
+            pending.lock().unwrap().sent(seq);
+        }
+        traffic.record_sent(len);
+    }
+    let _ = write.close().await;
+}
 
-enum ClipboardCache<'a> {
-    Text(String),
-    Image(ImageData<'a>),
+/// Why a run ended.
+enum Disconnect {
+    /// the server sent a close frame
+    Remote(Option<CloseFrame<'static>>),
+    /// the client is closing the connection, for this reason
+    Local(&'static str),
+    /// reading or writing failed, there is no one left to close it with
+    Lost,
 }
 
-struct ClientState {
-    cache: ClipboardCache<'static>,
-    image_info: ClipboardMessageImage,
-    id: String,
-    timestamp: u64,
+/// Applies what the server sends until the connection ends, and tells why
+/// it did. A message over `--max-message-size` ends it, and is reported to
+/// the user the first time.
+async fn receive(
+    read: &mut FrameStream,
+    state: &Arc<Mutex<ClientState>>,
+    options: &ClientOptions,
+    traffic: &Traffic,
+    oversized: &AtomicBool,
+) -> Disconnect {
+    let heartbeat_timeout = Duration::from_secs(options.heartbeat_timeout);
+    loop {
+        match tokio::time::timeout(heartbeat_timeout, read.next()).await {
+            Ok(Some(Ok(Message::Close(frame)))) => return Disconnect::Remote(frame),
+            Ok(Some(Ok(message))) => {
+                traffic.record_received(message.len());
+                if let Some(seq) = acked_seq(&message) {
+                    state.lock().unwrap().pending.lock().unwrap().acked(seq);
+                    continue;
+                }
+                handle_message(message, state.clone(), options).await
+            }
+            Ok(Some(Err(tungstenite::Error::Capacity(err)))) => {
+                // the connection can't go on past it, tungstenite fails it
+                error!(
+                    "read error: a message from the server exceeds a limit: {}",
+                    err
+                );
+                if !oversized.swap(true, Ordering::Relaxed) {
+                    alert(
+                        options,
+                        "copy-sync can't receive a message",
+                        &format!("{}, raise --max-message-size to receive it", err),
+                    );
+                }
+                return Disconnect::Lost;
+            }
+            Ok(Some(Err(err))) => {
+                warn!("read error: {:?}", err);
+                return Disconnect::Lost;
+            }
+            Ok(None) => return Disconnect::Lost,
+            Err(_) => {
+                warn!("server missed its heartbeat");
+                return Disconnect::Local("missed heartbeat");
+            }
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct ClipboardMessageImage {
-    width: usize,
-    height: usize,
+fn incompatible(major: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "the server speaks protocol major {}, this client speaks {}",
+            major, PROTOCOL_MAJOR
+        ),
+    )
 }
 
-#[derive(Serialize, Deserialize)]
-struct ClipboardMessageText {
-    content: String,
+/// Sends the handshake and waits for the server to acknowledge it. Returns
+/// whether the server acknowledges frames too and the wire format it agreed
+/// to, an error when it rejected the handshake or the connection failed. An
+/// old server that never answers is only waited out without a token, which
+/// it would have had to accept.
+async fn handshake(
+    write: &mut FrameSink,
+    read: &mut FrameStream,
+    options: &ClientOptions,
+) -> io::Result<(bool, WireFormat)> {
+    let handshake = Handshake {
+        version: PROTOCOL_VERSION,
+        major: PROTOCOL_MAJOR,
+        token: options.token.clone(),
+        room: options.room.clone(),
+        name: options.name.clone(),
+        role: options.mode.role(),
+        wire_format: options.wire_format,
+    };
+    let handshake = serde_json::to_string(&handshake).unwrap();
+    write
+        .send(Message::Text(handshake))
+        .await
+        .map_err(io::Error::other)?;
+    let mut acks = false;
+    let mut wire_format = WireFormat::Json;
+    let reply = tokio::time::timeout(
+        Duration::from_secs(HANDSHAKE_TIMEOUT_IN_SECONDS),
+        read.next(),
+    )
+    .await;
+    match reply {
+        Ok(Some(Ok(Message::Text(text)))) => match serde_json::from_str::<HandshakeAck>(&text) {
+            Ok(ack) if !is_compatible(ack.major) => {
+                return Err(incompatible(ack.major));
+            }
+            Ok(ack) => {
+                if ack.version != PROTOCOL_VERSION {
+                    warn!(
+                        "Server speaks protocol version {}, client speaks {}",
+                        ack.version, PROTOCOL_VERSION
+                    );
+                }
+                acks = ack.acks;
+                wire_format = ack.wire_format;
+            }
+            Err(_) => warn!("unexpected handshake reply: {}", text),
+        },
+        Ok(Some(Ok(Message::Close(Some(frame))))) if frame.code == CloseCode::Unsupported => {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, frame.reason));
+        }
+        Ok(Some(Ok(Message::Close(Some(frame))))) if frame.code == CloseCode::Policy => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("the server refused the token: {}", frame.reason),
+            ));
+        }
+        Ok(Some(Ok(Message::Close(frame)))) => {
+            let reason = frame.map_or("no reason".into(), |frame| frame.reason);
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("handshake rejected: {}", reason),
+            ));
+        }
+        Ok(Some(Ok(message))) => warn!("unexpected handshake reply: {}", message),
+        Ok(Some(Err(err))) => return Err(io::Error::other(err)),
+        Ok(None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "closed during the handshake",
+            ))
+        }
+        // without an answer, a refused token looks the same as an old server
+        Err(_) if options.token.is_some() => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the server did not acknowledge the handshake, the token may have been refused",
+            ))
+        }
+        Err(_) => warn!("server did not acknowledge the handshake"),
+    }
+    Ok((acks, wire_format))
 }
 
-#[derive(Serialize, Deserialize)]
-enum ClipboardMessagePayload {
-    Text(ClipboardMessageText),
-    Image(ClipboardMessageImage),
+/// The seq of the frame a server's [`Ack`] is for.
+fn acked_seq(message: &Message) -> Option<u64> {
+    match message {
+        // spares parsing every other text frame
+        Message::Text(text) if text.starts_with("{\"ack\":") => {
+            serde_json::from_str::<Ack>(text).ok().map(|ack| ack.ack)
+        }
+        _ => None,
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct ClipboardMessage {
-    payload: ClipboardMessagePayload,
+/// Syncs over `connection` until it drops. Returns `true` when it was
+/// closed because the process is shutting down.
+async fn run(
+    (write, mut read, acks): Connection,
+    options: ClientOptions,
+    shared: Shared,
+    manual: Option<Arc<Notify>>,
+    shutdown: &mut Shutdown,
+) -> bool {
+    let retransmit = options.retransmit.filter(|_| acks);
+    if options.retransmit.is_some() && !acks {
+        warn!("The server doesn't acknowledge frames, sending without --retransmit");
+    }
+
+    let traffic = shared.traffic.clone();
+    let signer = shared.signer.clone();
+    let synced = shared.synced.clone();
+    let pending = shared.pending.clone();
+    let oversized = shared.oversized.clone();
+    let state = Arc::new(Mutex::new(ClientState::new(shared)));
+
+    let (tx, rx) = futures_channel::mpsc::unbounded();
+
+    let forward_ws = forward(
+        rx,
+        write,
+        traffic.clone(),
+        options.max_upload_kbps,
+        signer,
+        pending,
+        retransmit,
+    );
+
+    let heartbeat_timeout = Duration::from_secs(options.heartbeat_timeout);
+    let handler = receive(&mut read, &state, &options, &traffic, &oversized);
+
+    // `tx` stays alive for the whole run so `forward_ws` doesn't end early in receive-only mode
+    let check_clipboard_handler = options.mode.sends().then(|| {
+        spawn(check_clipboard(
+            tx.clone(),
+            state.clone(),
+            options.clone(),
+            manual,
+        ))
+    });
+
+    tokio::pin!(forward_ws);
+    let (disconnect, stopped) = tokio::select! {
+        _ = &mut forward_ws => (Disconnect::Lost, false),
+        disconnect = handler => (disconnect, false),
+        _ = heartbeat(tx.clone(), heartbeat_timeout) => (Disconnect::Lost, false),
+        _ = shutdown.wait() => (Disconnect::Local("client shutting down"), true),
+    };
+
+    if let Some(check_clipboard_handler) = check_clipboard_handler {
+        check_clipboard_handler.abort();
+    }
+    state.lock().unwrap().hand_over(&synced);
+
+    let close = match disconnect {
+        Disconnect::Remote(frame) => {
+            match &frame {
+                Some(frame) => info!("Closed by the server: {} ({})", frame.reason, frame.code),
+                None => info!("Closed by the server"),
+            }
+            // acknowledges the server's close frame
+            Some(Message::Close(None))
+        }
+        Disconnect::Local(reason) => {
+            info!("Closing the connection: {}", reason);
+            Some(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: reason.into(),
+            })))
+        }
+        Disconnect::Lost => None,
+    };
+    if let Some(close) = close {
+        let _ = tx.unbounded_send(close);
+        drop(tx);
+        // lets the close frame go out before the socket is dropped
+        let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT_IN_SECONDS);
+        let _ = tokio::time::timeout(timeout, forward_ws).await;
+    }
+    stopped
+}
+
+/// Dials `tcp://` addresses directly and anything else as a WebSocket,
+/// falling back to HTTP long polling when the server or a proxy answers the
+/// upgrade with another status. Fails with `TimedOut` once
+/// `--connect-timeout` has passed.
+async fn connect(
+    addr: &str,
+    connector: Option<&Connector>,
+    options: &ClientOptions,
+) -> Result<(FrameSink, FrameStream), tungstenite::Error> {
+    let timeout = Duration::from_secs(options.connect_timeout);
+    let timed_out = |_| {
+        tungstenite::Error::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no connection after {:?}", timeout),
+        ))
+    };
+    if let Some(target) = tcp_target(addr) {
+        let stream = tokio::time::timeout(timeout, TcpStream::connect(target))
+            .await
+            .map_err(timed_out)??;
+        return Ok(framed(stream, max_message_size(options)));
+    }
+    let connecting =
+        connect_async_tls_with_config(addr, Some(options.web_socket), connector.cloned());
+    let ws = match tokio::time::timeout(timeout, connecting)
+        .await
+        .map_err(timed_out)?
+    {
+        Ok((ws, _)) => ws,
+        Err(tungstenite::Error::Http(response))
+            if response.status() != StatusCode::SWITCHING_PROTOCOLS =>
+        {
+            info!(
+                "WebSocket upgrade to {} refused ({}), falling back to HTTP long polling",
+                addr,
+                response.status()
+            );
+            return Ok(longpoll::connect(
+                addr,
+                connector,
+                max_message_size(options),
+            )?);
+        }
+        Err(err) => return Err(err),
+    };
+    let (write, read) = ws.split();
+    Ok((Box::pin(write), Box::pin(read)))
 }
 
-fn encode(bytes: Vec<u8>) -> Vec<u8> {
-    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(&bytes[..]).unwrap();
-    encoder.finish().unwrap()
+/// A connection the server accepted the handshake on, and whether the
+/// server acknowledges frames.
+type Connection = (FrameSink, FrameStream, bool);
+
+/// Connects to `addr` and shakes hands, so a server that turns the client
+/// away fails like one that can't be reached.
+async fn open(
+    addr: &str,
+    connector: Option<&Connector>,
+    options: &ClientOptions,
+) -> io::Result<Connection> {
+    let (mut write, mut read) = connect(addr, connector, options)
+        .await
+        .map_err(io::Error::other)?;
+    let (acks, wire_format) = handshake(&mut write, &mut read, options).await?;
+    if wire_format != options.wire_format {
+        info!("The server doesn't take --wire-format compact, sending JSON");
+    }
+    let (write, read) = with_wire_format((write, read), wire_format);
+    Ok((write, read, acks))
 }
 
-fn decode(bytes: Vec<u8>) -> Vec<u8> {
-    let mut decoder = ZlibDecoder::new(&bytes[..]);
-    let mut decoded_bytes = Vec::new();
-    decoder.read_to_end(&mut decoded_bytes).unwrap();
-    decoded_bytes
+/// Connects to `addr` for a one-off `push` or `pull`.
+async fn connect_once(
+    addr: String,
+    options: &ClientOptions,
+) -> io::Result<(FrameSink, FrameStream, Arc<Mutex<ClientState>>)> {
+    let addr = with_room(addr, options.room.as_deref());
+    let connector = load_connector(
+        options.ca_cert.as_deref(),
+        options.pin_fingerprint.as_deref(),
+    )?;
+    let (write, read, _) = open(&addr, connector.as_ref(), options).await?;
+    let state = ClientState::new(Shared::standalone(options));
+    Ok((write, read, Arc::new(Mutex::new(state))))
 }
 
-fn serialize_clipboard_message(payload: ClipboardMessagePayload) -> String {
-    let message = ClipboardMessage { payload };
+/// Connects to `addr` and hangs up once the server accepted the handshake,
+/// for `copy-sync doctor`.
+pub async fn probe(addr: String, options: &ClientOptions) -> io::Result<()> {
+    let (mut write, _, _) = connect_once(addr, options).await?;
+    let _ = write.send(Message::Close(None)).await;
+    Ok(())
+}
 
-    serde_json::to_string(&message).unwrap()
+/// Sends the clipboard to `addr` once, for `copy-sync push`. Returns once
+/// the server has answered the close frame that follows the update, which
+/// it only reads after relaying the update.
+pub async fn push(addr: String, options: ClientOptions) -> io::Result<()> {
+    let (write, read, state) = connect_once(addr, &options).await?;
+    let (tx, rx) = futures_channel::mpsc::unbounded();
+    sync_clipboard(&tx, &state, &options).await;
+    drop(tx);
+    let frames: Vec<Message> = rx.collect().await;
+    let pushed = !frames.is_empty();
+    let signer = state.lock().unwrap().signer.clone();
+    deliver(write, read, frames, signer).await?;
+    if !pushed {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "nothing to push, the clipboard is empty or unreadable",
+        ));
+    }
+    Ok(())
+}
+
+/// Sends `text` to `addr` as if it had been copied, for `copy-sync
+/// send-text`. The local clipboard isn't read or written.
+pub async fn push_text(addr: String, text: String, options: ClientOptions) -> io::Result<()> {
+    let (write, read, state) = connect_once(addr, &options).await?;
+    let (cipher, signer) = {
+        let state = state.lock().unwrap();
+        (state.cipher.clone(), state.signer.clone())
+    };
+    let payload = serialize_clipboard_message(
+        ClipboardMessagePayload::Text(ClipboardMessageText { content: text }),
+        cipher.as_deref(),
+        now_in_millis(),
+    );
+    let max = max_message_size(&options);
+    if payload.len() > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "text of {} bytes exceeds the {} bytes limit",
+                payload.len(),
+                max
+            ),
+        ));
+    }
+    deliver(write, read, vec![Message::Text(payload)], signer).await
+}
+
+/// Sends `frames` and then a close frame, and waits for the server to answer
+/// it, which it only does after relaying the frames.
+async fn deliver(
+    mut write: FrameSink,
+    mut read: FrameStream,
+    frames: Vec<Message>,
+    signer: Option<Arc<Signer>>,
+) -> io::Result<()> {
+    for frame in frames {
+        let frame = match &signer {
+            Some(signer) => sign_frame(frame, signer),
+            None => frame,
+        };
+        write.send(frame).await.map_err(io::Error::other)?;
+    }
+    let frame = CloseFrame {
+        code: CloseCode::Normal,
+        reason: "pushed".into(),
+    };
+    write
+        .send(Message::Close(Some(frame)))
+        .await
+        .map_err(io::Error::other)?;
+    let closed = async {
+        // the replayed room state can arrive before the server gets to the close
+        while let Some(Ok(message)) = read.next().await {
+            if message.is_close() {
+                break;
+            }
+        }
+    };
+    let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT_IN_SECONDS);
+    if tokio::time::timeout(timeout, closed).await.is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "server did not acknowledge the update",
+        ));
+    }
+    Ok(())
 }
 
-async fn check_clipboard(sender: UnboundedSender<Message>, state: Arc<Mutex<ClientState>>) {
+/// Waits for the first update from `addr` and applies it, for
+/// `copy-sync pull`. The server replays the room's latest update on join,
+/// so this usually returns right away.
+pub async fn pull(addr: String, options: ClientOptions) -> io::Result<()> {
+    let (mut write, mut read, state) = connect_once(addr, &options).await?;
+    let before = state.lock().unwrap().id.clone();
+    let heartbeat_timeout = Duration::from_secs(options.heartbeat_timeout);
+    let mut ping = tokio::time::interval(heartbeat_timeout / 3);
+    let mut deadline = Instant::now() + heartbeat_timeout;
     loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-        let mut state = state.lock().unwrap();
-        let mut clipboard = Clipboard::new().unwrap();
-        let current = clipboard.get_image();
-        match current {
-            Ok(current) => {
-                if let ClipboardCache::Image(image) = &state.cache {
-                    if image.bytes == current.bytes {
-                        continue;
-                    }
-                }
-                let payload = serialize_clipboard_message(ClipboardMessagePayload::Image(
-                    ClipboardMessageImage {
-                        width: current.width,
-                        height: current.height,
-                    },
+        tokio::select! {
+            _ = ping.tick() => {
+                write.send(Message::Ping(Vec::new())).await.map_err(io::Error::other)?;
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server missed its heartbeat",
                 ));
-                sender.unbounded_send(Message::Text(payload)).unwrap();
-                // compress image
-                sender
-                    .unbounded_send(Message::Binary(encode(current.bytes.to_vec())))
-                    .unwrap();
-                state.cache = ClipboardCache::Image(current);
-            }
-            Err(arboard::Error::ContentNotAvailable) => {
-                let current = clipboard.get_text();
-                match current {
-                    Ok(current) => {
-                        if let ClipboardCache::Text(text) = &state.cache {
-                            if text == &current {
-                                continue;
-                            }
-                        }
-                        let payload = serialize_clipboard_message(ClipboardMessagePayload::Text(
-                            ClipboardMessageText {
-                                content: current.to_string(),
-                            },
+            }
+            message = read.next() => {
+                deadline = Instant::now() + heartbeat_timeout;
+                match message {
+                    Some(Ok(Message::Close(_))) | None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "server closed the connection",
                         ));
-                        sender.unbounded_send(Message::Text(payload)).unwrap();
-                        state.cache = ClipboardCache::Text(current);
                     }
-                    Err(_) => {}
+                    Some(Ok(message)) => handle_message(message, state.clone(), &options).await,
+                    Some(Err(err)) => return Err(io::Error::other(err)),
                 }
             }
-            Err(_) => {}
+        }
+        // chunks of an image don't count until the whole image is in
+        if state.lock().unwrap().id != before {
+            break;
         }
     }
+    let frame = CloseFrame {
+        code: CloseCode::Normal,
+        reason: "pulled".into(),
+    };
+    let _ = write.send(Message::Close(Some(frame))).await;
+    Ok(())
 }
 
-fn generate_ulid() -> String {
-    let ulid = Ulid::new();
-    ulid.to_string()
+/// Writes what the clipboard holds to `path` as the frames `push` would send,
+/// laid out as in the `tcp` transport, for `copy-sync snapshot save`.
+pub async fn save_snapshot(path: &Path, options: ClientOptions) -> io::Result<()> {
+    let state = Arc::new(Mutex::new(ClientState::new(Shared::standalone(&options))));
+    let (tx, rx) = futures_channel::mpsc::unbounded();
+    sync_clipboard(&tx, &state, &options).await;
+    drop(tx);
+    let frames: Vec<Message> = rx.collect().await;
+    if frames.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "nothing to save, the clipboard is empty or unreadable",
+        ));
+    }
+    let mut snapshot = Vec::new();
+    for frame in frames {
+        snapshot.extend(encode_frame(frame)?);
+    }
+    fs::write(path, snapshot)
 }
 
-fn handle_message(message: Message, state: Arc<Mutex<ClientState>>) {
-    let mut state = state.lock().unwrap();
-    match message {
-        Message::Text(text) => {
-            let deserialized: ClipboardMessage = serde_json::from_str(&text).unwrap();
-            match deserialized.payload {
-                ClipboardMessagePayload::Text(payload) => {
-                    let mut clipboard = Clipboard::new().unwrap();
-                    let result = clipboard.set_text(&payload.content);
-                    if result.is_err() {
-                        println!("set text error: {:?}", result);
-                    }
-                    state.cache = ClipboardCache::Text(payload.content);
-                    state.id = generate_ulid();
-                    state.timestamp = 0;
+/// Puts a snapshot from [`save_snapshot`] back on the clipboard as if a peer
+/// had sent it, for `copy-sync snapshot restore`. A snapshot saved with
+/// `--key` needs the same key.
+pub async fn restore_snapshot(path: &Path, options: ClientOptions) -> io::Result<()> {
+    let snapshot = fs::read(path)?;
+    let state = Arc::new(Mutex::new(ClientState::new(Shared::standalone(&options))));
+    let before = state.lock().unwrap().id.clone();
+    let mut frames = &snapshot[..];
+    while let Some(frame) = read_frame(&mut frames, MAX_MESSAGE_SIZE_IN_BYTES)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a snapshot: {}", path.display(), err),
+            )
+        })?
+    {
+        handle_message(frame, state.clone(), &options).await;
+    }
+    if state.lock().unwrap().id == before {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("nothing restored from {}", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Adds the `room` query parameter the server uses to scope broadcasts.
+fn with_room(addr: String, room: Option<&str>) -> String {
+    let Some(room) = room else {
+        return addr;
+    };
+    match Url::parse(&addr) {
+        Ok(mut url) => {
+            url.query_pairs_mut().append_pair("room", room);
+            url.to_string()
+        }
+        Err(err) => {
+            warn!("invalid address {}: {}", addr, err);
+            addr
+        }
+    }
+}
+
+/// Where the client stands with its servers, reported through the
+/// `status` channel of [`start`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// trying the servers for the first time
+    Connecting,
+    Connected,
+    /// the connection dropped or every server failed, trying again
+    Reconnecting,
+    /// every server failed in a row, waiting out the backoff
+    Failed,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::Failed => "failed, waiting to retry",
+        };
+        f.write_str(state)
+    }
+}
+
+fn report(status: &watch::Sender<ConnectionState>, state: ConnectionState) {
+    status.send_if_modified(|current| {
+        let changed = *current != state;
+        *current = state;
+        changed
+    });
+}
+
+/// Keeps one status line on stderr up to date, or prints a line per change
+/// when stderr isn't a terminal.
+pub async fn print_status(mut status: watch::Receiver<ConnectionState>) {
+    let terminal = io::stderr().is_terminal();
+    loop {
+        let state = *status.borrow_and_update();
+        if terminal {
+            eprint!("\r\x1b[2Kcopy-sync: {}", state);
+        } else {
+            eprintln!("copy-sync: {}", state);
+        }
+        if status.changed().await.is_err() {
+            if terminal {
+                eprintln!();
+            }
+            return;
+        }
+    }
+}
+
+/// Connects to the first server in `addrs` that accepts, moving on to the
+/// next one when a connect fails. The server that last accepted is tried
+/// first on reconnect, and the backoff only kicks in once every server has
+/// failed in a row. Fails once `max_retries` connects in a row have failed.
+pub async fn start(
+    addrs: Vec<String>,
+    options: ClientOptions,
+    manual: Option<Arc<Notify>>,
+    status: watch::Sender<ConnectionState>,
+    traffic: Arc<Traffic>,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    report(&status, ConnectionState::Connecting);
+    let addrs: Vec<String> = addrs
+        .into_iter()
+        .map(|addr| with_room(addr, options.room.as_deref()))
+        .collect();
+    let cipher = options
+        .key
+        .as_deref()
+        .map(|key| Arc::new(Cipher::from_passphrase(key)));
+    let connector = load_connector(
+        options.ca_cert.as_deref(),
+        options.pin_fingerprint.as_deref(),
+    )?;
+    let mut backoff = Backoff::new(
+        Duration::from_millis(INITIAL_RETRY_CONNECT_INTERVAL_IN_MILLISECONDS),
+        Duration::from_secs(options.max_retry_interval),
+    );
+    let audit = match options.audit_log.as_deref() {
+        Some(path) => Some(Arc::new(AuditLog::open(path, options.audit_content)?)),
+        None => None,
+    };
+    let mut serving = Vec::new();
+    let history = Arc::new(Mutex::new(History::new(options.history_size)));
+    if options.history_size > 0 {
+        serving.push(spawn(history::serve(
+            history.clone(),
+            options.history_socket.clone(),
+        )));
+    }
+    let inbox = Arc::new(Mutex::new(Inbox::new(INBOX_SIZE)));
+    let paused = Arc::new(AtomicBool::new(false));
+    if let Some(path) = &options.control_socket {
+        serving.push(spawn(control::serve(paused.clone(), path.clone())));
+    }
+    let shared = Shared {
+        cipher,
+        signer: options
+            .sign_key
+            .as_deref()
+            .map(|key| Arc::new(Signer::new(key))),
+        history: history.clone(),
+        inbox: inbox.clone(),
+        audit,
+        paused,
+        traffic,
+        synced: Arc::default(),
+        clipboard: ClipboardThread::spawn(options.clipboard_backend.clone()),
+        pending: Arc::default(),
+        oversized: Arc::default(),
+    };
+    if options.read_only_clipboard {
+        let (pasted, options) = (inbox.clone(), options.clone());
+        let clipboard = shared.clipboard.clone();
+        serving.push(spawn(inbox::serve(
+            inbox.clone(),
+            options.inbox_socket.clone(),
+            move |item| paste_received(item, clipboard.clone(), options.clone(), pasted.clone()),
+        )));
+    }
+    // the sockets go away with the client, not with the process
+    let result = async {
+        let mut current = 0;
+        let mut failed = 0;
+        // unlike `failed`, not reset when going round the servers again
+        let mut failed_in_a_row = 0;
+        loop {
+            let addr = &addrs[current];
+            let result = tokio::select! {
+                result = open(addr, connector.as_ref(), &options) => result,
+                _ = shutdown.wait() => return Ok(()),
+            };
+            match result {
+                Ok(connection) => {
+                    info!("Connected: {}", addr);
+                    report(&status, ConnectionState::Connected);
+                    if options.notify_connection {
+                        alert(
+                            &options,
+                            "copy-sync connected",
+                            &format!("Syncing with {}", addr),
+                        );
+                    }
+                    backoff.reset();
+                    failed = 0;
+                    failed_in_a_row = 0;
+                    if run(
+                        connection,
+                        options.clone(),
+                        shared.clone(),
+                        manual.clone(),
+                        &mut shutdown,
+                    )
+                    .await
+                    {
+                        info!(
+                            "Disconnected: {} ({} bytes sent, {} received)",
+                            addr,
+                            shared.traffic.sent(),
+                            shared.traffic.received()
+                        );
+                        return Ok(());
+                    }
+                    info!("Connection lost: {}", addr);
+                    report(&status, ConnectionState::Reconnecting);
+                    if options.notify_connection {
+                        alert(
+                            &options,
+                            "copy-sync disconnected",
+                            &format!("Not syncing, reconnecting to {}", addr),
+                        );
+                    }
                 }
-                ClipboardMessagePayload::Image(payload) => {
-                    state.image_info = payload;
-                    state.id = generate_ulid();
-                    state.timestamp = 0;
+                // the same token would only be refused again, the same client
+                // not understood again
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                    ) =>
+                {
+                    error!("Connect to {} failed: {}", addr, err);
+                    return Err(err);
+                }
+                Err(err) => {
+                    warn!("Connect to {} failed: {:?}", addr, err);
+                    failed_in_a_row += 1;
+                    if let Some(max_retries) = options.max_retries {
+                        if failed_in_a_row >= max_retries {
+                            warn!(
+                                "Giving up after {} failed connects in a row",
+                                failed_in_a_row
+                            );
+                            return Err(io::Error::new(
+                                io::ErrorKind::NotConnected,
+                                format!("no connection after {} attempts", failed_in_a_row),
+                            ));
+                        }
+                    }
+                    current = (current + 1) % addrs.len();
+                    failed += 1;
+                    if failed < addrs.len() {
+                        continue;
+                    }
+                    failed = 0;
+                    report(&status, ConnectionState::Failed);
                 }
             }
+            let delay = backoff.next_delay();
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.wait() => return Ok(()),
+            }
+            report(&status, ConnectionState::Reconnecting);
+            info!("Reconnecting: {}...", addrs[current]);
         }
-        Message::Binary(binary) => {
-            let mut clipboard = Clipboard::new().unwrap();
-            let bytes = decode(binary);
+    }
+    .await;
+    for task in serving {
+        task.abort();
+        let _ = task.await;
+    }
+    result
+}
 
-            let image = ImageData {
-                width: state.image_info.width,
-                height: state.image_info.height,
-                bytes: Cow::from(bytes),
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::backend::MemoryClipboard;
+
+    /// A client on its own memory clipboard, and what it sends.
+    struct TestClient {
+        board: MemoryClipboard,
+        state: Arc<Mutex<ClientState>>,
+        options: ClientOptions,
+        sender: UnboundedSender<Message>,
+        sent: UnboundedReceiver<Message>,
+    }
+
+    impl TestClient {
+        fn new(options: ClientOptions) -> TestClient {
+            let board = MemoryClipboard::default();
+            let options = ClientOptions {
+                clipboard_backend: ClipboardBackend::Memory(board.clone()),
+                notify: false,
+                ..options
             };
-            let result = clipboard.set_image(image.clone());
-            if result.is_err() {
-                println!("set image error: {:?}", result);
+            let state = Arc::new(Mutex::new(ClientState::new(Shared::standalone(&options))));
+            let (sender, sent) = futures_channel::mpsc::unbounded();
+            TestClient {
+                board,
+                state,
+                options,
+                sender,
+                sent,
             }
-            state.cache = ClipboardCache::Image(image);
-            let info = &state.image_info;
-            notify(&format!("W: {} H: {}", info.width, info.height));
         }
-        _ => {
-            println!("unknow, {}", message);
+
+        async fn sync(&mut self) -> Vec<Message> {
+            sync_clipboard(&self.sender, &self.state, &self.options).await;
+            self.drain()
+        }
+
+        /// The frames sent since the last call.
+        fn drain(&mut self) -> Vec<Message> {
+            let mut frames = Vec::new();
+            while let Ok(Some(frame)) = self.sent.try_next() {
+                frames.push(frame);
+            }
+            frames
+        }
+
+        async fn receive(&self, frame: Message) {
+            handle_message(frame, self.state.clone(), &self.options).await;
         }
     }
-}
 
-async fn run(ws: WebSocketStream<MaybeTlsStream<TcpStream>>) {
-    let state = Arc::new(Mutex::new(ClientState {
-        cache: ClipboardCache::Text(String::new()),
-        image_info: ClipboardMessageImage {
-            width: 0,
-            height: 0,
-        },
-        id: generate_ulid(),
-        timestamp: 0,
-    }));
+    fn image(width: usize, height: usize) -> ImageData<'static> {
+        let bytes = (0..width * height * 4).map(|byte| byte as u8).collect();
+        ImageData {
+            width,
+            height,
+            bytes: Cow::Owned(bytes),
+        }
+    }
 
-    let (tx, rx) = futures_channel::mpsc::unbounded();
+    fn text_of(frame: &Message) -> String {
+        match deserialize_clipboard_message(frame.to_text().unwrap(), None) {
+            Ok((ClipboardMessagePayload::Text(text), _)) => text.content,
+            other => panic!("expected text, got {:?}", other.map(|_| ())),
+        }
+    }
 
-    let (write, read) = ws.split();
+    fn text_frame(content: &str) -> Message {
+        let payload = ClipboardMessagePayload::Text(ClipboardMessageText {
+            content: content.to_string(),
+        });
+        Message::Text(serialize_clipboard_message(payload, None, now_in_millis()))
+    }
 
-    let forward_ws = rx.map(Ok).forward(write);
+    #[tokio::test]
+    async fn sends_copied_text_once() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.board.set_text("hello");
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(text_of(&frames[0]), "hello");
+        assert!(client.sync().await.is_empty());
+    }
 
-    let handler = {
-        read.for_each(|message| async {
-            match message {
-                Ok(message) => {
-                    handle_message(message, state.clone());
-                }
-                Err(err) => {
-                    println!("{:?}", err);
-                }
+    #[tokio::test]
+    async fn sends_copied_image() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.board.set_image(image(3, 2));
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        let Message::Binary(frame) = &frames[0] else {
+            panic!("expected an image frame");
+        };
+        let (header, _) = unpack_frame::<ClipboardMessageImage>(frame).unwrap();
+        assert_eq!((header.width, header.height), (3, 2));
+        assert!(client.sync().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_clear_once_emptied() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.board.set_text("hello");
+        client.sync().await;
+        client.board.clear();
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        let message = deserialize_clipboard_message(frames[0].to_text().unwrap(), None);
+        assert!(matches!(message, Ok((ClipboardMessagePayload::Clear, _))));
+    }
+
+    #[tokio::test]
+    async fn applies_text_without_echoing_it() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.receive(text_frame("from a peer")).await;
+        assert_eq!(client.board.text().as_deref(), Some("from a peer"));
+        assert!(client.sync().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn applies_a_frame_after_ones_it_cant_read() {
+        let mut client = TestClient::new(ClientOptions::default());
+        for unreadable in [
+            "not json",
+            r#"{"payload":"#,
+            r#"{"payload":{"Hologram":{"depth":3}},"timestamp":1}"#,
+        ] {
+            client.receive(Message::Text(unreadable.to_string())).await;
+        }
+        client
+            .receive(Message::Binary(vec![0, 0, 0, 9, b'{']))
+            .await;
+        assert_eq!(client.board.text(), None);
+        client.receive(text_frame("readable")).await;
+        assert_eq!(client.board.text().as_deref(), Some("readable"));
+        assert!(client.sync().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn applies_image_without_echoing_it() {
+        let mut sender = TestClient::new(ClientOptions::default());
+        let mut receiver = TestClient::new(ClientOptions::default());
+        sender.board.set_image(image(4, 4));
+        for frame in sender.sync().await {
+            receiver.receive(frame).await;
+        }
+        let applied = receiver.board.image().expect("no image applied");
+        assert_eq!(applied.bytes, image(4, 4).bytes);
+        assert!(receiver.sync().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn caches_an_applied_image_without_copying_it() {
+        let client = TestClient::new(ClientOptions::default());
+        let applied = image(64, 64);
+        let pixels = applied.bytes.as_ptr();
+        let write = {
+            let mut state = client.state.lock().unwrap();
+            apply_image(applied, None, None, &mut state, &client.options)
+        };
+        let Some(ClipboardCache::Image(cached, _)) = write.unwrap().await else {
+            panic!("the image wasn't cached");
+        };
+        assert!(matches!(cached.bytes, Cow::Owned(_)));
+        assert_eq!(cached.bytes.as_ptr(), pixels);
+        assert_eq!(client.board.image().unwrap().bytes, cached.bytes);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_image_of_the_wrong_size() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.receive(text_frame("kept")).await;
+        let header = ClipboardMessageImage {
+            width: 4,
+            height: 4,
+            codec: ImageCodec::Raw,
+            compression: Compression::None,
+            peer: None,
+            name: None,
+            timestamp: Some(now_in_millis()),
+            chunk: None,
+            seq: None,
+        };
+        let short = image(3, 3).bytes;
+        client
+            .receive(Message::Binary(pack_frame(&header, &short)))
+            .await;
+        assert_eq!(client.board.text().as_deref(), Some("kept"));
+
+        let mut wrong = image(3, 3);
+        wrong.width = 4;
+        client.board.set_image(wrong);
+        assert!(client.sync().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn applies_clear_without_echoing_it() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.board.set_text("local");
+        client.sync().await;
+        let clear =
+            serialize_clipboard_message(ClipboardMessagePayload::Clear, None, now_in_millis());
+        client.receive(Message::Text(clear)).await;
+        assert_eq!(client.board.text(), None);
+        assert!(client.sync().await.is_empty());
+    }
+
+    fn is_image(frame: &Message) -> bool {
+        matches!(frame, Message::Binary(frame)
+            if unpack_frame::<ClipboardMessageImage>(frame).is_some())
+    }
+
+    #[tokio::test]
+    async fn sends_each_change_between_text_and_image() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.board.set_text("same");
+        assert_eq!(text_of(&client.sync().await[0]), "same");
+        client.board.set_image(image(2, 2));
+        assert!(is_image(&client.sync().await[0]));
+        // the text copied again is a change from the image
+        client.board.set_text("same");
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(text_of(&frames[0]), "same");
+        client.board.set_image(image(2, 2));
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        assert!(is_image(&frames[0]));
+    }
+
+    #[tokio::test]
+    async fn resends_applied_text_copied_again_after_an_image() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.receive(text_frame("from a peer")).await;
+        assert!(client.sync().await.is_empty());
+        client.board.set_image(image(2, 2));
+        assert!(is_image(&client.sync().await[0]));
+        client.board.set_text("from a peer");
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(text_of(&frames[0]), "from a peer");
+    }
+
+    #[tokio::test]
+    async fn keeps_polling_after_a_read_error() {
+        let mut client = TestClient::new(ClientOptions::default());
+        client.board.set_unreadable();
+        assert!(client.sync().await.is_empty());
+        assert!(client.state.lock().unwrap().read_failure.is_some());
+        client.board.set_text("readable again");
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(text_of(&frames[0]), "readable again");
+        assert_eq!(client.state.lock().unwrap().read_failure, None);
+    }
+
+    #[tokio::test]
+    async fn skips_text_over_max_message_size_and_goes_on() {
+        let mut client = TestClient::new(ClientOptions {
+            web_socket: web_socket_config(Some(1024), None, None, false),
+            ..ClientOptions::default()
+        });
+        client.board.set_text("x".repeat(1024));
+        assert!(client.sync().await.is_empty());
+        // reported once, not on every tick
+        assert!(client.sync().await.is_empty());
+        client.board.set_text("fits");
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(text_of(&frames[0]), "fits");
+    }
+
+    #[tokio::test]
+    async fn skips_text_over_max_text_bytes() {
+        let mut client = TestClient::new(ClientOptions {
+            max_text_bytes: Some(4),
+            ..ClientOptions::default()
+        });
+        client.board.set_text("too long");
+        assert!(client.sync().await.is_empty());
+        client.board.set_text("fits");
+        assert_eq!(client.sync().await.len(), 1);
+    }
+
+    #[test]
+    fn saved_files_never_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "mine").unwrap();
+        let file = ClipboardMessageFile {
+            name: "../notes.txt".to_string(),
+            content: BASE64.encode("theirs"),
+        };
+        let paths = save_files(vec![file], Compression::None, dir.path()).unwrap();
+        assert_eq!(paths, [dir.path().join("notes-1.txt")]);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("notes.txt")).unwrap(),
+            "mine"
+        );
+        assert_eq!(fs::read_to_string(&paths[0]).unwrap(), "theirs");
+    }
+
+    #[tokio::test]
+    async fn skips_image_over_max_image_bytes() {
+        let mut client = TestClient::new(ClientOptions {
+            max_image_bytes: Some(16),
+            compress: Compression::None,
+            ..ClientOptions::default()
+        });
+        client.board.set_image(image(4, 4));
+        assert!(client.sync().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn skips_an_image_over_the_limit_and_goes_on() {
+        let mut client = TestClient::new(ClientOptions {
+            compress: Compression::None,
+            ..ClientOptions::default()
+        });
+        let side = 8 << 10;
+        let bytes = vec![0; MAX_IMAGE_SIZE_IN_BYTES.div_ceil(side * 4) * side * 4];
+        client.board.set_image(ImageData {
+            width: side,
+            height: bytes.len() / side / 4,
+            bytes: Cow::Owned(bytes),
+        });
+        assert!(client.sync().await.is_empty());
+        client.board.set_text("still syncing");
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(text_of(&frames[0]), "still syncing");
+    }
+
+    /// A server on a free port that answers every handshake with `answers`.
+    async fn handshake_server(answers: Vec<Message>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let answers = answers.clone();
+                spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    let _ = ws.next().await;
+                    for answer in answers {
+                        let _ = ws.send(answer).await;
+                    }
+                    let _ = ws.next().await;
+                });
             }
-        })
-    };
+        });
+        format!("ws://{}", addr)
+    }
+
+    fn ack(version: u32, major: u32) -> Message {
+        let ack = HandshakeAck {
+            version,
+            major,
+            acks: true,
+            wire_format: WireFormat::Json,
+        };
+        Message::Text(serde_json::to_string(&ack).unwrap())
+    }
 
-    let check_clipboard_handler = spawn(check_clipboard(tx, state.clone()));
+    fn small_messages(options: ClientOptions) -> ClientOptions {
+        ClientOptions {
+            web_socket: web_socket_config(Some(16 << 10), None, None, false),
+            notify: false,
+            ..options
+        }
+    }
 
-    pin_mut!(forward_ws, handler);
+    #[tokio::test]
+    async fn reports_a_message_over_max_message_size_once() {
+        let too_large = Message::Text("x".repeat(32 << 10));
+        let addr = handshake_server(vec![ack(PROTOCOL_VERSION, PROTOCOL_MAJOR), too_large]).await;
+        let options = small_messages(ClientOptions::default());
+        let state = Arc::new(Mutex::new(ClientState::new(Shared::standalone(&options))));
+        let (traffic, oversized) = (Traffic::default(), AtomicBool::new(false));
+        for _ in 0..2 {
+            let (_, mut read, _) = open(&addr, None, &options).await.unwrap();
+            let disconnect = receive(&mut read, &state, &options, &traffic, &oversized).await;
+            assert!(matches!(disconnect, Disconnect::Lost));
+            // the alert goes out while this is still unset, so only once
+            assert!(oversized.load(Ordering::Relaxed));
+        }
+    }
 
-    select(forward_ws, handler).await;
+    #[tokio::test]
+    async fn push_text_refuses_text_over_max_message_size() {
+        let addr = handshake_server(vec![ack(PROTOCOL_VERSION, PROTOCOL_MAJOR)]).await;
+        let options = small_messages(ClientOptions::default());
+        let err = push_text(addr, "x".repeat(16 << 10), options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
 
-    check_clipboard_handler.abort();
-}
+    #[tokio::test]
+    async fn image_with_text_over_max_message_size_goes_alone() {
+        let mut client = TestClient::new(small_messages(ClientOptions {
+            compress: Compression::None,
+            image_codec: ImageCodec::Raw,
+            ..ClientOptions::default()
+        }));
+        let text = "x".repeat(16 << 10);
+        send_image(
+            image(16, 16),
+            Some(text),
+            &client.sender,
+            &mut client.state.lock().unwrap(),
+            &client.options,
+        );
+        let frames = client.drain();
+        assert_eq!(frames.len(), 1);
+        assert!(is_image(&frames[0]));
+    }
 
-pub async fn start(addr: String) {
-    loop {
-        let result = connect_async_with_config(&addr, Some(WEB_SOCKET_CONFIG)).await;
-        match result {
-            Ok((ws, _)) => {
-                println!("Connected: {}", addr);
-                run(ws).await;
+    #[tokio::test]
+    async fn image_chunks_fit_max_message_size() {
+        let mut client = TestClient::new(small_messages(ClientOptions {
+            compress: Compression::None,
+            image_codec: ImageCodec::Raw,
+            ..ClientOptions::default()
+        }));
+        client.board.set_image(image(64, 64));
+        let frames = client.sync().await;
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.len() <= 16 << 10));
+    }
+
+    #[tokio::test]
+    async fn skips_files_over_max_message_size() {
+        let mut client = TestClient::new(small_messages(ClientOptions::default()));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.bin");
+        fs::write(&path, vec![0; 32 << 10]).unwrap();
+        send_files(
+            vec![path.clone()],
+            &client.sender,
+            &mut client.state.lock().unwrap(),
+            &client.options,
+        );
+        assert!(client.drain().is_empty());
+    }
+
+    /// Answers every connection with `response` in place of the upgrade.
+    async fn http_server(response: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = longpoll::read_head(&mut stream, Duration::from_secs(1)).await;
+                let _ = stream.write_all(response.as_bytes()).await;
             }
-            Err(err) => {
-                println!("{:?}", err);
-                tokio::time::sleep(tokio::time::Duration::from_secs(
-                    RETRY_CONNECT_INTERVAL_IN_SECONDS,
-                ))
-                .await;
-                println!("Reconnecting: {}...", addr);
+        });
+        format!("ws://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn only_a_refused_upgrade_falls_back_to_long_polling() {
+        let options = ClientOptions::default();
+        let refused = http_server("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(connect(&refused, None, &options).await.is_ok());
+        let broken = http_server("HTTP/1.1 101 Switching Protocols\r\n\r\n").await;
+        assert!(connect(&broken, None, &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn an_accepted_handshake_opens_the_connection() {
+        let addr = handshake_server(vec![ack(PROTOCOL_VERSION, PROTOCOL_MAJOR)]).await;
+        let (_, _, acks) = open(&addr, None, &ClientOptions::default()).await.unwrap();
+        assert!(acks);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_server_of_another_major() {
+        let addr = handshake_server(vec![ack(PROTOCOL_VERSION + 1, PROTOCOL_MAJOR + 1)]).await;
+        let Err(err) = open(&addr, None, &ClientOptions::default()).await else {
+            panic!("connected to a server of another major");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn skips_a_payload_from_a_newer_version() {
+        let newer = r#"{"payload":{"Hologram":{"depth":3}},"timestamp":1}"#;
+        assert!(matches!(
+            deserialize_clipboard_message(newer, None),
+            Err(DecodeError::Unsupported(_))
+        ));
+        let garbled = r#"{"payload":"#;
+        assert!(matches!(
+            deserialize_clipboard_message(garbled, None),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    fn close(code: CloseCode, reason: &'static str) -> Message {
+        Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn a_token_the_server_never_answers_fails_the_connect() {
+        let addr = handshake_server(Vec::new()).await;
+        let options = ClientOptions {
+            token: Some("secret".to_string()),
+            ..ClientOptions::default()
+        };
+        let Err(err) = open(&addr, None, &options).await else {
+            panic!("connected without the token being accepted");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn a_rejected_handshake_fails_the_connect() {
+        let addr = handshake_server(vec![close(CloseCode::Again, "too many rooms")]).await;
+        let Err(err) = open(&addr, None, &ClientOptions::default()).await else {
+            panic!("connected despite the rejected handshake");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_refused_token() {
+        let addr = handshake_server(vec![close(CloseCode::Policy, "unauthorized")]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let options = ClientOptions {
+            token: Some("wrong".to_string()),
+            control_socket: Some(dir.path().join("control.sock")),
+            clipboard_backend: ClipboardBackend::Memory(MemoryClipboard::default()),
+            notify: false,
+            ..ClientOptions::default()
+        };
+        let (status, _) = watch::channel(ConnectionState::Connecting);
+        let (result, started) = futures_channel::oneshot::channel();
+        let _client = Handle::spawn(move |shutdown| async move {
+            let traffic = Arc::default();
+            let _ = result.send(start(vec![addr], options, None, status, traffic, shutdown).await);
+        });
+        // without retrying, which would wait out the backoff
+        let ended = tokio::time::timeout(Duration::from_secs(1), started).await;
+        let err = ended.unwrap().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_closes_the_control_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let client = Client::builder()
+            .addr("ws://127.0.0.1:1")
+            .clipboard_backend(ClipboardBackend::Memory(MemoryClipboard::default()))
+            .control_socket(&path)
+            .spawn()
+            .unwrap();
+        let listening = async {
+            while tokio::net::UnixStream::connect(&path).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
-        }
+        };
+        tokio::time::timeout(Duration::from_secs(1), listening)
+            .await
+            .unwrap();
+        client.shutdown().await;
+        assert!(tokio::net::UnixStream::connect(&path).await.is_err());
     }
 }
@@ -0,0 +1,274 @@
+use std::io::{self, Cursor, Read, Write};
+
+use crate::config::MAX_IMAGE_SIZE_IN_BYTES;
+pub use crate::protocol::{Compression, ImageCodec};
+
+/// Default for `--compression`, zlib's own default level.
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/// Compresses `bytes` with `compression` at `level`, 0 (fastest) to 9
+/// (smallest).
+pub fn compress(bytes: &[u8], compression: Compression, level: u32) -> Vec<u8> {
+    match compression {
+        Compression::Zlib => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(level));
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        }
+        // zstd reads 0 as its default level, 1 is its fastest
+        Compression::Zstd => zstd::encode_all(bytes, level.max(1) as i32).unwrap(),
+        Compression::None => bytes.to_vec(),
+    }
+}
+
+/// zstd frames asking for a longer window than levels up to 9 use take that
+/// much memory to decode, so they are refused.
+const ZSTD_WINDOW_LOG_MAX: u32 = 24;
+
+/// Reverses [`compress`], or `None` if `bytes` weren't compressed that way
+/// or decompress to more than the largest image.
+pub fn decompress(bytes: &[u8], compression: Compression) -> Option<Vec<u8>> {
+    let limit = MAX_IMAGE_SIZE_IN_BYTES as u64;
+    match compression {
+        Compression::Zlib => read_at_most(flate2::read::ZlibDecoder::new(bytes), limit),
+        Compression::Zstd => {
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(bytes).ok()?;
+            decoder.window_log_max(ZSTD_WINDOW_LOG_MAX).ok()?;
+            read_at_most(decoder, limit)
+        }
+        Compression::None => Some(bytes.to_vec()),
+    }
+}
+
+/// Reads `reader` to the end, `None` if it fails or has more than `limit`
+/// bytes.
+fn read_at_most(reader: impl Read, limit: u64) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut decoded).ok()?;
+    (decoded.len() as u64 <= limit).then_some(decoded)
+}
+
+/// Encodes 8-bit RGBA pixels as a PNG, failing when `rgba` doesn't hold
+/// `width` by `height` of them or a PNG can't be that size.
+pub fn encode_png(width: usize, height: usize, rgba: &[u8]) -> io::Result<Vec<u8>> {
+    let too_large = |_| io::Error::new(io::ErrorKind::InvalidInput, "image too large for a PNG");
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(
+        &mut png,
+        u32::try_from(width).map_err(too_large)?,
+        u32::try_from(height).map_err(too_large)?,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(rgba).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)?;
+    Ok(png)
+}
+
+/// Decodes a PNG into `(width, height, rgba)` with 8-bit RGBA pixels, the
+/// layout images travel in, whatever color type and depth it was saved with.
+/// `None` if it doesn't decode.
+pub fn decode_png(png: &[u8]) -> Option<(usize, usize, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(Cursor::new(png));
+    // palettes, transparency chunks and depths other than 8 come out as
+    // 8-bit gray, gray and alpha, RGB or RGBA
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().ok()?;
+    let mut samples = vec![0; reader.output_buffer_size()?];
+    let info = reader.next_frame(&mut samples).ok()?;
+    if info.bit_depth != png::BitDepth::Eight {
+        return None;
+    }
+    let (width, height) = (info.width as usize, info.height as usize);
+    let channels = info.color_type.samples();
+    let rgba = samples
+        .chunks_exact(info.line_size)
+        .take(height)
+        .flat_map(|row| row[..width * channels].chunks_exact(channels))
+        .flat_map(|pixel| match *pixel {
+            [gray] => [gray, gray, gray, u8::MAX],
+            [gray, alpha] => [gray, gray, gray, alpha],
+            [r, g, b] => [r, g, b, u8::MAX],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!("8-bit pixels have 1 to 4 samples"),
+        })
+        .collect::<Vec<_>>();
+    (rgba.len() == width * height * 4).then_some((width, height, rgba))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        (0..10_000u32)
+            .flat_map(|n| (n % 251).to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn zlib_round_trips() {
+        let compressed = compress(&sample(), Compression::Zlib, DEFAULT_COMPRESSION_LEVEL);
+        assert!(compressed.len() < sample().len());
+        assert_eq!(decompress(&compressed, Compression::Zlib), Some(sample()));
+    }
+
+    #[test]
+    fn zstd_round_trips() {
+        let compressed = compress(&sample(), Compression::Zstd, DEFAULT_COMPRESSION_LEVEL);
+        assert!(compressed.len() < sample().len());
+        assert_eq!(decompress(&compressed, Compression::Zstd), Some(sample()));
+    }
+
+    #[test]
+    fn every_level_round_trips() {
+        for level in 0..=9 {
+            for (compression, name) in [(Compression::Zlib, "zlib"), (Compression::Zstd, "zstd")] {
+                let compressed = compress(&sample(), compression, level);
+                assert_eq!(
+                    decompress(&compressed, compression),
+                    Some(sample()),
+                    "{} at level {}",
+                    name,
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn none_passes_through() {
+        let sent = compress(&sample(), Compression::None, DEFAULT_COMPRESSION_LEVEL);
+        assert_eq!(sent, sample());
+        assert_eq!(decompress(&sent, Compression::None), Some(sample()));
+    }
+
+    #[test]
+    fn png_round_trips() {
+        let rgba: Vec<u8> = (0..3 * 2 * 4).map(|n| n as u8 * 10).collect();
+        let png = encode_png(3, 2, &rgba).unwrap();
+        assert_eq!(decode_png(&png), Some((3, 2, rgba)));
+    }
+
+    /// A PNG of `samples` saved as `color` at `depth`.
+    fn png_of(
+        (width, height): (u32, u32),
+        color: png::ColorType,
+        depth: png::BitDepth,
+        palette: Option<&[u8]>,
+        samples: &[u8],
+    ) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        if let Some(palette) = palette {
+            encoder.set_palette(palette);
+        }
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(samples).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    #[test]
+    fn png_of_any_color_type_decodes_to_rgba() {
+        use png::{BitDepth, ColorType};
+
+        let rgb = png_of(
+            (2, 1),
+            ColorType::Rgb,
+            BitDepth::Eight,
+            None,
+            &[1, 2, 3, 4, 5, 6],
+        );
+        assert_eq!(
+            decode_png(&rgb),
+            Some((2, 1, vec![1, 2, 3, 255, 4, 5, 6, 255]))
+        );
+        let gray = png_of((2, 1), ColorType::Grayscale, BitDepth::Eight, None, &[7, 8]);
+        assert_eq!(
+            decode_png(&gray),
+            Some((2, 1, vec![7, 7, 7, 255, 8, 8, 8, 255]))
+        );
+        let gray_alpha = png_of(
+            (1, 1),
+            ColorType::GrayscaleAlpha,
+            BitDepth::Eight,
+            None,
+            &[9, 10],
+        );
+        assert_eq!(decode_png(&gray_alpha), Some((1, 1, vec![9, 9, 9, 10])));
+        let indexed = png_of(
+            (2, 1),
+            ColorType::Indexed,
+            BitDepth::Eight,
+            Some(&[10, 20, 30, 40, 50, 60]),
+            &[1, 0],
+        );
+        assert_eq!(
+            decode_png(&indexed),
+            Some((2, 1, vec![40, 50, 60, 255, 10, 20, 30, 255]))
+        );
+        let deep = png_of(
+            (1, 1),
+            ColorType::Rgba,
+            BitDepth::Sixteen,
+            None,
+            &[1, 0, 2, 0, 3, 0, 4, 0],
+        );
+        assert_eq!(decode_png(&deep), Some((1, 1, vec![1, 2, 3, 4])));
+    }
+
+    #[test]
+    fn png_rows_of_less_than_a_byte_a_pixel_decode() {
+        // 3 pixels of 1 bit fill a byte of which 5 bits are padding, on each row
+        let bits = png_of(
+            (3, 2),
+            png::ColorType::Grayscale,
+            png::BitDepth::One,
+            None,
+            &[0b1010_0000, 0b0100_0000],
+        );
+        let (white, black) = ([255; 4], [0, 0, 0, 255]);
+        let expected = [white, black, white, black, white, black].concat();
+        assert_eq!(decode_png(&bits), Some((3, 2, expected)));
+    }
+
+    #[test]
+    fn png_needs_four_bytes_a_pixel() {
+        assert!(encode_png(3, 2, &[0; 3 * 2 * 4 - 1]).is_err());
+        assert!(encode_png(3, 2, &[0; 3 * 2 * 4 + 4]).is_err());
+        assert!(encode_png(0, 2, &[]).is_err());
+        assert!(encode_png(usize::MAX, 1, &[]).is_err());
+    }
+
+    #[test]
+    fn garbage_doesnt_decompress() {
+        assert_eq!(decompress(b"not compressed", Compression::Zlib), None);
+        assert_eq!(decompress(b"not compressed", Compression::Zstd), None);
+    }
+
+    #[test]
+    fn stops_reading_past_the_limit() {
+        let zeros = compress(&[0; 1000], Compression::Zlib, DEFAULT_COMPRESSION_LEVEL);
+        let decoder = || flate2::read::ZlibDecoder::new(&zeros[..]);
+        assert_eq!(
+            read_at_most(decoder(), 1000).map(|bytes| bytes.len()),
+            Some(1000)
+        );
+        assert_eq!(read_at_most(decoder(), 999), None);
+    }
+
+    #[test]
+    fn refuses_a_zstd_window_too_large() {
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 1).unwrap();
+        encoder.window_log(ZSTD_WINDOW_LOG_MAX + 1).unwrap();
+        encoder.write_all(&sample()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(zstd::decode_all(&compressed[..]).is_ok());
+        assert_eq!(decompress(&compressed, Compression::Zstd), None);
+    }
+}
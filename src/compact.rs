@@ -0,0 +1,247 @@
+//! The binary form of JSON that `--wire-format compact` sends text frames
+//! in: the part of CBOR (RFC 8949) JSON maps onto, so it describes itself
+//! like JSON does and carries the same values, field names included, in
+//! fewer bytes. It opens with the self-described CBOR tag, which no image
+//! frame can start with.
+
+use serde_json::{Map, Number, Value};
+
+/// Tag 55799, marking what follows as CBOR.
+pub const MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// Nesting past this is refused, as serde_json does.
+const MAX_DEPTH: usize = 128;
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+
+const FALSE: u8 = 20;
+const TRUE: u8 = 21;
+const NULL: u8 = 22;
+const FLOAT64: u8 = 27;
+
+/// Whether `bytes` are in this format rather than a frame of another kind.
+pub fn is_compact(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+pub fn to_vec(value: &Value) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    write_value(&mut out, value);
+    out
+}
+
+/// Reverses [`to_vec`], `None` if `bytes` aren't a single value in this
+/// format.
+pub fn from_slice(bytes: &[u8]) -> Option<Value> {
+    let mut reader = Reader {
+        bytes: bytes.strip_prefix(&MAGIC)?,
+    };
+    let value = reader.value(0)?;
+    reader.bytes.is_empty().then_some(value)
+}
+
+/// The major type in the top 3 bits, then `arg` in as few bytes as it fits.
+fn write_head(out: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    if arg < 24 {
+        out.push(major | arg as u8);
+    } else if let Ok(arg) = u8::try_from(arg) {
+        out.push(major | 24);
+        out.push(arg);
+    } else if let Ok(arg) = u16::try_from(arg) {
+        out.push(major | 25);
+        out.extend_from_slice(&arg.to_be_bytes());
+    } else if let Ok(arg) = u32::try_from(arg) {
+        out.push(major | 26);
+        out.extend_from_slice(&arg.to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&arg.to_be_bytes());
+    }
+}
+
+fn write_text(out: &mut Vec<u8>, text: &str) {
+    write_head(out, TEXT, text.len() as u64);
+    out.extend_from_slice(text.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(SIMPLE << 5 | NULL),
+        Value::Bool(false) => out.push(SIMPLE << 5 | FALSE),
+        Value::Bool(true) => out.push(SIMPLE << 5 | TRUE),
+        Value::Number(number) => {
+            if let Some(number) = number.as_u64() {
+                write_head(out, UNSIGNED, number);
+            } else if let Some(number) = number.as_i64() {
+                // -1 - n, which for a negative n is its complement
+                write_head(out, NEGATIVE, !number as u64);
+            } else {
+                let number = number.as_f64().unwrap_or_default();
+                out.push(SIMPLE << 5 | FLOAT64);
+                out.extend_from_slice(&number.to_bits().to_be_bytes());
+            }
+        }
+        Value::String(text) => write_text(out, text),
+        Value::Array(values) => {
+            write_head(out, ARRAY, values.len() as u64);
+            for value in values {
+                write_value(out, value);
+            }
+        }
+        Value::Object(object) => {
+            write_head(out, MAP, object.len() as u64);
+            for (key, value) in object {
+                write_text(out, key);
+                write_value(out, value);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    /// The major type, the low 5 bits and the argument they stand for.
+    fn head(&mut self) -> Option<(u8, u8, u64)> {
+        let initial = self.take(1)?[0];
+        let info = initial & 0x1f;
+        let arg = match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().ok()?) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().ok()?) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().ok()?),
+            _ => return None,
+        };
+        Some((initial >> 5, info, arg))
+    }
+
+    fn text(&mut self, len: u64) -> Option<String> {
+        let bytes = self.take(usize::try_from(len).ok()?)?;
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+
+    /// A count of items that each take a byte at least, so a forged one
+    /// can't make room for more than there are.
+    fn count(&self, len: u64) -> Option<usize> {
+        usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= self.bytes.len())
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let (major, info, arg) = self.head()?;
+        match major {
+            UNSIGNED => Some(Value::from(arg)),
+            NEGATIVE => Some(Value::from(-1 - i64::try_from(arg).ok()?)),
+            TEXT => self.text(arg).map(Value::String),
+            ARRAY => {
+                let len = self.count(arg)?;
+                let mut values = Vec::with_capacity(len);
+                for _ in 0..len {
+                    values.push(self.value(depth + 1)?);
+                }
+                Some(Value::Array(values))
+            }
+            MAP => {
+                let len = self.count(arg)?;
+                let mut object = Map::new();
+                for _ in 0..len {
+                    let (TEXT, _, key_len) = self.head()? else {
+                        return None;
+                    };
+                    let key = self.text(key_len)?;
+                    object.insert(key, self.value(depth + 1)?);
+                }
+                Some(Value::Object(object))
+            }
+            SIMPLE => match info {
+                FALSE => Some(Value::Bool(false)),
+                TRUE => Some(Value::Bool(true)),
+                NULL => Some(Value::Null),
+                FLOAT64 => Number::from_f64(f64::from_bits(arg)).map(Value::Number),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_every_kind_of_value() {
+        let value = json!({
+            "payload": {"Text": {"content": "héllo"}},
+            "timestamp": 1_700_000_000_000u64,
+            "seq": 23,
+            "peer": 24,
+            "offset": -25,
+            "min": i64::MIN,
+            "max": u64::MAX,
+            "ratio": 0.5,
+            "flags": [true, false, null],
+            "empty": {},
+            "long": "x".repeat(70_000),
+        });
+        assert_eq!(from_slice(&to_vec(&value)), Some(value));
+    }
+
+    #[test]
+    fn is_smaller_than_json() {
+        let value =
+            json!({"payload": {"Text": {"content": "copied"}}, "timestamp": 1_700_000_000_000u64});
+        assert!(to_vec(&value).len() < value.to_string().len());
+    }
+
+    #[test]
+    fn uses_the_cbor_encoding() {
+        let value = json!({"a": [1, -1, "b"]});
+        assert_eq!(
+            to_vec(&value)[MAGIC.len()..],
+            [0xa1, 0x61, b'a', 0x83, 0x01, 0x20, 0x61, b'b']
+        );
+    }
+
+    #[test]
+    fn refuses_anything_else() {
+        assert!(!is_compact(&[0, 0, 0, 2, b'{', b'}']));
+        assert_eq!(from_slice(b"{}"), None);
+        let mut trailing = to_vec(&json!(1));
+        trailing.push(0);
+        assert_eq!(from_slice(&trailing), None);
+        // an array claiming more items than there are bytes
+        assert_eq!(
+            from_slice(&[0xd9, 0xd9, 0xf7, 0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            None
+        );
+        // a map keyed by a number
+        assert_eq!(from_slice(&[0xd9, 0xd9, 0xf7, 0xa1, 0x01, 0x01]), None);
+        let deep = [MAGIC.to_vec(), vec![0x81; MAX_DEPTH + 1], vec![0x01]].concat();
+        assert_eq!(from_slice(&deep), None);
+    }
+}
@@ -0,0 +1,215 @@
+//! Password managers mark what they copy so clipboard tools leave it alone:
+//! `x-kde-passwordManagerHint` on X11, `ExcludeClipboardContentFromMonitorProcessing`
+//! on Windows, `org.nspasteboard.ConcealedType` on macOS. The marks are only
+//! there on the system clipboard.
+
+/// Whether what the system clipboard holds now is marked as a secret.
+/// Failing to tell counts as not marked.
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+pub fn is_concealed() -> bool {
+    use std::sync::Mutex;
+
+    // one connection for every check, made again after an error
+    static TARGETS: Mutex<Option<x11::Targets>> = Mutex::new(None);
+
+    let mut targets = TARGETS.lock().unwrap();
+    if targets.is_none() {
+        *targets = x11::Targets::open();
+    }
+    let Some(open) = targets.as_ref() else {
+        return false;
+    };
+    match open.has_hint() {
+        Some(concealed) => concealed,
+        None => {
+            *targets = None;
+            false
+        }
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+mod x11 {
+    use std::time::{Duration, Instant};
+
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{
+        Atom, AtomEnum, ConnectionExt, CreateWindowAux, Window, WindowClass,
+    };
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+
+    /// The clipboard owner answers within this, or the check gives up.
+    const REPLY_TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// Asks the clipboard owner for the formats it offers, on a window of
+    /// its own.
+    pub struct Targets {
+        conn: RustConnection,
+        window: Window,
+        clipboard: Atom,
+        targets: Atom,
+        property: Atom,
+        hint: Atom,
+    }
+
+    impl Targets {
+        pub fn open() -> Option<Targets> {
+            let (conn, screen) = x11rb::connect(None).ok()?;
+            let root = conn.setup().roots.get(screen)?.root;
+            let window = conn.generate_id().ok()?;
+            conn.create_window(
+                x11rb::COPY_DEPTH_FROM_PARENT,
+                window,
+                root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_ONLY,
+                x11rb::COPY_FROM_PARENT,
+                &CreateWindowAux::new(),
+            )
+            .ok()?;
+            let atom = |name: &str| {
+                Some(
+                    conn.intern_atom(false, name.as_bytes())
+                        .ok()?
+                        .reply()
+                        .ok()?
+                        .atom,
+                )
+            };
+            Some(Targets {
+                clipboard: atom("CLIPBOARD")?,
+                targets: atom("TARGETS")?,
+                property: atom("COPY_SYNC_TARGETS")?,
+                hint: atom("x-kde-passwordManagerHint")?,
+                window,
+                conn,
+            })
+        }
+
+        /// `None` when the connection failed.
+        pub fn has_hint(&self) -> Option<bool> {
+            self.conn
+                .convert_selection(
+                    self.window,
+                    self.clipboard,
+                    self.targets,
+                    self.property,
+                    x11rb::CURRENT_TIME,
+                )
+                .ok()?;
+            self.conn.flush().ok()?;
+            let started = Instant::now();
+            let answered = loop {
+                match self.conn.poll_for_event().ok()? {
+                    Some(Event::SelectionNotify(event)) if event.requestor == self.window => {
+                        break event.property == self.property;
+                    }
+                    Some(_) => {}
+                    None if started.elapsed() > REPLY_TIMEOUT => return Some(false),
+                    None => std::thread::sleep(Duration::from_millis(5)),
+                }
+            };
+            if !answered {
+                // nobody owns the clipboard, or the owner offers no TARGETS
+                return Some(false);
+            }
+            let reply = self
+                .conn
+                .get_property(true, self.window, self.property, AtomEnum::ATOM, 0, 1024)
+                .ok()?
+                .reply()
+                .ok()?;
+            Some(
+                reply
+                    .value32()
+                    .is_some_and(|mut atoms| atoms.any(|atom| atom == self.hint)),
+            )
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn is_concealed() -> bool {
+    use windows_sys::Win32::System::DataExchange::{
+        IsClipboardFormatAvailable, RegisterClipboardFormatW,
+    };
+
+    // KeePass and others put the older name on the clipboard as well
+    [
+        "ExcludeClipboardContentFromMonitorProcessing",
+        "Clipboard Viewer Ignore",
+    ]
+    .iter()
+    .any(|name| {
+        let name: Vec<u16> = name.encode_utf16().chain([0]).collect();
+        let format = unsafe { RegisterClipboardFormatW(name.as_ptr()) };
+        format != 0 && unsafe { IsClipboardFormatAvailable(format) } != 0
+    })
+}
+
+#[cfg(target_os = "macos")]
+pub fn is_concealed() -> bool {
+    use std::ffi::{c_char, c_void};
+
+    type Id = *mut c_void;
+
+    #[link(name = "AppKit", kind = "framework")]
+    extern "C" {}
+
+    #[link(name = "objc")]
+    extern "C" {
+        fn objc_getClass(name: *const c_char) -> Id;
+        fn sel_registerName(name: *const c_char) -> Id;
+        fn objc_msgSend();
+        fn objc_autoreleasePoolPush() -> Id;
+        fn objc_autoreleasePoolPop(pool: Id);
+    }
+
+    unsafe {
+        // objc_msgSend is called through the exact type of each message
+        let send: unsafe extern "C" fn(Id, Id) -> Id =
+            std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+        let send_str: unsafe extern "C" fn(Id, Id, *const c_char) -> Id =
+            std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+        let send_bool: unsafe extern "C" fn(Id, Id, Id) -> i8 =
+            std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+
+        let pool = objc_autoreleasePoolPush();
+        let pasteboard = send(
+            objc_getClass(c"NSPasteboard".as_ptr()),
+            sel_registerName(c"generalPasteboard".as_ptr()),
+        );
+        let types = send(pasteboard, sel_registerName(c"types".as_ptr()));
+        let concealed = !types.is_null() && {
+            let concealed_type = send_str(
+                objc_getClass(c"NSString".as_ptr()),
+                sel_registerName(c"stringWithUTF8String:".as_ptr()),
+                c"org.nspasteboard.ConcealedType".as_ptr(),
+            );
+            send_bool(
+                types,
+                sel_registerName(c"containsObject:".as_ptr()),
+                concealed_type,
+            ) != 0
+        };
+        objc_autoreleasePoolPop(pool);
+        concealed
+    }
+}
+
+/// Nothing marks clipboard content here.
+#[cfg(any(target_os = "android", target_os = "emscripten"))]
+pub fn is_concealed() -> bool {
+    false
+}
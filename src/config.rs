@@ -1,10 +1,404 @@
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
 use tungstenite::protocol::WebSocketConfig;
 
-pub const WEB_SOCKET_CONFIG: WebSocketConfig = WebSocketConfig {
-    max_send_queue: None,
-    max_message_size: None,
-    max_frame_size: None,
-    accept_unmasked_frames: false,
-};
+use crate::backend::ClipboardBackend;
+use crate::client::{ImageName, Selections, SyncMode, TextPolicy};
+use crate::codec::{Compression, ImageCodec};
+use crate::protocol::WireFormat;
+use crate::runtime;
+use crate::server::{Cidr, IpVersion};
+use crate::transport::Transport;
+use crate::watch::WatchMode;
+
+// Clipboard messages are sent as a single frame, so both limits are kept equal.
+pub const MAX_MESSAGE_SIZE_IN_BYTES: usize = 64 << 20;
+
+/// WebSocket limits from `--max-message-size`, `--max-frame-size`,
+/// `--max-send-queue` and `--accept-unmasked-frames`. Sizes left unset stay
+/// at [`MAX_MESSAGE_SIZE_IN_BYTES`], the send queue unbounded.
+pub fn web_socket_config(
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    max_send_queue: Option<usize>,
+    accept_unmasked_frames: bool,
+) -> WebSocketConfig {
+    WebSocketConfig {
+        max_send_queue,
+        max_message_size: Some(max_message_size.unwrap_or(MAX_MESSAGE_SIZE_IN_BYTES)),
+        max_frame_size: Some(max_frame_size.unwrap_or(MAX_MESSAGE_SIZE_IN_BYTES)),
+        accept_unmasked_frames,
+    }
+}
+
+// images larger than one frame are split into chunks of this size
+pub const IMAGE_CHUNK_SIZE_IN_BYTES: usize = 4 << 20;
+
+// what a chunk's header may take of --max-message-size, the name the server
+// tags it with included
+pub const IMAGE_HEADER_ROOM_IN_BYTES: usize = 4 << 10;
+
+pub const MAX_IMAGE_SIZE_IN_BYTES: usize = 256 << 20;
+
+pub const MAX_IMAGE_CHUNKS: usize = 1024;
+
+// chunks of an image that hasn't completed by then are dropped
+pub const IMAGE_CHUNK_TIMEOUT_IN_SECONDS: u64 = 30;
+
+pub const DEFAULT_PORT: u16 = 5120;
+
+pub const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+pub const INITIAL_RETRY_CONNECT_INTERVAL_IN_MILLISECONDS: u64 = 1000;
 
 pub const RETRY_CONNECT_INTERVAL_IN_SECONDS: u64 = 60;
+
+pub const DEFAULT_POLL_INTERVAL_IN_MILLISECONDS: u64 = 2000;
+
+pub const HANDSHAKE_TIMEOUT_IN_SECONDS: u64 = 10;
+
+/// Default for `--connect-timeout`.
+pub const DEFAULT_CONNECT_TIMEOUT_IN_SECONDS: u64 = 10;
+
+pub const DEFAULT_HEARTBEAT_TIMEOUT_IN_SECONDS: u64 = 45;
+
+pub const SHUTDOWN_TIMEOUT_IN_SECONDS: u64 = 5;
+
+/// How long a connection to the control or inbox socket may take to send
+/// its request and read the answer.
+pub const LOCAL_REQUEST_TIMEOUT_IN_SECONDS: u64 = 5;
+
+/// Default for `--peer-queue-size`, room for the chunks of the largest image.
+pub const DEFAULT_PEER_QUEUE_SIZE: u64 = 256;
+
+/// How long a client with `--retransmit` waits for the server to acknowledge
+/// a frame before sending it again.
+pub const ACK_TIMEOUT_IN_SECONDS: u64 = 5;
+
+/// How long `copy-sync discover` and `connect --auto` wait for mDNS answers.
+pub const DISCOVERY_TIMEOUT_IN_MILLISECONDS: u64 = 2000;
+
+pub const PEER_DUMP_INTERVAL_IN_SECONDS: u64 = 60;
+
+/// Rooms the server keeps at once, those with peers and those only holding
+/// the last update for whoever joins next.
+pub const MAX_ROOMS: usize = 1024;
+
+/// What the last updates of all rooms may take together, two of the largest
+/// images.
+pub const MAX_REPLAY_BYTES: usize = 2 * MAX_IMAGE_SIZE_IN_BYTES;
+
+/// A room nobody joined for this long forgets its last update.
+pub const ROOM_EXPIRY_IN_SECONDS: u64 = 3600;
+
+/// Frames in a row a peer may have dropped for `--per-peer-rate` or
+/// `--per-peer-bytes` before it is disconnected.
+pub const MAX_QUOTA_VIOLATIONS: u32 = 20;
+
+// another process holding the clipboard usually lets go within a second
+pub const SET_CLIPBOARD_ATTEMPTS: u32 = 5;
+
+pub const SET_CLIPBOARD_RETRY_INTERVAL_IN_MILLISECONDS: u64 = 50;
+
+/// `copy-sync` in the user's downloads, or in their local data dir where
+/// there is no such thing.
+pub fn default_download_dir() -> PathBuf {
+    match dirs::download_dir().or_else(dirs::data_local_dir) {
+        Some(dir) => dir.join("copy-sync"),
+        None => runtime::dir().join("downloads"),
+    }
+}
+
+/// Default for `--image-name`.
+pub const DEFAULT_IMAGE_NAME: &str = "{name}-{timestamp}-{width}x{height}.png";
+
+pub fn default_pid_file() -> PathBuf {
+    runtime::dir().join("copy-sync.pid")
+}
+
+/// History keeps copied text around, so it's only on when asked for.
+pub const DEFAULT_HISTORY_SIZE: usize = 0;
+
+/// Where `copy-sync history` finds a running client.
+#[cfg(unix)]
+pub fn default_history_socket() -> PathBuf {
+    runtime::dir().join("history.sock")
+}
+
+#[cfg(windows)]
+pub fn default_history_socket() -> PathBuf {
+    PathBuf::from(r"\\.\pipe\copy-sync-history")
+}
+
+/// How many received items `--read-only-clipboard` keeps for `copy-sync inbox`.
+pub const INBOX_SIZE: usize = 10;
+
+/// Text matching any of these is never sent unless `--no-default-excludes`:
+/// private keys and the access tokens of a few common services.
+pub const DEFAULT_EXCLUDE_PATTERNS: &[&str] = &[
+    r"-----BEGIN [A-Z ]*PRIVATE KEY-----",
+    r"\bAKIA[0-9A-Z]{16}\b",
+    r"\bgh[pousr]_[A-Za-z0-9]{36,}\b",
+    r"\bxox[abposr]-[A-Za-z0-9-]{10,}",
+    r"\bsk-[A-Za-z0-9_-]{32,}",
+];
+
+/// Default for `--name`, `None` if the hostname isn't valid UTF-8.
+pub fn default_name() -> Option<String> {
+    gethostname::gethostname().into_string().ok()
+}
+
+/// Where `copy-sync inbox` finds a running client.
+#[cfg(unix)]
+pub fn default_inbox_socket() -> PathBuf {
+    runtime::dir().join("inbox.sock")
+}
+
+#[cfg(windows)]
+pub fn default_inbox_socket() -> PathBuf {
+    PathBuf::from(r"\\.\pipe\copy-sync-inbox")
+}
+
+/// Where `copy-sync pause` and `copy-sync resume` find a running client.
+#[cfg(unix)]
+pub fn default_control_socket() -> PathBuf {
+    runtime::dir().join("control.sock")
+}
+
+#[cfg(windows)]
+pub fn default_control_socket() -> PathBuf {
+    PathBuf::from(r"\\.\pipe\copy-sync-control")
+}
+
+pub const CONFIG_FILE_NAME: &str = "copy-sync.toml";
+
+/// Contents of `copy-sync.toml`. Each table mirrors the flags of its
+/// subcommand, and flags given on the command line win over the file.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub start: StartConfig,
+    pub connect: ConnectConfig,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StartConfig {
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub sign_key: Option<String>,
+    #[serde(deserialize_with = "positive")]
+    pub heartbeat_timeout: Option<u64>,
+    pub metrics_port: Option<u16>,
+    pub max_clients: Option<usize>,
+    #[serde(deserialize_with = "cidrs")]
+    pub allow: Vec<Cidr>,
+    #[serde(deserialize_with = "cidrs")]
+    pub deny: Vec<Cidr>,
+    #[serde(deserialize_with = "positive")]
+    pub per_peer_rate: Option<u64>,
+    #[serde(deserialize_with = "positive")]
+    pub per_peer_bytes: Option<u64>,
+    #[serde(deserialize_with = "positive")]
+    pub peer_queue_size: Option<u64>,
+    pub verbose: Option<bool>,
+    pub transport: Option<Transport>,
+    pub server_time: Option<bool>,
+    pub ip_version: Option<IpVersion>,
+    pub dedup: Option<bool>,
+    pub advertise: Option<bool>,
+    #[serde(deserialize_with = "positive_size")]
+    pub max_message_size: Option<usize>,
+    #[serde(deserialize_with = "positive_size")]
+    pub max_frame_size: Option<usize>,
+    #[serde(deserialize_with = "positive_size")]
+    pub max_send_queue: Option<usize>,
+    pub accept_unmasked_frames: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConnectConfig {
+    #[serde(deserialize_with = "one_or_many")]
+    pub addr: Vec<String>,
+    #[serde(deserialize_with = "positive")]
+    pub poll_interval: Option<u64>,
+    pub watch_mode: Option<WatchMode>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub key: Option<String>,
+    pub sign_key: Option<String>,
+    #[serde(deserialize_with = "positive")]
+    pub max_retry_interval: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub download_dir: Option<PathBuf>,
+    pub save_images: Option<bool>,
+    pub save_dir: Option<PathBuf>,
+    pub image_name: Option<ImageName>,
+    pub image_with_text: Option<bool>,
+    pub image_dedup_window: Option<u64>,
+    pub mode: Option<SyncMode>,
+    pub selection: Option<Selections>,
+    pub max_image_bytes: Option<usize>,
+    pub max_text_bytes: Option<usize>,
+    pub text_policy: Option<TextPolicy>,
+    pub name: Option<String>,
+    pub image_codec: Option<ImageCodec>,
+    pub history_size: Option<usize>,
+    pub history_socket: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_content: Option<bool>,
+    pub no_notify: Option<bool>,
+    pub notify_text: Option<bool>,
+    pub notify_connection: Option<bool>,
+    pub debounce: Option<u64>,
+    pub normalize_text: Option<bool>,
+    #[serde(deserialize_with = "patterns")]
+    pub exclude_pattern: Vec<Regex>,
+    pub no_default_excludes: Option<bool>,
+    pub clipboard_backend: Option<ClipboardBackend>,
+    pub compress: Option<Compression>,
+    #[serde(deserialize_with = "compression_level")]
+    pub compression: Option<u32>,
+    pub wire_format: Option<WireFormat>,
+    pub manual_sync: Option<String>,
+    pub status: Option<bool>,
+    pub read_only_clipboard: Option<bool>,
+    pub inbox_socket: Option<PathBuf>,
+    pub control_socket: Option<PathBuf>,
+    pub auto: Option<bool>,
+    #[serde(deserialize_with = "positive")]
+    pub connect_timeout: Option<u64>,
+    #[serde(deserialize_with = "positive_size")]
+    pub max_message_size: Option<usize>,
+    #[serde(deserialize_with = "positive_size")]
+    pub max_frame_size: Option<usize>,
+    #[serde(deserialize_with = "positive_size")]
+    pub max_send_queue: Option<usize>,
+    pub accept_unmasked_frames: Option<bool>,
+    #[serde(deserialize_with = "positive")]
+    pub max_upload_kbps: Option<u64>,
+    pub retransmit: Option<u32>,
+    pub ca_cert: Option<PathBuf>,
+    pub pin_fingerprint: Option<String>,
+    pub text_only: Option<bool>,
+    pub password_strip: Option<bool>,
+    pub room: Option<String>,
+    #[serde(deserialize_with = "positive")]
+    pub heartbeat_timeout: Option<u64>,
+}
+
+fn positive<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(0) => Err(serde::de::Error::custom("must be greater than 0")),
+        value => Ok(value),
+    }
+}
+
+/// Like [`positive`], for sizes.
+fn positive_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    match Option::<usize>::deserialize(deserializer)? {
+        Some(0) => Err(serde::de::Error::custom("must be greater than 0")),
+        value => Ok(value),
+    }
+}
+
+fn compression_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    match Option::<u32>::deserialize(deserializer)? {
+        Some(level) if level > 9 => Err(serde::de::Error::custom("must be between 0 and 9")),
+        value => Ok(value),
+    }
+}
+
+/// Like [`one_or_many`], for regular expressions.
+fn patterns<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    one_or_many(deserializer)?
+        .iter()
+        .map(|pattern| Regex::new(pattern).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Like [`one_or_many`], for `allow` and `deny`.
+fn cidrs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Cidr>, D::Error> {
+    one_or_many(deserializer)?
+        .iter()
+        .map(|cidr| cidr.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Accepts `addr = "ws://a"` as well as `addr = ["ws://a", "ws://b"]`.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+fn search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(CONFIG_FILE_NAME)];
+    if let Some(dir) = dirs::config_dir() {
+        paths.push(dir.join(CONFIG_FILE_NAME));
+    }
+    paths
+}
+
+/// Reads `path`, or else the first `copy-sync.toml` in the working directory
+/// or the OS config directory. Having no config file at all is not an error.
+pub fn load(path: Option<&Path>) -> io::Result<Config> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match search_paths().into_iter().find(|path| path.is_file()) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        },
+    };
+    let text = fs::read_to_string(&path)?;
+    toml::from_str(&text).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_levels_stop_at_9() {
+        let file: ConnectConfig = toml::from_str("compression = 9").unwrap();
+        assert_eq!(file.compression, Some(9));
+        assert!(toml::from_str::<ConnectConfig>("compression = 10").is_err());
+    }
+
+    #[test]
+    fn a_zero_retry_interval_is_refused() {
+        assert!(toml::from_str::<ConnectConfig>("max-retry-interval = 0").is_err());
+    }
+
+    #[test]
+    fn zero_web_socket_sizes_are_refused() {
+        for key in ["max-message-size", "max-frame-size", "max-send-queue"] {
+            assert!(toml::from_str::<StartConfig>(&format!("{} = 0", key)).is_err());
+            assert!(toml::from_str::<ConnectConfig>(&format!("{} = 0", key)).is_err());
+            assert!(toml::from_str::<ConnectConfig>(&format!("{} = 1", key)).is_ok());
+        }
+    }
+}
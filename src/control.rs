@@ -0,0 +1,150 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::{timeout, Duration};
+use tracing::{debug, info, warn};
+
+use crate::config::LOCAL_REQUEST_TIMEOUT_IN_SECONDS;
+#[cfg(unix)]
+use crate::runtime;
+
+const MAX_REQUEST_BYTES: u64 = 16;
+
+/// Reads one request line, `pause` or `resume`, and answers with whether
+/// syncing is paused afterwards as JSON.
+async fn answer<S>(stream: S, paused: &AtomicBool)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    if (&mut reader)
+        .take(MAX_REQUEST_BYTES)
+        .read_line(&mut request)
+        .await
+        .is_err()
+    {
+        return;
+    }
+    // only logged when the request changes the state
+    match request.trim() {
+        "pause" if !paused.swap(true, Ordering::Relaxed) => info!("Sync paused"),
+        "resume" if paused.swap(false, Ordering::Relaxed) => info!("Sync resumed"),
+        _ => {}
+    }
+    let reply = serde_json::to_vec(&paused.load(Ordering::Relaxed)).unwrap();
+    let _ = reader.get_mut().write_all(&reply).await;
+}
+
+/// Answers on a task of its own, so a connection that never sends its
+/// request holds up no other.
+fn spawn_answer<S>(stream: S, paused: Arc<AtomicBool>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let limit = Duration::from_secs(LOCAL_REQUEST_TIMEOUT_IN_SECONDS);
+        if timeout(limit, answer(stream, &paused)).await.is_err() {
+            debug!("control request timed out");
+        }
+    });
+}
+
+/// Answers every connection on `path` until the socket can't be created or
+/// accepting fails.
+#[cfg(unix)]
+pub async fn serve(paused: Arc<AtomicBool>, path: PathBuf) {
+    let listener = match runtime::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("control socket {} unavailable: {:?}", path.display(), err);
+            return;
+        }
+    };
+    while let Ok((stream, _)) = listener.accept().await {
+        if !runtime::is_own(&stream) {
+            warn!("control request from another user, refused");
+            continue;
+        }
+        spawn_answer(stream, paused.clone());
+    }
+}
+
+#[cfg(windows)]
+pub async fn serve(paused: Arc<AtomicBool>, path: PathBuf) {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = match ServerOptions::new().first_pipe_instance(true).create(&path) {
+        Ok(server) => server,
+        Err(err) => {
+            warn!("control pipe {} unavailable: {:?}", path.display(), err);
+            return;
+        }
+    };
+    while server.connect().await.is_ok() {
+        let client = server;
+        server = match ServerOptions::new().create(&path) {
+            Ok(server) => server,
+            Err(err) => {
+                warn!("control pipe {} unavailable: {:?}", path.display(), err);
+                return;
+            }
+        };
+        spawn_answer(client, paused.clone());
+    }
+}
+
+async fn request(path: &Path, line: &str) -> io::Result<bool> {
+    #[cfg(unix)]
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    #[cfg(windows)]
+    let mut stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+
+    stream.write_all(format!("{}\n", line).as_bytes()).await?;
+    let mut body = Vec::new();
+    stream.read_to_end(&mut body).await?;
+    serde_json::from_slice(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Stops the client listening on `path` from sending or applying updates
+/// until [`resume`].
+pub async fn pause(path: &Path) -> io::Result<()> {
+    request(path, "pause").await.map(drop)
+}
+
+/// Lets the client listening on `path` sync again.
+pub async fn resume(path: &Path) -> io::Result<()> {
+    request(path, "resume").await.map(drop)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn an_idle_connection_doesnt_block_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let paused = Arc::new(AtomicBool::new(false));
+        tokio::spawn(serve(paused.clone(), path.clone()));
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+        // connects and never sends its request
+        let _idle = tokio::net::UnixStream::connect(&path).await.unwrap();
+        timeout(Duration::from_secs(1), pause(&path))
+            .await
+            .expect("pause waited for the idle connection")
+            .unwrap();
+        assert!(paused.load(Ordering::Relaxed));
+        resume(&path).await.unwrap();
+        assert!(!paused.load(Ordering::Relaxed));
+    }
+}
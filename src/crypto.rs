@@ -0,0 +1,95 @@
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use sha2::{Digest, Sha256};
+
+// Every peer must derive the same key from the passphrase alone, so the salt
+// is fixed rather than negotiated.
+const KEY_SALT: &[u8] = b"copy-sync/e2e/v1";
+
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug)]
+pub struct DecryptError;
+
+pub struct Cipher(XChaCha20Poly1305);
+
+impl Cipher {
+    pub fn from_passphrase(passphrase: &str) -> Cipher {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), KEY_SALT, &mut key)
+            .expect("Failed to derive encryption key");
+        Cipher(XChaCha20Poly1305::new(&key.into()))
+    }
+
+    /// Returns the random nonce followed by the ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext)
+            .expect("Failed to encrypt payload");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(DecryptError);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.0
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| DecryptError)
+    }
+}
+
+const BLOCK_LEN: usize = 64;
+
+/// HMAC-SHA256 under a key every peer shares, for `--sign-key`.
+pub struct Signer {
+    inner_pad: [u8; BLOCK_LEN],
+    outer_pad: [u8; BLOCK_LEN],
+}
+
+impl Signer {
+    pub fn new(key: &str) -> Signer {
+        let mut block = [0u8; BLOCK_LEN];
+        if key.len() > BLOCK_LEN {
+            block[..32].copy_from_slice(&Sha256::digest(key.as_bytes()));
+        } else {
+            block[..key.len()].copy_from_slice(key.as_bytes());
+        }
+        Signer {
+            inner_pad: block.map(|byte| byte ^ 0x36),
+            outer_pad: block.map(|byte| byte ^ 0x5c),
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let inner = Sha256::new()
+            .chain_update(self.inner_pad)
+            .chain_update(data)
+            .finalize();
+        Sha256::new()
+            .chain_update(self.outer_pad)
+            .chain_update(inner)
+            .finalize()
+            .to_vec()
+    }
+
+    /// Compares without short-circuiting on the first differing byte.
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let expected = self.sign(data);
+        expected.len() == signature.len()
+            && expected
+                .iter()
+                .zip(signature)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
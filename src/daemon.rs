@@ -0,0 +1,230 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::runtime;
+
+/// Re-launches the current command line without `--daemon` as a detached
+/// background process and records its PID in `pid_file`.
+pub fn spawn(pid_file: &Path) -> io::Result<()> {
+    let args = std::env::args_os()
+        .skip(1)
+        .filter(|arg| arg.as_os_str() != "--daemon");
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    detach(&mut command);
+
+    let mut file = create_pid_file(pid_file)?;
+    let child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            let _ = fs::remove_file(pid_file);
+            return Err(err);
+        }
+    };
+    file.write_all(child.id().to_string().as_bytes())?;
+    println!("Started in background, pid {}", child.id());
+    Ok(())
+}
+
+/// Creates `pid_file` anew, never through a link or over a file someone
+/// else put there. One left by an instance that is gone is replaced.
+fn create_pid_file(pid_file: &Path) -> io::Result<File> {
+    runtime::prepare(pid_file)?;
+    match open_new(pid_file) {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            if let Some(pid) = read_pid(pid_file)? {
+                if is_copy_sync(pid)? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("already running as process {}", pid),
+                    ));
+                }
+            }
+            fs::remove_file(pid_file)?;
+            open_new(pid_file)
+        }
+        result => result,
+    }
+}
+
+#[cfg(unix)]
+fn open_new(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(windows)]
+fn open_new(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// The PID in `pid_file`, `None` if it holds anything else.
+fn read_pid(pid_file: &Path) -> io::Result<Option<u32>> {
+    Ok(fs::read_to_string(pid_file)?.trim().parse().ok())
+}
+
+#[cfg(unix)]
+fn detach(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    // a new process group keeps the child alive when the terminal closes
+    command.process_group(0);
+}
+
+#[cfg(windows)]
+fn detach(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+/// Terminates the instance recorded in `pid_file` and removes the file.
+/// A PID that now belongs to another program, after the instance died and
+/// the PID was reused, is left alone.
+pub fn stop(pid_file: &Path) -> io::Result<()> {
+    let Some(pid) = read_pid(pid_file)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid pid in {}", pid_file.display()),
+        ));
+    };
+    if !is_copy_sync(pid)? {
+        fs::remove_file(pid_file)?;
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "process {} isn't copy-sync, removed the stale {}",
+                pid,
+                pid_file.display()
+            ),
+        ));
+    }
+
+    let status = kill(pid)?;
+    if !status.success() {
+        return Err(io::Error::other(format!("failed to stop process {}", pid)));
+    }
+    fs::remove_file(pid_file)?;
+    println!("Stopped process {}", pid);
+    Ok(())
+}
+
+/// Whether process `pid` runs the same executable as this one.
+fn is_copy_sync(pid: u32) -> io::Result<bool> {
+    let exe = std::env::current_exe()?;
+    let exe = exe
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Ok(process_name(pid)?.is_some_and(|name| same_name(&name, &exe)))
+}
+
+/// Linux cuts the names `ps` shows to 15 bytes.
+fn same_name(name: &str, exe: &str) -> bool {
+    name == exe || (cfg!(target_os = "linux") && name.len() == 15 && exe.starts_with(name))
+}
+
+/// The executable name of process `pid`, `None` if there is none.
+#[cfg(unix)]
+fn process_name(pid: u32) -> io::Result<Option<String>> {
+    let output = Command::new("ps")
+        .args(["-p", &pid.to_string(), "-o", "comm="])
+        .stderr(Stdio::null())
+        .output()?;
+    let name = String::from_utf8_lossy(&output.stdout);
+    // macOS shows the whole path
+    Ok(Path::new(name.trim())
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned()))
+}
+
+#[cfg(windows)]
+fn process_name(pid: u32) -> io::Result<Option<String>> {
+    let output = Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/FO", "CSV", "/NH"])
+        .stderr(Stdio::null())
+        .output()?;
+    let listed = String::from_utf8_lossy(&output.stdout);
+    // `"copy-sync.exe","1234",...`, or a line of INFO without quotes
+    Ok(listed
+        .trim()
+        .strip_prefix('"')
+        .and_then(|line| line.split_once('"'))
+        .map(|(name, _)| name.to_string()))
+}
+
+#[cfg(unix)]
+fn kill(pid: u32) -> io::Result<std::process::ExitStatus> {
+    Command::new("kill")
+        .args(["-TERM", &pid.to_string()])
+        .status()
+}
+
+#[cfg(windows)]
+fn kill(pid: u32) -> io::Result<std::process::ExitStatus> {
+    Command::new("taskkill")
+        .args(["/PID", &pid.to_string(), "/F"])
+        .status()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_its_own_process() {
+        assert!(is_copy_sync(std::process::id()).unwrap());
+    }
+
+    #[test]
+    fn leaves_other_processes_alone() {
+        let mut other = Command::new("sleep").arg("5").spawn().unwrap();
+        assert!(!is_copy_sync(other.id()).unwrap());
+        other.kill().unwrap();
+        other.wait().unwrap();
+    }
+
+    #[test]
+    fn replaces_a_stale_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("copy-sync.pid");
+        fs::write(&pid_file, "not a pid").unwrap();
+        let mut file = create_pid_file(&pid_file).unwrap();
+        file.write_all(b"1").unwrap();
+        assert_eq!(read_pid(&pid_file).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn refuses_a_running_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("copy-sync.pid");
+        fs::write(&pid_file, std::process::id().to_string()).unwrap();
+        let err = create_pid_file(&pid_file).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn doesnt_follow_a_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let pid_file = dir.path().join("copy-sync.pid");
+        std::os::unix::fs::symlink(&target, &pid_file).unwrap();
+        assert!(open_new(&pid_file).is_err());
+        assert!(!target.exists());
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Range,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::config::default_name;
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// DNS-SD service type a server started with `--advertise` answers for.
+pub const SERVICE: &str = "_copysync._tcp.local";
+
+const TTL_IN_SECONDS: u32 = 120;
+const MAX_PACKET_SIZE: usize = 9000;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// set on records only this host has, so caches replace rather than add to them
+const CACHE_FLUSH: u16 = 0x8000;
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+fn write_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label);
+    }
+    packet.push(0);
+}
+
+fn write_record(packet: &mut Vec<u8>, name: &str, kind: u16, class: u16, data: &[u8]) {
+    write_name(packet, name);
+    packet.extend_from_slice(&kind.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    packet.extend_from_slice(&TTL_IN_SECONDS.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Reads the name at `offset`, following compression pointers, and returns it
+/// with the offset just past it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // bounds the pointers a malformed packet could loop through
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(offset + 2);
+            offset = (read_u16(packet, offset)? & 0x3fff) as usize;
+            continue;
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + len;
+    }
+    None
+}
+
+struct Record {
+    name: String,
+    kind: u16,
+    data: Range<usize>,
+}
+
+/// The parts of an mDNS packet discovery looks at.
+struct Packet {
+    id: u16,
+    is_response: bool,
+    questions: Vec<(String, u16)>,
+    records: Vec<Record>,
+}
+
+fn parse(packet: &[u8]) -> Option<Packet> {
+    let id = read_u16(packet, 0)?;
+    let flags = read_u16(packet, 2)?;
+    let questions = read_u16(packet, 4)?;
+    let records: usize = [6, 8, 10]
+        .iter()
+        .map(|&offset| read_u16(packet, offset).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut offset = 12;
+    let mut parsed = Packet {
+        id,
+        is_response: flags & 0x8000 != 0,
+        questions: Vec::new(),
+        records: Vec::new(),
+    };
+    for _ in 0..questions {
+        let (name, end) = read_name(packet, offset)?;
+        parsed.questions.push((name, read_u16(packet, end)?));
+        offset = end + 4;
+    }
+    for _ in 0..records {
+        let (name, end) = read_name(packet, offset)?;
+        let kind = read_u16(packet, end)?;
+        let len = read_u16(packet, end + 8)? as usize;
+        let start = end + 10;
+        if packet.len() < start + len {
+            return None;
+        }
+        parsed.records.push(Record {
+            name,
+            kind,
+            data: start..start + len,
+        });
+        offset = start + len;
+    }
+    Some(parsed)
+}
+
+fn query() -> Vec<u8> {
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    write_name(&mut packet, SERVICE);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+/// What this server answers with: where to find the instance, on which port,
+/// over which scheme and, when known, at which address.
+fn response(id: u16, host: &str, port: u16, scheme: &str, ip: Option<Ipv4Addr>) -> Vec<u8> {
+    let instance = format!("{}.{}", host, SERVICE);
+    let target = format!("{}.local", host);
+    let mut packet = id.to_be_bytes().to_vec();
+    packet.extend_from_slice(&FLAGS_RESPONSE.to_be_bytes());
+    let answers: u16 = if ip.is_some() { 4 } else { 3 };
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&answers.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+
+    let mut pointer = Vec::new();
+    write_name(&mut pointer, &instance);
+    write_record(&mut packet, SERVICE, TYPE_PTR, CLASS_IN, &pointer);
+
+    let mut service = vec![0, 0, 0, 0];
+    service.extend_from_slice(&port.to_be_bytes());
+    write_name(&mut service, &target);
+    write_record(
+        &mut packet,
+        &instance,
+        TYPE_SRV,
+        CLASS_IN | CACHE_FLUSH,
+        &service,
+    );
+
+    let text = format!("scheme={}", scheme);
+    let mut texts = vec![text.len() as u8];
+    texts.extend_from_slice(text.as_bytes());
+    write_record(
+        &mut packet,
+        &instance,
+        TYPE_TXT,
+        CLASS_IN | CACHE_FLUSH,
+        &texts,
+    );
+
+    if let Some(ip) = ip {
+        write_record(
+            &mut packet,
+            &target,
+            TYPE_A,
+            CLASS_IN | CACHE_FLUSH,
+            &ip.octets(),
+        );
+    }
+    packet
+}
+
+/// The IPv4 address the server is reachable at: `bind` when it is a single
+/// address, else the one the OS would send multicast from.
+fn advertised_ip(bind: IpAddr) -> Option<Ipv4Addr> {
+    match bind {
+        IpAddr::V4(ip) if !ip.is_unspecified() => return Some(ip),
+        IpAddr::V4(_) => {}
+        IpAddr::V6(_) => return None,
+    }
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+fn multicast_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // shares the port with the system's own mDNS responder
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Answers mDNS queries for [`SERVICE`] with the server listening on `addr`,
+/// until the socket fails.
+pub async fn advertise(addr: SocketAddr, scheme: &'static str) {
+    let socket = match multicast_socket() {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!("mDNS unavailable, not advertising: {:?}", err);
+            return;
+        }
+    };
+    // a dot would split the instance name into labels
+    let host = default_name()
+        .unwrap_or_else(|| "copy-sync".to_string())
+        .replace('.', "-");
+    let ip = advertised_ip(addr.ip());
+    info!("Advertising {}.{} on mDNS", host, SERVICE);
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("mDNS socket failed, no longer advertising: {:?}", err);
+                return;
+            }
+        };
+        let Some(packet) = parse(&buf[..len]) else {
+            continue;
+        };
+        let asked = !packet.is_response
+            && packet.questions.iter().any(|(name, kind)| {
+                name.eq_ignore_ascii_case(SERVICE) && (*kind == TYPE_PTR || *kind == TYPE_ANY)
+            });
+        if !asked {
+            continue;
+        }
+        debug!("Answering mDNS query from {}", from);
+        let reply = response(packet.id, &host, addr.port(), scheme, ip);
+        // a one-shot query like `copy-sync discover` expects the answer back
+        // on its own port
+        let to = if from.port() == MDNS_PORT {
+            SocketAddr::from((MDNS_GROUP, MDNS_PORT))
+        } else {
+            from
+        };
+        let _ = socket.send_to(&reply, to).await;
+    }
+}
+
+/// A server that answered [`browse`].
+pub struct Found {
+    /// the host it runs on
+    pub name: String,
+    /// what to pass to `--addr`
+    pub addr: String,
+}
+
+/// Servers advertised in one response packet. `from` stands in for the
+/// address when the packet has no A record.
+fn found_in(bytes: &[u8], packet: &Packet, from: IpAddr) -> Vec<Found> {
+    let record = |name: &str, kind: u16| {
+        packet
+            .records
+            .iter()
+            .find(|record| record.kind == kind && record.name.eq_ignore_ascii_case(name))
+    };
+    let mut found = Vec::new();
+    for pointer in packet
+        .records
+        .iter()
+        .filter(|record| record.kind == TYPE_PTR && record.name.eq_ignore_ascii_case(SERVICE))
+    {
+        let Some((instance, _)) = read_name(bytes, pointer.data.start) else {
+            continue;
+        };
+        let Some(service) = record(&instance, TYPE_SRV) else {
+            continue;
+        };
+        let Some(port) = read_u16(bytes, service.data.start + 4) else {
+            continue;
+        };
+        let Some((target, _)) = read_name(bytes, service.data.start + 6) else {
+            continue;
+        };
+        let scheme = record(&instance, TYPE_TXT)
+            .and_then(|text| {
+                let text = String::from_utf8_lossy(bytes.get(text.data.start + 1..text.data.end)?);
+                text.strip_prefix("scheme=").map(str::to_string)
+            })
+            .unwrap_or_else(|| "ws".to_string());
+        let ip = record(&target, TYPE_A)
+            .and_then(|a| <[u8; 4]>::try_from(&bytes[a.data.clone()]).ok())
+            .map_or(from, IpAddr::from);
+        let name = instance
+            .strip_suffix(SERVICE)
+            .map_or(instance.as_str(), |name| name.trim_end_matches('.'))
+            .to_string();
+        found.push(Found {
+            name,
+            addr: format!("{}://{}", scheme, SocketAddr::new(ip, port)),
+        });
+    }
+    found
+}
+
+/// Asks the LAN for copy-sync servers over mDNS and collects the answers
+/// that arrive within `timeout`, in the order they came.
+pub async fn browse(timeout: Duration) -> io::Result<Vec<Found>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.send_to(&query(), (MDNS_GROUP, MDNS_PORT)).await?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    let mut found: Vec<Found> = Vec::new();
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        let Some(packet) = parse(&buf[..len]) else {
+            continue;
+        };
+        if !packet.is_response {
+            continue;
+        }
+        for server in found_in(&buf[..len], &packet, from.ip()) {
+            if found.iter().all(|known| known.addr != server.addr) {
+                found.push(server);
+            }
+        }
+    }
+    Ok(found)
+}
@@ -0,0 +1,144 @@
+//! `copy-sync doctor`: tries what syncing depends on and reports each part.
+
+use std::env;
+
+use ulid::Ulid;
+
+use crate::backend::{Board, ClipboardBackend};
+use crate::client::{self, ClientOptions};
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    /// not checked on this platform or with this configuration
+    Skip(String),
+}
+
+fn report(name: &str, outcome: &Outcome) {
+    let (label, detail) = match outcome {
+        Outcome::Pass(detail) => ("pass", detail),
+        Outcome::Fail(detail) => ("FAIL", detail),
+        Outcome::Skip(detail) => ("skip", detail),
+    };
+    println!("[{}] {}: {}", label, name, detail);
+}
+
+/// Writes a marker to the clipboard and reads it back, then puts back the
+/// text or image that was there. Anything else on it is lost.
+fn clipboard(backend: &ClipboardBackend) -> Outcome {
+    let mut board = match Board::open(backend) {
+        Ok(board) => board,
+        Err(err) => return Outcome::Fail(format!("can't open the {} clipboard: {}", backend, err)),
+    };
+    let text = board.get_text().ok();
+    let image = match text {
+        Some(_) => None,
+        None => board.get_image().ok(),
+    };
+    let marker = format!("copy-sync doctor {}", Ulid::new());
+    if let Err(err) = board.set_text(&marker) {
+        return Outcome::Fail(format!(
+            "writing to the {} clipboard failed: {}",
+            backend, err
+        ));
+    }
+    let read = board.get_text();
+    let restored = match (text, image) {
+        (Some(text), _) => board.set_text(&text),
+        (None, Some(image)) => board.set_image(image),
+        (None, None) => board.clear(),
+    };
+    match read {
+        Ok(read) if read == marker => {}
+        Ok(_) => {
+            return Outcome::Fail(format!(
+                "the {} clipboard held something else right after writing to it, \
+                 another program may be holding it",
+                backend
+            ))
+        }
+        Err(err) => {
+            return Outcome::Fail(format!("reading the {} clipboard failed: {}", backend, err))
+        }
+    }
+    match restored {
+        Ok(()) => Outcome::Pass(format!(
+            "wrote to and read back from the {} clipboard",
+            backend
+        )),
+        Err(err) => Outcome::Fail(format!(
+            "round trip worked, but putting back the previous content failed: {}",
+            err
+        )),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn notifications(options: &ClientOptions) -> Outcome {
+    if !options.notify {
+        return Outcome::Skip("turned off with --no-notify".to_string());
+    }
+    match notify_rust::get_server_information() {
+        Ok(server) => Outcome::Pass(format!(
+            "{} {} by {}",
+            server.name, server.version, server.vendor
+        )),
+        Err(err) => Outcome::Fail(format!("no notification server on D-Bus: {}", err)),
+    }
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn notifications(_options: &ClientOptions) -> Outcome {
+    Outcome::Skip("the system shows them, nothing to check".to_string())
+}
+
+async fn server(addr: Option<String>, options: &ClientOptions) -> Outcome {
+    let Some(addr) = addr else {
+        return Outcome::Skip("no server, pass --addr or set addr in copy-sync.toml".to_string());
+    };
+    match client::probe(addr.clone(), options).await {
+        Ok(()) => Outcome::Pass(format!("{} accepted the handshake", addr)),
+        Err(err) => Outcome::Fail(format!("{}: {}", addr, err)),
+    }
+}
+
+fn display_server() -> String {
+    if cfg!(target_os = "windows") {
+        return "Windows".to_string();
+    }
+    if cfg!(target_os = "macos") {
+        return "Quartz".to_string();
+    }
+    match (env::var("WAYLAND_DISPLAY"), env::var("DISPLAY")) {
+        (Ok(wayland), Ok(x11)) => format!("Wayland ({}), with XWayland ({})", wayland, x11),
+        (Ok(wayland), Err(_)) => format!("Wayland ({})", wayland),
+        (Err(_), Ok(x11)) => format!("X11 ({})", x11),
+        (Err(_), Err(_)) => "none, neither WAYLAND_DISPLAY nor DISPLAY is set".to_string(),
+    }
+}
+
+/// Prints the versions and environment, then checks the clipboard, the
+/// notification server and `addr`. Returns whether nothing failed.
+pub async fn run(addr: Option<String>, options: ClientOptions) -> bool {
+    println!(
+        "copy-sync {} on {} {}",
+        env!("CARGO_PKG_VERSION"),
+        env::consts::OS,
+        env::consts::ARCH
+    );
+    println!(
+        "arboard {}, tungstenite {}",
+        env!("ARBOARD_VERSION"),
+        env!("TUNGSTENITE_VERSION")
+    );
+    println!("display server: {}", display_server());
+    let mut passed = true;
+    let mut check = |name: &str, outcome: Outcome| {
+        passed &= !matches!(outcome, Outcome::Fail(_));
+        report(name, &outcome);
+    };
+    check("clipboard", clipboard(&options.clipboard_backend));
+    check("notifications", notifications(&options));
+    check("server", server(addr, &options).await);
+    passed
+}
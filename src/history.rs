@@ -0,0 +1,103 @@
+use std::{
+    collections::VecDeque,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::warn;
+
+#[cfg(unix)]
+use crate::runtime;
+
+/// The last few texts copied locally or received from peers.
+pub struct History {
+    entries: VecDeque<String>,
+    capacity: usize,
+}
+
+pub type SharedHistory = Arc<Mutex<History>>;
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, text: &str) {
+        if self.capacity == 0 || self.entries.back().is_some_and(|last| last == text) {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(text.to_string());
+    }
+
+    /// Entries as JSON, newest first.
+    fn snapshot(&self) -> Vec<u8> {
+        let entries: Vec<&String> = self.entries.iter().rev().collect();
+        serde_json::to_vec(&entries).unwrap()
+    }
+}
+
+/// Answers every connection on `path` with the current history, until the
+/// socket can't be created or accepting fails.
+#[cfg(unix)]
+pub async fn serve(history: SharedHistory, path: PathBuf) {
+    let listener = match runtime::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("history socket {} unavailable: {:?}", path.display(), err);
+            return;
+        }
+    };
+    while let Ok((mut stream, _)) = listener.accept().await {
+        if !runtime::is_own(&stream) {
+            warn!("history requested by another user, refused");
+            continue;
+        }
+        let snapshot = history.lock().unwrap().snapshot();
+        let _ = stream.write_all(&snapshot).await;
+    }
+}
+
+#[cfg(windows)]
+pub async fn serve(history: SharedHistory, path: PathBuf) {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = match ServerOptions::new().first_pipe_instance(true).create(&path) {
+        Ok(server) => server,
+        Err(err) => {
+            warn!("history pipe {} unavailable: {:?}", path.display(), err);
+            return;
+        }
+    };
+    while server.connect().await.is_ok() {
+        let mut client = server;
+        server = match ServerOptions::new().create(&path) {
+            Ok(server) => server,
+            Err(err) => {
+                warn!("history pipe {} unavailable: {:?}", path.display(), err);
+                return;
+            }
+        };
+        let snapshot = history.lock().unwrap().snapshot();
+        let _ = client.write_all(&snapshot).await;
+    }
+}
+
+/// Asks the client listening on `path` for its history, newest first.
+pub async fn fetch(path: &Path) -> io::Result<Vec<String>> {
+    #[cfg(unix)]
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    #[cfg(windows)]
+    let mut stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+
+    let mut body = Vec::new();
+    stream.read_to_end(&mut body).await?;
+    serde_json::from_slice(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
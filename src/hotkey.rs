@@ -0,0 +1,73 @@
+use std::{io, sync::Arc};
+
+use global_hotkey::hotkey::HotKey;
+use tokio::sync::Notify;
+
+/// Registers `hotkey` system-wide and returns a trigger that wakes on every
+/// press. The registration lives on its own thread until the process exits.
+#[cfg(not(target_os = "macos"))]
+pub fn listen(hotkey: HotKey) -> io::Result<Arc<Notify>> {
+    use global_hotkey::{GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState};
+
+    let trigger = Arc::new(Notify::new());
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    let pressed = trigger.clone();
+    std::thread::spawn(move || {
+        let manager = match GlobalHotKeyManager::new() {
+            Ok(manager) => manager,
+            Err(err) => {
+                let _ = ready_tx.send(Err(err));
+                return;
+            }
+        };
+        if let Err(err) = manager.register(hotkey) {
+            let _ = ready_tx.send(Err(err));
+            return;
+        }
+        GlobalHotKeyEvent::set_event_handler(Some(move |event: GlobalHotKeyEvent| {
+            if event.id == hotkey.id() && event.state == HotKeyState::Pressed {
+                pressed.notify_one();
+            }
+        }));
+        let _ = ready_tx.send(Ok(()));
+        run_event_loop();
+        drop(manager);
+    });
+    match ready_rx.recv() {
+        Ok(Ok(())) => Ok(trigger),
+        Ok(Err(err)) => Err(io::Error::other(err)),
+        Err(_) => Err(io::Error::other("hotkey listener exited")),
+    }
+}
+
+/// macOS only delivers hotkeys to the main thread's run loop, which tokio owns.
+#[cfg(target_os = "macos")]
+pub fn listen(_hotkey: HotKey) -> io::Result<Arc<Notify>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "manual sync hotkeys are not supported on macOS",
+    ))
+}
+
+#[cfg(windows)]
+fn run_event_loop() {
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        DispatchMessageW, GetMessageW, TranslateMessage, MSG,
+    };
+
+    // the hotkey window only sees presses while its thread pumps messages
+    let mut msg: MSG = unsafe { std::mem::zeroed() };
+    while unsafe { GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) } > 0 {
+        unsafe {
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+    }
+}
+
+#[cfg(all(not(windows), not(target_os = "macos")))]
+fn run_event_loop() {
+    loop {
+        std::thread::park();
+    }
+}
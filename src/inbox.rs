@@ -0,0 +1,279 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use arboard::ImageData;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::{timeout, Duration};
+use tracing::{debug, warn};
+
+use crate::config::LOCAL_REQUEST_TIMEOUT_IN_SECONDS;
+#[cfg(unix)]
+use crate::runtime;
+
+const MAX_REQUEST_BYTES: u64 = 64;
+
+/// Something a peer sent while `--read-only-clipboard` kept it off the
+/// clipboard.
+#[derive(Clone)]
+pub enum Received {
+    Text(String),
+    Html {
+        html: String,
+        alt_text: Option<String>,
+    },
+    Rtf {
+        rtf: String,
+        alt_text: Option<String>,
+    },
+    Image(ImageData<'static>),
+    Files(Vec<PathBuf>),
+}
+
+impl Received {
+    fn describe(&self) -> String {
+        match self {
+            Received::Text(text) => text.clone(),
+            Received::Html { html, alt_text } => {
+                format!("html: {}", alt_text.as_deref().unwrap_or(html))
+            }
+            Received::Rtf { rtf, alt_text } => {
+                format!("rtf: {}", alt_text.as_deref().unwrap_or(rtf))
+            }
+            Received::Image(image) => format!("image: {}x{}", image.width, image.height),
+            Received::Files(paths) => {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                format!("files: {}", paths.join(", "))
+            }
+        }
+    }
+}
+
+/// The last few items received in `--read-only-clipboard` mode, waiting for
+/// `copy-sync inbox --paste`.
+pub struct Inbox {
+    entries: VecDeque<Received>,
+    capacity: usize,
+    /// hash of the item pasted last, so the client doesn't send it back
+    pasted: Option<u64>,
+}
+
+pub type SharedInbox = Arc<Mutex<Inbox>>;
+
+impl Inbox {
+    pub fn new(capacity: usize) -> Self {
+        Inbox {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            pasted: None,
+        }
+    }
+
+    pub fn push(&mut self, item: Received) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(item);
+    }
+
+    /// The `index`th newest item, counting from 1.
+    fn get(&self, index: usize) -> Option<Received> {
+        let position = self.entries.len().checked_sub(index)?;
+        self.entries.get(position).cloned()
+    }
+
+    pub fn mark_pasted(&mut self, hash: u64) {
+        self.pasted = Some(hash);
+    }
+
+    pub fn was_pasted(&self, hash: u64) -> bool {
+        self.pasted == Some(hash)
+    }
+
+    /// One line per item, newest first.
+    fn describe(&self) -> Vec<String> {
+        self.entries.iter().rev().map(Received::describe).collect()
+    }
+}
+
+/// Reads one request line, `list` or `paste <n>`, and answers with the items
+/// or the outcome of the paste as a JSON `Result`.
+async fn answer<S, F, P>(stream: S, inbox: &SharedInbox, paste: &F)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(Received) -> P,
+    P: Future<Output = Result<(), String>>,
+{
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    if (&mut reader)
+        .take(MAX_REQUEST_BYTES)
+        .read_line(&mut request)
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let reply: Result<Vec<String>, String> = match request.trim().split_once(' ') {
+        Some(("paste", index)) => {
+            let item = index
+                .parse()
+                .ok()
+                .and_then(|index| inbox.lock().unwrap().get(index));
+            match item {
+                Some(item) => paste(item).await.map(|()| Vec::new()),
+                None => Err(format!("no item {} in the inbox", index)),
+            }
+        }
+        _ => Ok(inbox.lock().unwrap().describe()),
+    };
+    let _ = reader
+        .get_mut()
+        .write_all(&serde_json::to_vec(&reply).unwrap())
+        .await;
+}
+
+/// Answers on a task of its own, so a connection that never sends its
+/// request holds up no other.
+fn spawn_answer<S, F, P>(stream: S, inbox: SharedInbox, paste: Arc<F>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Received) -> P + Send + Sync + 'static,
+    P: Future<Output = Result<(), String>> + Send,
+{
+    tokio::spawn(async move {
+        let limit = Duration::from_secs(LOCAL_REQUEST_TIMEOUT_IN_SECONDS);
+        if timeout(limit, answer(stream, &inbox, &*paste))
+            .await
+            .is_err()
+        {
+            debug!("inbox request timed out");
+        }
+    });
+}
+
+/// Answers every connection on `path` until the socket can't be created or
+/// accepting fails. `paste` puts an item on the clipboard.
+#[cfg(unix)]
+pub async fn serve<F, P>(inbox: SharedInbox, path: PathBuf, paste: F)
+where
+    F: Fn(Received) -> P + Send + Sync + 'static,
+    P: Future<Output = Result<(), String>> + Send,
+{
+    let listener = match runtime::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("inbox socket {} unavailable: {:?}", path.display(), err);
+            return;
+        }
+    };
+    let paste = Arc::new(paste);
+    while let Ok((stream, _)) = listener.accept().await {
+        if !runtime::is_own(&stream) {
+            warn!("inbox request from another user, refused");
+            continue;
+        }
+        spawn_answer(stream, inbox.clone(), paste.clone());
+    }
+}
+
+#[cfg(windows)]
+pub async fn serve<F, P>(inbox: SharedInbox, path: PathBuf, paste: F)
+where
+    F: Fn(Received) -> P + Send + Sync + 'static,
+    P: Future<Output = Result<(), String>> + Send,
+{
+    let paste = Arc::new(paste);
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = match ServerOptions::new().first_pipe_instance(true).create(&path) {
+        Ok(server) => server,
+        Err(err) => {
+            warn!("inbox pipe {} unavailable: {:?}", path.display(), err);
+            return;
+        }
+    };
+    while server.connect().await.is_ok() {
+        let client = server;
+        server = match ServerOptions::new().create(&path) {
+            Ok(server) => server,
+            Err(err) => {
+                warn!("inbox pipe {} unavailable: {:?}", path.display(), err);
+                return;
+            }
+        };
+        spawn_answer(client, inbox.clone(), paste.clone());
+    }
+}
+
+async fn request(path: &Path, line: &str) -> io::Result<Vec<String>> {
+    #[cfg(unix)]
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    #[cfg(windows)]
+    let mut stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+
+    stream.write_all(format!("{}\n", line).as_bytes()).await?;
+    let mut body = Vec::new();
+    stream.read_to_end(&mut body).await?;
+    let reply: Result<Vec<String>, String> = serde_json::from_slice(&body)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    reply.map_err(io::Error::other)
+}
+
+/// Asks the client listening on `path` for its inbox, newest first.
+pub async fn list(path: &Path) -> io::Result<Vec<String>> {
+    request(path, "list").await
+}
+
+/// Has the client listening on `path` put its `index`th newest item on the
+/// clipboard.
+pub async fn paste(path: &Path, index: usize) -> io::Result<()> {
+    request(path, &format!("paste {}", index)).await.map(drop)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn an_idle_connection_doesnt_block_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inbox.sock");
+        let inbox = Arc::new(Mutex::new(Inbox::new(2)));
+        inbox
+            .lock()
+            .unwrap()
+            .push(Received::Text("kept".to_string()));
+        let pasted = Arc::new(Mutex::new(Vec::new()));
+        let paste = {
+            let pasted = pasted.clone();
+            move |item: Received| {
+                pasted.lock().unwrap().push(item.describe());
+                async { Ok(()) }
+            }
+        };
+        tokio::spawn(serve(inbox, path.clone(), paste));
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+        // connects and never sends its request
+        let _idle = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let listed = timeout(Duration::from_secs(1), list(&path))
+            .await
+            .expect("list waited for the idle connection")
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        super::paste(&path, 1).await.unwrap();
+        assert_eq!(pasted.lock().unwrap().len(), 1);
+    }
+}
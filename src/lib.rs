@@ -0,0 +1,67 @@
+//! Clipboard sync over WebSocket, usable as a library as well as through
+//! the `copy-sync` binary. Start with [`Client::builder`] or
+//! [`Server::builder`].
+//!
+//! Built for `wasm32`, only [`protocol`] is there, for browser clients that
+//! bring their own WebSocket and clipboard.
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod audit;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod backend;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod backoff;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod client;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod codec;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod compact;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod conceal;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod control;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod crypto;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod daemon;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod discovery;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod doctor;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod history;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod hotkey;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod inbox;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod longpoll;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod metrics;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod notify;
+pub mod protocol;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod runtime;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod service;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod shutdown;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tls;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transport;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod watch;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use client::{Client, ClientBuilder, ClientOptions, ConnectionState, Traffic};
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::{Server, ServerBuilder, ServerOptions};
+#[cfg(not(target_arch = "wasm32"))]
+pub use shutdown::Handle;
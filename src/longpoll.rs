@@ -0,0 +1,579 @@
+//! HTTP long polling, for networks that block WebSocket upgrades. The
+//! client names a session in every request: `POST /send` carries frames to
+//! the server and `GET /poll` waits for frames from it. Bodies hold frames
+//! laid out as in the `tcp` transport, so peers get the same content over
+//! every transport.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use futures_channel::mpsc::{self, Receiver, UnboundedSender};
+use futures_util::{future, sink, stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tokio_rustls::{
+    rustls::{ClientConfig, ServerName},
+    TlsConnector,
+};
+use tokio_tungstenite::Connector;
+use tungstenite::{Error, Message};
+use ulid::Ulid;
+use url::Url;
+
+use crate::config::HANDSHAKE_TIMEOUT_IN_SECONDS;
+use crate::tls::client_config;
+use crate::transport::{encode_frame, read_frame, FrameSink, FrameStream};
+
+const MAX_HEAD_BYTES: usize = 8 << 10;
+
+/// The largest body for frames of up to `max_message_size` bytes. A poll
+/// answers with every frame that is waiting, but stops adding more once the
+/// body passes `max_message_size`.
+fn max_body_bytes(max_message_size: usize) -> usize {
+    max_message_size.saturating_mul(2).saturating_add(1024)
+}
+
+/// Until the server answers the handshake, a session sends no more than
+/// that fits in this.
+const MAX_UNAUTHORIZED_BODY_BYTES: usize = 64 << 10;
+
+/// How long `GET /poll` waits for a frame before answering with none. The
+/// server's heartbeat pings come sooner than that.
+const POLL_TIMEOUT_IN_SECONDS: u64 = 20;
+
+/// Frames a session holds for the next poll. Past that the connection waits,
+/// and a client that stops polling backs up into the server's
+/// `--peer-queue-size`.
+const POLL_BUFFER: usize = 64;
+
+fn head_len(request: &[u8]) -> Option<usize> {
+    request
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|end| end + 4)
+}
+
+/// Reads until the end of a request or response head and returns it, with
+/// whatever of the body came along. `None` for a closed or oversized head,
+/// or one that takes longer than `timeout`.
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S, timeout: Duration) -> Option<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    let read = async {
+        while head_len(&request).is_none() {
+            if request.len() > MAX_HEAD_BYTES {
+                return None;
+            }
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return None,
+                Ok(len) => request.extend_from_slice(&buffer[..len]),
+            }
+        }
+        Some(())
+    };
+    tokio::time::timeout(timeout, read).await.ok()??;
+    Some(request)
+}
+
+struct Head {
+    method: String,
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn parse(request: &[u8]) -> Option<Head> {
+        let head = std::str::from_utf8(&request[..head_len(request)?]).ok()?;
+        let mut lines = head.lines();
+        let mut request_line = lines.next()?.split_whitespace();
+        let method = request_line.next()?.to_string();
+        let target = request_line.next()?;
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        Some(Head {
+            method,
+            path,
+            query,
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn session(&self) -> Option<String> {
+        url::form_urlencoded::parse(self.query.as_deref()?.as_bytes())
+            .find(|(key, _)| key == "session")
+            .map(|(_, session)| session.into_owned())
+            .filter(|session| !session.is_empty())
+    }
+}
+
+/// Whether the request in `request` asks for a WebSocket. Heads that don't
+/// parse count as one, for the WebSocket handshake to reject.
+pub fn is_upgrade(request: &[u8]) -> bool {
+    Head::parse(request).is_none_or(|head| {
+        head.header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    })
+}
+
+/// A stream that replays `head`, already read off `stream`, before reading
+/// on, so a request can be looked at before it is handed on.
+pub struct Rewind<S> {
+    head: Vec<u8>,
+    stream: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(head: Vec<u8>, stream: S) -> Rewind<S> {
+        Rewind { head, stream }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.head.is_empty() {
+            let len = this.head.len().min(buf.remaining());
+            buf.put_slice(&this.head[..len]);
+            this.head.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+async fn decode_frames(mut body: &[u8], max_size: usize) -> Result<Vec<Message>, Error> {
+    let mut frames = Vec::new();
+    while let Some(frame) = read_frame(&mut body, max_size).await? {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// Both ends of a session's connection, as the server holds them between
+/// requests.
+pub struct Session {
+    /// frames from `POST /send`, read by the connection
+    incoming: UnboundedSender<Message>,
+    /// frames the connection sends, handed out by `GET /poll`
+    outgoing: Arc<tokio::sync::Mutex<Receiver<Message>>>,
+    /// set once the connection sent a frame, which it only does after
+    /// accepting the handshake
+    authorized: Arc<AtomicBool>,
+}
+
+pub type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, status: &str, body: &[u8]) {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(body).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Answers a `POST /send` or `GET /poll` whose head is in `request`, for
+/// frames of up to `max_message_size` bytes. The first request of a session
+/// hands its query and both ends of the new connection to `open`, which
+/// returns whether the server takes it.
+pub async fn serve<S, F>(
+    request: Vec<u8>,
+    mut stream: S,
+    sessions: &Sessions,
+    max_message_size: usize,
+    open: F,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(Option<&str>, FrameSink, FrameStream) -> bool,
+{
+    let Some(head) = Head::parse(&request) else {
+        return respond(&mut stream, "400 Bad Request", &[]).await;
+    };
+    let Some(id) = head.session() else {
+        return respond(&mut stream, "404 Not Found", &[]).await;
+    };
+    match head.method.as_str() {
+        "POST" if head.path.ends_with("/send") => {
+            let len = head
+                .header("content-length")
+                .and_then(|len| len.parse().ok())
+                .unwrap_or(0);
+            let authorized = sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .is_some_and(|session| session.authorized.load(Ordering::Relaxed));
+            let max = if authorized {
+                max_body_bytes(max_message_size)
+            } else {
+                MAX_UNAUTHORIZED_BODY_BYTES
+            };
+            if len > max {
+                return respond(&mut stream, "413 Payload Too Large", &[]).await;
+            }
+            let mut body = request[head_len(&request).unwrap()..].to_vec();
+            let remaining = len.saturating_sub(body.len());
+            let mut rest = (&mut stream).take(remaining as u64);
+            let read = rest.read_to_end(&mut body);
+            let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT_IN_SECONDS);
+            if !matches!(tokio::time::timeout(timeout, read).await, Ok(Ok(_))) {
+                return;
+            }
+            body.truncate(len);
+            let Ok(frames) = decode_frames(&body, max_message_size).await else {
+                return respond(&mut stream, "400 Bad Request", &[]).await;
+            };
+            let incoming = {
+                let mut sessions = sessions.lock().unwrap();
+                match sessions.get(&id) {
+                    Some(session) => Some(session.incoming.clone()),
+                    None => {
+                        // sessions whose connection ended without a word from the client
+                        sessions.retain(|_, session| !session.incoming.is_closed());
+                        let (incoming, from_client) = mpsc::unbounded();
+                        let (to_client, outgoing) = mpsc::channel(POLL_BUFFER);
+                        let authorized = Arc::new(AtomicBool::new(false));
+                        let sink = sink::unfold(
+                            (to_client, authorized.clone()),
+                            |(mut to_client, authorized), message: Message| async move {
+                                if message.is_text() || message.is_binary() {
+                                    authorized.store(true, Ordering::Relaxed);
+                                }
+                                future::poll_fn(|cx| to_client.poll_ready(cx))
+                                    .await
+                                    .and_then(|()| to_client.start_send(message))
+                                    .map_err(|_| Error::ConnectionClosed)?;
+                                Ok((to_client, authorized))
+                            },
+                        );
+                        let sink: FrameSink = Box::pin(sink);
+                        let from_client: FrameStream = Box::pin(from_client.map(Ok));
+                        let opened = open(head.query.as_deref(), sink, from_client);
+                        opened.then(|| {
+                            sessions.insert(
+                                id.clone(),
+                                Session {
+                                    incoming: incoming.clone(),
+                                    outgoing: Arc::new(tokio::sync::Mutex::new(outgoing)),
+                                    authorized,
+                                },
+                            );
+                            incoming
+                        })
+                    }
+                }
+            };
+            let Some(incoming) = incoming else {
+                return respond(&mut stream, "503 Service Unavailable", &[]).await;
+            };
+            for frame in frames {
+                if incoming.unbounded_send(frame).is_err() {
+                    sessions.lock().unwrap().remove(&id);
+                    return respond(&mut stream, "410 Gone", &[]).await;
+                }
+            }
+            respond(&mut stream, "204 No Content", &[]).await
+        }
+        "GET" if head.path.ends_with("/poll") => {
+            let outgoing = sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .map(|session| session.outgoing.clone());
+            let Some(outgoing) = outgoing else {
+                return respond(&mut stream, "404 Not Found", &[]).await;
+            };
+            let mut outgoing = outgoing.lock().await;
+            let mut body = Vec::new();
+            let mut ended = false;
+            let timeout = Duration::from_secs(POLL_TIMEOUT_IN_SECONDS);
+            match tokio::time::timeout(timeout, outgoing.next()).await {
+                Ok(Some(frame)) => body.extend(encode_frame(frame).unwrap_or_default()),
+                Ok(None) => ended = true,
+                Err(_) => {}
+            }
+            while !ended && body.len() < max_message_size {
+                match outgoing.try_next() {
+                    Ok(Some(frame)) => body.extend(encode_frame(frame).unwrap_or_default()),
+                    Ok(None) => ended = true,
+                    Err(_) => break,
+                }
+            }
+            if ended {
+                sessions.lock().unwrap().remove(&id);
+                if body.is_empty() {
+                    return respond(&mut stream, "410 Gone", &[]).await;
+                }
+            }
+            respond(&mut stream, "200 OK", &body).await
+        }
+        _ => respond(&mut stream, "404 Not Found", &[]).await,
+    }
+}
+
+/// Where the requests of a client's session go.
+struct Endpoint {
+    host: String,
+    port: u16,
+    tls: Option<Arc<ClientConfig>>,
+    /// the path of the `ws://` address, without the trailing slash
+    path: String,
+    /// the query of the `ws://` address, with the session added
+    query: String,
+    max_message_size: usize,
+}
+
+impl Endpoint {
+    async fn request(&self, method: &str, route: &str, body: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+        let head = format!(
+            "{} {}/{}?{} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            self.path,
+            route,
+            self.query,
+            self.host,
+            self.port,
+            body.len()
+        );
+        let timeout = Duration::from_secs(POLL_TIMEOUT_IN_SECONDS + HANDSHAKE_TIMEOUT_IN_SECONDS);
+        let exchange = async {
+            let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+            match &self.tls {
+                Some(config) => {
+                    let name = ServerName::try_from(self.host.as_str())
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                    let stream = TlsConnector::from(config.clone())
+                        .connect(name, stream)
+                        .await?;
+                    exchange(stream, &head, body, self.max_message_size).await
+                }
+                None => exchange(stream, &head, body, self.max_message_size).await,
+            }
+        };
+        tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no answer to the long poll"))?
+    }
+}
+
+/// Sends one request and reads the status and body of the answer.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    head: &str,
+    body: &[u8],
+    max_message_size: usize,
+) -> io::Result<(u16, Vec<u8>)> {
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response");
+    // the caller bounds the whole exchange
+    let mut response = read_head(&mut stream, Duration::MAX)
+        .await
+        .ok_or_else(malformed)?;
+    let head = Head::parse(&response).ok_or_else(malformed)?;
+    // the request line parses into the method and path slots
+    let status = head.path.parse().map_err(|_| malformed())?;
+    let len: usize = head
+        .header("content-length")
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(0);
+    if len > max_body_bytes(max_message_size) {
+        return Err(malformed());
+    }
+    let mut body = response.split_off(head_len(&response).unwrap());
+    let remaining = len.saturating_sub(body.len());
+    (&mut stream)
+        .take(remaining as u64)
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((status, body))
+}
+
+/// Speaks long polling to the server behind the `ws://` or `wss://` `addr`,
+/// taking frames of up to `max_message_size` bytes. Nothing is sent until
+/// the first frame, which opens the session.
+pub fn connect(
+    addr: &str,
+    connector: Option<&Connector>,
+    max_message_size: usize,
+) -> io::Result<(FrameSink, FrameStream)> {
+    let url = Url::parse(addr).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let tls = match url.scheme() {
+        "wss" => Some(client_config(connector)),
+        _ => None,
+    };
+    let host = url
+        .host_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no host in the address"))?;
+    let mut query =
+        url::form_urlencoded::Serializer::new(url.query().unwrap_or_default().to_string());
+    query.append_pair("session", &Ulid::new().to_string());
+    let endpoint = Arc::new(Endpoint {
+        host: host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        port: url.port_or_known_default().unwrap_or(80),
+        tls,
+        path: url.path().trim_end_matches('/').to_string(),
+        query: query.finish(),
+        max_message_size,
+    });
+
+    let sink = sink::unfold(endpoint.clone(), |endpoint, message: Message| async move {
+        let frame = encode_frame(message)?;
+        match endpoint.request("POST", "send", &frame).await? {
+            (204, _) => Ok(endpoint),
+            (status, _) => Err(Error::Io(io::Error::other(format!(
+                "the server answered POST /send with {}",
+                status
+            )))),
+        }
+    });
+    let stream = stream::unfold(Some((endpoint, VecDeque::new())), |state| async move {
+        let (endpoint, mut frames) = state?;
+        loop {
+            if let Some(frame) = frames.pop_front() {
+                // like a WebSocket, nothing follows a close frame
+                let state = match frame {
+                    Message::Close(_) => None,
+                    _ => Some((endpoint, frames)),
+                };
+                return Some((Ok(frame), state));
+            }
+            match endpoint.request("GET", "poll", &[]).await {
+                Ok((200, body)) => match decode_frames(&body, endpoint.max_message_size).await {
+                    Ok(decoded) => frames.extend(decoded),
+                    Err(err) => return Some((Err(err), None)),
+                },
+                // the server ended the session
+                Ok((404 | 410, _)) => return None,
+                Ok((status, _)) => {
+                    let err =
+                        io::Error::other(format!("the server answered GET /poll with {}", status));
+                    return Some((Err(Error::Io(err)), None));
+                }
+                Err(err) => return Some((Err(Error::Io(err)), None)),
+            }
+        }
+    });
+    Ok((Box::pin(sink), Box::pin(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::SinkExt;
+
+    use super::*;
+    use crate::config::MAX_MESSAGE_SIZE_IN_BYTES;
+
+    /// Answers `request` with `serve` and returns the response.
+    async fn request<F>(sessions: &Sessions, request: Vec<u8>, open: F) -> String
+    where
+        F: FnOnce(Option<&str>, FrameSink, FrameStream) -> bool,
+    {
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        serve(request, server, sessions, MAX_MESSAGE_SIZE_IN_BYTES, open).await;
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    fn send(text: &str) -> Vec<u8> {
+        let body = encode_frame(Message::Text(text.to_string())).unwrap();
+        let head = format!(
+            "POST /send?session=a HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        [head.into_bytes(), body].concat()
+    }
+
+    /// Accepts the session and acknowledges the handshake.
+    fn accept(_: Option<&str>, mut sink: FrameSink, mut stream: FrameStream) -> bool {
+        tokio::spawn(async move {
+            sink.send(Message::Text("ack".to_string())).await.unwrap();
+            while stream.next().await.is_some() {}
+        });
+        true
+    }
+
+    #[tokio::test]
+    async fn caps_bodies_until_the_handshake_is_answered() {
+        let sessions = Sessions::default();
+        let large = "x".repeat(MAX_UNAUTHORIZED_BODY_BYTES);
+        let refused = request(&sessions, send(&large), |_, _, _| unreachable!()).await;
+        assert!(refused.starts_with("HTTP/1.1 413"), "{}", refused);
+
+        let sent = request(&sessions, send("handshake"), accept).await;
+        assert!(sent.starts_with("HTTP/1.1 204"), "{}", sent);
+        let poll = b"GET /poll?session=a HTTP/1.1\r\n\r\n".to_vec();
+        let polled = request(&sessions, poll, |_, _, _| unreachable!()).await;
+        assert!(polled.starts_with("HTTP/1.1 200"), "{}", polled);
+
+        let sent = request(&sessions, send(&large), |_, _, _| unreachable!()).await;
+        assert!(sent.starts_with("HTTP/1.1 204"), "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn refuses_frames_over_max_message_size() {
+        let sessions = Sessions::default();
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        serve(send(&"x".repeat(64)), server, &sessions, 32, accept).await;
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        assert!(sessions.lock().unwrap().is_empty());
+    }
+}
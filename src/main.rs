@@ -1,39 +1,1041 @@
-use std::io;
+use std::{
+    env, fs, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-pub mod client;
-pub mod config;
-pub mod notify;
-pub mod server;
-
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use copy_sync::backend::ClipboardBackend;
+use copy_sync::client::{
+    self, ClientOptions, ConnectionState, ImageName, Selections, SyncMode, TextPolicy, Traffic,
+};
+use copy_sync::codec::{Compression, ImageCodec};
+use copy_sync::protocol::WireFormat;
+use copy_sync::server::{self, Cidr, IpVersion, ServerOptions};
+use copy_sync::shutdown::Shutdown;
+use copy_sync::transport::Transport;
+use copy_sync::watch::WatchMode;
+use copy_sync::{config, control, daemon, discovery, doctor, history, hotkey, inbox, service};
+use regex::Regex;
+use tokio::sync::watch;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+use tungstenite::protocol::WebSocketConfig;
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// Log filter, e.g. `debug` or `copy_sync=trace`
+    #[arg(long, global = true, default_value = "info")]
+    log_level: String,
+    /// Config file to use instead of searching for copy-sync.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+}
+#[derive(Args)]
+struct DaemonArgs {
+    /// Run in the background
+    #[arg(long)]
+    daemon: bool,
+    /// Don't report to systemd or the Windows service manager even when started by one
+    #[arg(long, conflicts_with = "daemon")]
+    foreground: bool,
+    /// Where the background process records its PID
+    #[arg(long, default_value_os_t = config::default_pid_file())]
+    pid_file: PathBuf,
+}
+
+impl DaemonArgs {
+    /// Hooks up a service manager that started the process, or re-launches
+    /// it in the background for --daemon. Returns whether this process is
+    /// done.
+    fn start(&self) -> io::Result<bool> {
+        if self.foreground || !service::detect() {
+            if self.daemon {
+                daemon::spawn(&self.pid_file)?;
+            }
+            return Ok(self.daemon);
+        }
+        if self.daemon {
+            // the service manager tracks this process, not a detached copy
+            warn!("started as a service, ignoring --daemon");
+        }
+        Ok(false)
+    }
+}
+
+#[derive(Args)]
+struct WebSocketArgs {
+    /// Largest WebSocket message accepted, in bytes [default: 67108864]
+    #[arg(long, value_parser = parse_size)]
+    max_message_size: Option<usize>,
+    /// Largest WebSocket frame accepted, in bytes [default: 67108864]
+    #[arg(long, value_parser = parse_size)]
+    max_frame_size: Option<usize>,
+    /// Frames queued for sending before sends fail [default: unbounded]
+    #[arg(long, value_parser = parse_size)]
+    max_send_queue: Option<usize>,
+    /// Accept frames a client didn't mask, which the WebSocket spec forbids
+    #[arg(long)]
+    accept_unmasked_frames: bool,
 }
+
+impl WebSocketArgs {
+    /// The limits, with the config file's in place of flags not given.
+    fn config(
+        self,
+        max_message_size: Option<usize>,
+        max_frame_size: Option<usize>,
+        max_send_queue: Option<usize>,
+        accept_unmasked_frames: Option<bool>,
+    ) -> WebSocketConfig {
+        config::web_socket_config(
+            self.max_message_size.or(max_message_size),
+            self.max_frame_size.or(max_frame_size),
+            self.max_send_queue.or(max_send_queue),
+            self.accept_unmasked_frames || accept_unmasked_frames.unwrap_or(false),
+        )
+    }
+}
+
+/// What `push`, `pull` and `send-text` need to reach the other clients;
+/// everything else comes from the `[connect]` section of the config file.
+#[derive(Args)]
+struct OnceArgs {
+    /// Server address, e.g. ws://host:5120 or tcp://host:5120 [default: the first addr in the config file]
+    #[arg(short, long)]
+    addr: Option<String>,
+    /// Shared secret expected by the server [default: $COPY_SYNC_TOKEN]
+    #[arg(long)]
+    token: Option<String>,
+    /// File holding --token, read when --token isn't given
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+    /// Passphrase for end-to-end encryption, must match on every client
+    #[arg(long)]
+    key: Option<String>,
+    /// Key to sign and check frames with, for servers and clients started with --sign-key
+    #[arg(long)]
+    sign_key: Option<String>,
+    /// Only sync with peers in the same room
+    #[arg(long)]
+    room: Option<String>,
+    /// Clipboard to sync, `system` or `file:<path>` holding text or a PNG [default: system]
+    #[arg(long)]
+    clipboard_backend: Option<ClipboardBackend>,
+}
+
+// parsed once per process, boxing the connect flags wouldn't buy anything
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     Start {
-        #[arg(short, long, default_value_t = 5120)]
-        port: u16,
+        /// Address of the interface to listen on, e.g. 127.0.0.1 or ::1 [default: 0.0.0.0]
+        #[arg(long)]
+        bind: Option<IpAddr>,
+        /// Listen on IPv4, IPv6, or both; sets the address when --bind is not given [default: v4]
+        #[arg(long, value_enum)]
+        ip_version: Option<IpVersion>,
+        /// Port to listen on [default: 5120]
+        #[arg(short, long)]
+        port: Option<u16>,
+        /// PEM certificate chain, enables TLS (wss://)
+        #[arg(long)]
+        cert: Option<PathBuf>,
+        /// PEM private key matching --cert
+        #[arg(long)]
+        key: Option<PathBuf>,
+        /// Shared secret clients must present to join [default: $COPY_SYNC_TOKEN]
+        #[arg(long)]
+        token: Option<String>,
+        /// File holding --token, read when --token isn't given
+        #[arg(long)]
+        token_file: Option<PathBuf>,
+        /// Only relay frames signed with this key, dropping unsigned and forged ones
+        #[arg(long)]
+        sign_key: Option<String>,
+        /// Seconds without any frame from a client before it is dropped [default: 45]
+        #[arg(long, value_parser = parse_positive)]
+        heartbeat_timeout: Option<u64>,
+        /// Serve Prometheus metrics over HTTP on this port
+        #[arg(long)]
+        metrics_port: Option<u16>,
+        /// Refuse new connections while this many clients are connected
+        #[arg(long)]
+        max_clients: Option<usize>,
+        /// Only accept clients from this address or CIDR block; repeat it or separate with commas
+        #[arg(long, value_delimiter = ',')]
+        allow: Vec<Cidr>,
+        /// Refuse clients from this address or CIDR block, even inside an --allow block
+        #[arg(long, value_delimiter = ',')]
+        deny: Vec<Cidr>,
+        /// Frames per second a client may send, an image counts once; excess frames are dropped
+        #[arg(long, value_parser = parse_positive)]
+        per_peer_rate: Option<u64>,
+        /// Bytes per second a client may send; excess frames are dropped
+        #[arg(long, value_parser = parse_positive)]
+        per_peer_bytes: Option<u64>,
+        /// Frames waiting for a client before it is disconnected as too slow [default: 256]
+        #[arg(long, value_parser = parse_positive)]
+        peer_queue_size: Option<u64>,
+        /// Log the connected peers every minute (SIGUSR1 logs them any time)
+        #[arg(long)]
+        verbose: bool,
+        /// Speak WebSocket, or length-prefixed frames for tcp:// clients [default: ws]
+        #[arg(long, value_enum)]
+        transport: Option<Transport>,
+        /// Stamp updates with the time they reach the server, for clients whose clocks disagree
+        #[arg(long)]
+        server_time: bool,
+        /// Don't relay a frame identical to the one just relayed in the room
+        #[arg(long)]
+        dedup: bool,
+        /// Answer mDNS queries so clients on the LAN can find the server with --auto
+        #[arg(long)]
+        advertise: bool,
+        #[command(flatten)]
+        web_socket: WebSocketArgs,
+        #[command(flatten)]
+        daemon: DaemonArgs,
     },
     Connect {
-        #[arg(short, long)]
-        addr: String,
+        /// Server address, e.g. ws://host:5120 or tcp://host:5120; repeat it or separate with commas to fail over
+        #[arg(short, long, value_delimiter = ',')]
+        addr: Vec<String>,
+        /// Without --addr, find servers started with --advertise on the LAN over mDNS
+        #[arg(long)]
+        auto: bool,
+        /// Clipboard polling interval in milliseconds [default: 2000]
+        #[arg(long, value_parser = parse_positive)]
+        poll_interval: Option<u64>,
+        /// How clipboard changes are detected [default: poll]
+        #[arg(long, value_enum)]
+        watch_mode: Option<WatchMode>,
+        /// Shared secret expected by the server [default: $COPY_SYNC_TOKEN]
+        #[arg(long)]
+        token: Option<String>,
+        /// File holding --token, read when --token isn't given
+        #[arg(long)]
+        token_file: Option<PathBuf>,
+        /// Passphrase for end-to-end encryption, must match on every client
+        #[arg(long)]
+        key: Option<String>,
+        /// Sign sent frames with this key and apply only frames signed with it
+        #[arg(long)]
+        sign_key: Option<String>,
+        /// Upper bound in seconds for the reconnect backoff [default: 60]
+        #[arg(long, value_parser = parse_positive)]
+        max_retry_interval: Option<u64>,
+        /// Exit with an error after this many failed connects in a row [default: unlimited]
+        #[arg(long)]
+        max_retries: Option<u32>,
+        /// Seconds to wait for a server to accept the connection [default: 10]
+        #[arg(long, value_parser = parse_positive)]
+        connect_timeout: Option<u64>,
+        /// Directory received files are written to, never over an existing file [default: <downloads>/copy-sync]
+        #[arg(long)]
+        download_dir: Option<PathBuf>,
+        /// Also save received images to disk as PNG
+        #[arg(long)]
+        save_images: bool,
+        /// Send an image together with the text copied along with it, e.g. its file URL
+        #[arg(long)]
+        image_with_text: bool,
+        /// Don't send an image again within this many seconds of sending it
+        #[arg(long)]
+        image_dedup_window: Option<u64>,
+        /// Directory --save-images writes to [default: --download-dir]
+        #[arg(long)]
+        save_dir: Option<PathBuf>,
+        /// File name for --save-images, with {timestamp}, {width}, {height} and {name} of the sender [default: {name}-{timestamp}-{width}x{height}.png]
+        #[arg(long)]
+        image_name: Option<ImageName>,
+        /// Whether to push local changes, apply remote ones, or both [default: both]
+        #[arg(long, value_enum)]
+        mode: Option<SyncMode>,
+        /// Which selections to sync, primary only on X11 and Wayland [default: clipboard]
+        #[arg(long, value_enum)]
+        selection: Option<Selections>,
+        /// Drop images larger than this many bytes after compression
+        #[arg(long)]
+        max_image_bytes: Option<usize>,
+        /// Apply --text-policy to texts larger than this many bytes
+        #[arg(long)]
+        max_text_bytes: Option<usize>,
+        /// Whether texts over --max-text-bytes are skipped or truncated [default: skip]
+        #[arg(long, value_enum)]
+        text_policy: Option<TextPolicy>,
+        /// How images are encoded before sending [default: raw]
+        #[arg(long, value_enum)]
+        image_codec: Option<ImageCodec>,
+        /// Only sync plain text
+        #[arg(long)]
+        text_only: bool,
+        /// Never send what a password manager marked as concealed on the system clipboard
+        #[arg(long)]
+        password_strip: bool,
+        /// Only sync with peers in the same room
+        #[arg(long)]
+        room: Option<String>,
+        /// Label shown in the notifications of peers receiving from this machine [default: hostname]
+        #[arg(long)]
+        name: Option<String>,
+        /// Seconds without any frame from the server before reconnecting [default: 45]
+        #[arg(long, value_parser = parse_positive)]
+        heartbeat_timeout: Option<u64>,
+        /// How many recent texts `copy-sync history` can show, 0 disables it [default: 0]
+        #[arg(long)]
+        history_size: Option<usize>,
+        /// Socket (named pipe on Windows) serving the history
+        #[arg(long)]
+        history_socket: Option<PathBuf>,
+        /// Append a JSON line with the time, direction, type, size and SHA-256 of every item synced
+        #[arg(long)]
+        audit_log: Option<PathBuf>,
+        /// Also put text, HTML and RTF themselves in --audit-log
+        #[arg(long)]
+        audit_content: bool,
+        /// Never show desktop notifications
+        #[arg(long)]
+        no_notify: bool,
+        /// Also notify when text arrives, with a short preview
+        #[arg(long, conflicts_with = "no_notify")]
+        notify_text: bool,
+        /// Also notify when the connection to the server comes up or goes down
+        #[arg(long, conflicts_with = "no_notify")]
+        notify_connection: bool,
+        /// Only send a change once the clipboard stays the same for this many milliseconds
+        #[arg(long)]
+        debounce: Option<u64>,
+        /// Don't resend text that only changed in leading or trailing whitespace
+        #[arg(long)]
+        normalize_text: bool,
+        /// Don't send text matching this regex, repeat it for more patterns
+        #[arg(long)]
+        exclude_pattern: Vec<Regex>,
+        /// Send text that looks like a private key or an API token too
+        #[arg(long)]
+        no_default_excludes: bool,
+        /// Clipboard to sync, `system` or `file:<path>` holding text or a PNG [default: system]
+        #[arg(long)]
+        clipboard_backend: Option<ClipboardBackend>,
+        /// How raw images and files are compressed before sending [default: zlib]
+        #[arg(long, value_enum)]
+        compress: Option<Compression>,
+        /// Level for --compress, 0 (fastest) to 9 (smallest) [default: 6]
+        #[arg(long, value_parser = clap::value_parser!(u32).range(0..=9))]
+        compression: Option<u32>,
+        /// How frames travel to the server, `compact` if it agrees [default: json]
+        #[arg(long, value_enum)]
+        wire_format: Option<WireFormat>,
+        /// PEM CA certificate to verify wss:// servers against, e.g. a self-signed relay's
+        #[arg(long)]
+        ca_cert: Option<PathBuf>,
+        /// Only accept the wss:// server certificate with this SHA-256 fingerprint
+        #[arg(long)]
+        pin_fingerprint: Option<String>,
+        /// Pace uploads to this many kilobits per second on average, nothing is dropped
+        #[arg(long, value_parser = parse_positive)]
+        max_upload_kbps: Option<u64>,
+        /// Resend frames the server hasn't acknowledged up to this many times
+        #[arg(long)]
+        retransmit: Option<u32>,
+        /// Show the connection state as a status line on stderr
+        #[arg(long)]
+        status: bool,
+        /// Keep received items for `copy-sync inbox` instead of overwriting the clipboard
+        #[arg(long)]
+        read_only_clipboard: bool,
+        /// Socket (named pipe on Windows) serving the inbox
+        #[arg(long)]
+        inbox_socket: Option<PathBuf>,
+        /// Socket (named pipe on Windows) listening for `copy-sync pause` and `copy-sync resume`
+        #[arg(long)]
+        control_socket: Option<PathBuf>,
+        /// Stop syncing automatically, send the clipboard only when this hotkey is pressed, e.g. ctrl+shift+c
+        #[arg(long)]
+        manual_sync: Option<String>,
+        #[command(flatten)]
+        web_socket: WebSocketArgs,
+        #[command(flatten)]
+        daemon: DaemonArgs,
+    },
+    /// Log what the clipboard watcher detects without connecting anywhere
+    Monitor {
+        /// Clipboard polling interval in milliseconds [default: 2000]
+        #[arg(long, value_parser = parse_positive)]
+        poll_interval: Option<u64>,
+        /// How clipboard changes are detected [default: poll]
+        #[arg(long, value_enum)]
+        watch_mode: Option<WatchMode>,
+        /// How images are encoded before sending [default: raw]
+        #[arg(long, value_enum)]
+        image_codec: Option<ImageCodec>,
+        /// How raw images and files are compressed before sending [default: zlib]
+        #[arg(long, value_enum)]
+        compress: Option<Compression>,
+        /// Level for --compress, 0 (fastest) to 9 (smallest) [default: 6]
+        #[arg(long, value_parser = clap::value_parser!(u32).range(0..=9))]
+        compression: Option<u32>,
+        /// Only watch plain text
+        #[arg(long)]
+        text_only: bool,
+        /// Clipboard to watch, `system` or `file:<path>` holding text or a PNG [default: system]
+        #[arg(long)]
+        clipboard_backend: Option<ClipboardBackend>,
+    },
+    /// Send the clipboard once and exit
+    Push {
+        #[command(flatten)]
+        args: OnceArgs,
+    },
+    /// Send a text to the other clients without touching the clipboard, and exit
+    SendText {
+        #[command(flatten)]
+        args: OnceArgs,
+        /// Text to send [default: stdin, without trailing newlines]
+        #[arg(long)]
+        text: Option<String>,
+    },
+    /// Wait for the latest update, put it on the clipboard and exit
+    Pull {
+        #[command(flatten)]
+        args: OnceArgs,
+    },
+    /// List the servers started with --advertise on the LAN
+    Discover,
+    /// Check the clipboard, notifications and the server, and print what was found
+    Doctor {
+        #[command(flatten)]
+        args: OnceArgs,
+    },
+    /// Print the texts recently synced by a running client, newest first
+    History {
+        #[arg(long, default_value_os_t = config::default_history_socket())]
+        history_socket: PathBuf,
     },
+    /// Print what a --read-only-clipboard client received, newest first
+    Inbox {
+        /// Put the nth newest item on that client's clipboard instead
+        #[arg(long, value_parser = parse_positive)]
+        paste: Option<u64>,
+        #[arg(long, default_value_os_t = config::default_inbox_socket())]
+        inbox_socket: PathBuf,
+    },
+    /// Stop a running client from sending or applying updates until `copy-sync resume`
+    Pause {
+        #[arg(long, default_value_os_t = config::default_control_socket())]
+        control_socket: PathBuf,
+    },
+    /// Let a paused client sync again
+    Resume {
+        #[arg(long, default_value_os_t = config::default_control_socket())]
+        control_socket: PathBuf,
+    },
+    /// Stop an instance started with --daemon
+    Stop {
+        #[arg(long, default_value_os_t = config::default_pid_file())]
+        pid_file: PathBuf,
+    },
+    /// Save the clipboard to a file, or put a saved one back
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+        /// Passphrase to encrypt the snapshot with, needed again to restore it
+        #[arg(long, global = true)]
+        key: Option<String>,
+        /// Clipboard to save or restore, `system` or `file:<path>` holding text or a PNG [default: system]
+        #[arg(long, global = true)]
+        clipboard_backend: Option<ClipboardBackend>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SnapshotAction {
+    /// Write what the clipboard holds to FILE
+    Save { file: PathBuf },
+    /// Put what FILE holds on the clipboard
+    Restore { file: PathBuf },
+}
+
+fn parse_positive(value: &str) -> Result<u64, String> {
+    let value: u64 = value.parse().map_err(|err| format!("{}", err))?;
+    if value == 0 {
+        return Err("must be greater than 0".to_string());
+    }
+    Ok(value)
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    parse_positive(value).map(|value| value as usize)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// The token from `--token`, `--token-file` or `COPY_SYNC_TOKEN`, falling
+/// back to `token` and then `token-file` in the config file.
+fn load_token(
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    file_token: Option<String>,
+    file_token_file: Option<PathBuf>,
+) -> io::Result<Option<String>> {
+    if token.is_some() {
+        return Ok(token);
+    }
+    let read = |path: PathBuf| {
+        let token = fs::read_to_string(&path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("cannot read token file {}: {}", path.display(), err),
+            )
+        })?;
+        Ok(Some(token.trim_end_matches(['\r', '\n']).to_string()))
+    };
+    if let Some(path) = token_file {
+        return read(path);
+    }
+    if let Some(token) = env::var("COPY_SYNC_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+    {
+        return Ok(Some(token));
+    }
+    match (file_token, file_token_file) {
+        (Some(token), _) => Ok(Some(token)),
+        (None, Some(path)) => read(path),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The server and options for a one-off `push` or `pull`.
+fn once_options(
+    args: OnceArgs,
+    file: config::ConnectConfig,
+) -> io::Result<(String, ClientOptions)> {
+    let (addr, options) = once_settings(args, file)?;
+    let addr = addr.ok_or_else(|| {
+        invalid_input("no server address, pass --addr or set addr in copy-sync.toml")
+    })?;
+    Ok((addr, options))
+}
+
+/// What `push`, `pull`, `send-text` and `doctor` take from the flags and the config file,
+/// with the server if either names one.
+fn once_settings(
+    args: OnceArgs,
+    file: config::ConnectConfig,
+) -> io::Result<(Option<String>, ClientOptions)> {
+    let addr = args.addr.or_else(|| file.addr.into_iter().next());
+    let defaults = ClientOptions::default();
+    let options = ClientOptions {
+        token: load_token(args.token, args.token_file, file.token, file.token_file)?,
+        key: args.key.or(file.key),
+        sign_key: args.sign_key.or(file.sign_key),
+        room: args.room.or(file.room),
+        name: file.name.or(defaults.name),
+        connect_timeout: file.connect_timeout.unwrap_or(defaults.connect_timeout),
+        web_socket: config::web_socket_config(
+            file.max_message_size,
+            file.max_frame_size,
+            file.max_send_queue,
+            file.accept_unmasked_frames.unwrap_or(false),
+        ),
+        clipboard_backend: args
+            .clipboard_backend
+            .or(file.clipboard_backend)
+            .unwrap_or(defaults.clipboard_backend),
+        download_dir: file.download_dir.unwrap_or(defaults.download_dir),
+        max_image_bytes: file.max_image_bytes,
+        max_text_bytes: file.max_text_bytes,
+        text_policy: file.text_policy.unwrap_or(defaults.text_policy),
+        text_only: file.text_only.unwrap_or(false),
+        exclude_patterns: file.exclude_pattern,
+        default_excludes: !file.no_default_excludes.unwrap_or(false),
+        heartbeat_timeout: file.heartbeat_timeout.unwrap_or(defaults.heartbeat_timeout),
+        image_codec: file.image_codec.unwrap_or(defaults.image_codec),
+        compress: file.compress.unwrap_or(defaults.compress),
+        compression: file.compression.unwrap_or(defaults.compression),
+        wire_format: file.wire_format.unwrap_or(defaults.wire_format),
+        ca_cert: file.ca_cert,
+        pin_fingerprint: file.pin_fingerprint,
+        notify: false,
+        ..defaults
+    };
+    Ok((addr, options))
+}
+
+/// Servers advertised over mDNS, an error if there are none.
+async fn discover() -> io::Result<Vec<discovery::Found>> {
+    let found = discovery::browse(Duration::from_millis(
+        config::DISCOVERY_TIMEOUT_IN_MILLISECONDS,
+    ))
+    .await?;
+    if found.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no copy-sync server answered on the LAN",
+        ));
+    }
+    Ok(found)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), io::Error> {
     let cli = Cli::parse();
 
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_new(&cli.log_level).map_err(|err| invalid_input(&err.to_string()))?,
+        )
+        .init();
+
     match cli.command {
-        Some(Commands::Start { port }) => server::start(port).await,
-        Some(Commands::Connect { addr }) => client::start(addr).await,
+        Some(Commands::Start {
+            bind,
+            ip_version,
+            port,
+            cert,
+            key,
+            token,
+            token_file,
+            sign_key,
+            heartbeat_timeout,
+            metrics_port,
+            max_clients,
+            allow,
+            deny,
+            per_peer_rate,
+            per_peer_bytes,
+            peer_queue_size,
+            verbose,
+            transport,
+            server_time,
+            dedup,
+            advertise,
+            web_socket,
+            daemon,
+        }) => {
+            let file = config::load(cli.config.as_deref())?.start;
+            let cert = cert.or(file.cert);
+            let key = key.or(file.key);
+            if cert.is_some() != key.is_some() {
+                return Err(invalid_input("cert and key must be set together"));
+            }
+            let ip_version = ip_version.or(file.ip_version).unwrap_or_default();
+            let addr = SocketAddr::new(
+                bind.or(file.bind).unwrap_or(ip_version.unspecified()),
+                port.or(file.port).unwrap_or(config::DEFAULT_PORT),
+            );
+            let options = ServerOptions {
+                cert,
+                key,
+                token: load_token(token, token_file, file.token, file.token_file)?,
+                sign_key: sign_key.or(file.sign_key),
+                heartbeat_timeout: heartbeat_timeout
+                    .or(file.heartbeat_timeout)
+                    .unwrap_or(config::DEFAULT_HEARTBEAT_TIMEOUT_IN_SECONDS),
+                metrics_port: metrics_port.or(file.metrics_port),
+                max_clients: max_clients.or(file.max_clients),
+                allow: if allow.is_empty() { file.allow } else { allow },
+                deny: if deny.is_empty() { file.deny } else { deny },
+                per_peer_rate: per_peer_rate.or(file.per_peer_rate),
+                per_peer_bytes: per_peer_bytes.or(file.per_peer_bytes),
+                peer_queue_size: peer_queue_size
+                    .or(file.peer_queue_size)
+                    .unwrap_or(config::DEFAULT_PEER_QUEUE_SIZE)
+                    as usize,
+                verbose: verbose || file.verbose.unwrap_or(false),
+                transport: transport.or(file.transport).unwrap_or_default(),
+                server_time: server_time || file.server_time.unwrap_or(false),
+                ip_version,
+                dedup: dedup || file.dedup.unwrap_or(false),
+                advertise: advertise || file.advertise.unwrap_or(false),
+                web_socket: web_socket.config(
+                    file.max_message_size,
+                    file.max_frame_size,
+                    file.max_send_queue,
+                    file.accept_unmasked_frames,
+                ),
+            };
+            // the background copy would fail where the error can't be seen
+            if daemon.daemon {
+                server::check(addr, &options)?;
+            }
+            if daemon.start()? {
+                return Ok(());
+            }
+            let result = server::start(addr, options, Shutdown::listen()).await;
+            service::stopped(result.is_ok());
+            result?;
+        }
+        Some(Commands::Connect {
+            addr,
+            auto,
+            poll_interval,
+            watch_mode,
+            token,
+            token_file,
+            key,
+            sign_key,
+            max_retry_interval,
+            max_retries,
+            connect_timeout,
+            download_dir,
+            save_images,
+            save_dir,
+            image_name,
+            image_with_text,
+            image_dedup_window,
+            mode,
+            selection,
+            max_image_bytes,
+            max_text_bytes,
+            text_policy,
+            image_codec,
+            text_only,
+            password_strip,
+            room,
+            name,
+            heartbeat_timeout,
+            history_size,
+            history_socket,
+            audit_log,
+            audit_content,
+            no_notify,
+            notify_text,
+            notify_connection,
+            debounce,
+            normalize_text,
+            exclude_pattern,
+            no_default_excludes,
+            clipboard_backend,
+            compress,
+            compression,
+            wire_format,
+            manual_sync,
+            ca_cert,
+            pin_fingerprint,
+            max_upload_kbps,
+            retransmit,
+            status,
+            read_only_clipboard,
+            inbox_socket,
+            control_socket,
+            web_socket,
+            daemon,
+        }) => {
+            let file = config::load(cli.config.as_deref())?.connect;
+            let auto = auto || file.auto.unwrap_or(false);
+            let addrs = match (addr.is_empty(), auto) {
+                (false, _) => addr,
+                (true, true) => Vec::new(),
+                (true, false) => file.addr,
+            };
+            if addrs.is_empty() && !auto {
+                return Err(invalid_input(
+                    "no server address, pass --addr or --auto, or set addr in copy-sync.toml",
+                ));
+            }
+            let defaults = ClientOptions::default();
+            let download_dir = download_dir
+                .or(file.download_dir)
+                .unwrap_or(defaults.download_dir);
+            let options = ClientOptions {
+                poll_interval: poll_interval
+                    .or(file.poll_interval)
+                    .unwrap_or(defaults.poll_interval),
+                watch_mode: watch_mode
+                    .or(file.watch_mode)
+                    .unwrap_or(defaults.watch_mode),
+                token: load_token(token, token_file, file.token, file.token_file)?,
+                key: key.or(file.key),
+                sign_key: sign_key.or(file.sign_key),
+                max_retry_interval: max_retry_interval
+                    .or(file.max_retry_interval)
+                    .unwrap_or(defaults.max_retry_interval),
+                max_retries: max_retries.or(file.max_retries),
+                connect_timeout: connect_timeout
+                    .or(file.connect_timeout)
+                    .unwrap_or(defaults.connect_timeout),
+                save_images: save_images || file.save_images.unwrap_or(false),
+                image_with_text: image_with_text || file.image_with_text.unwrap_or(false),
+                image_dedup_window: image_dedup_window.or(file.image_dedup_window),
+                save_dir: save_dir
+                    .or(file.save_dir)
+                    .unwrap_or_else(|| download_dir.clone()),
+                image_name: image_name
+                    .or(file.image_name)
+                    .unwrap_or(defaults.image_name),
+                download_dir,
+                mode: mode.or(file.mode).unwrap_or(defaults.mode),
+                selection: selection.or(file.selection).unwrap_or(defaults.selection),
+                max_image_bytes: max_image_bytes.or(file.max_image_bytes),
+                max_text_bytes: max_text_bytes.or(file.max_text_bytes),
+                text_policy: text_policy
+                    .or(file.text_policy)
+                    .unwrap_or(defaults.text_policy),
+                text_only: text_only || file.text_only.unwrap_or(false),
+                password_strip: password_strip || file.password_strip.unwrap_or(false),
+                room: room.or(file.room),
+                name: name.or(file.name).or(defaults.name),
+                heartbeat_timeout: heartbeat_timeout
+                    .or(file.heartbeat_timeout)
+                    .unwrap_or(defaults.heartbeat_timeout),
+                image_codec: image_codec
+                    .or(file.image_codec)
+                    .unwrap_or(defaults.image_codec),
+                history_size: history_size
+                    .or(file.history_size)
+                    .unwrap_or(defaults.history_size),
+                history_socket: history_socket
+                    .or(file.history_socket)
+                    .unwrap_or(defaults.history_socket),
+                audit_log: audit_log.or(file.audit_log),
+                audit_content: audit_content || file.audit_content.unwrap_or(false),
+                notify: !(no_notify || file.no_notify.unwrap_or(false)),
+                notify_text: notify_text || file.notify_text.unwrap_or(false),
+                notify_connection: notify_connection || file.notify_connection.unwrap_or(false),
+                web_socket: web_socket.config(
+                    file.max_message_size,
+                    file.max_frame_size,
+                    file.max_send_queue,
+                    file.accept_unmasked_frames,
+                ),
+                debounce: debounce.or(file.debounce).unwrap_or(defaults.debounce),
+                normalize_text: normalize_text || file.normalize_text.unwrap_or(false),
+                exclude_patterns: if exclude_pattern.is_empty() {
+                    file.exclude_pattern
+                } else {
+                    exclude_pattern
+                },
+                default_excludes: !(no_default_excludes
+                    || file.no_default_excludes.unwrap_or(false)),
+                clipboard_backend: clipboard_backend
+                    .or(file.clipboard_backend)
+                    .unwrap_or(defaults.clipboard_backend),
+                compress: compress.or(file.compress).unwrap_or(defaults.compress),
+                compression: compression
+                    .or(file.compression)
+                    .unwrap_or(defaults.compression),
+                wire_format: wire_format
+                    .or(file.wire_format)
+                    .unwrap_or(defaults.wire_format),
+                read_only_clipboard: read_only_clipboard
+                    || file.read_only_clipboard.unwrap_or(false),
+                inbox_socket: inbox_socket
+                    .or(file.inbox_socket)
+                    .unwrap_or(defaults.inbox_socket),
+                control_socket: Some(
+                    control_socket
+                        .or(file.control_socket)
+                        .unwrap_or_else(config::default_control_socket),
+                ),
+                max_upload_kbps: max_upload_kbps.or(file.max_upload_kbps),
+                retransmit: retransmit.or(file.retransmit),
+                ca_cert: ca_cert.or(file.ca_cert),
+                pin_fingerprint: pin_fingerprint.or(file.pin_fingerprint),
+            };
+            let hotkey = manual_sync
+                .or(file.manual_sync)
+                .map(|hotkey| {
+                    hotkey
+                        .parse()
+                        .map_err(|err| invalid_input(&format!("--manual-sync: {}", err)))
+                })
+                .transpose()?;
+            // everything above fails here, where the error can still be seen
+            if daemon.start()? {
+                return Ok(());
+            }
+            let addrs = if addrs.is_empty() {
+                discover()
+                    .await?
+                    .into_iter()
+                    .map(|found| found.addr)
+                    .collect()
+            } else {
+                addrs
+            };
+            let manual = hotkey.map(hotkey::listen).transpose()?;
+            let (sender, receiver) = watch::channel(ConnectionState::Connecting);
+            if status || file.status.unwrap_or(false) {
+                tokio::spawn(client::print_status(receiver));
+            }
+            let traffic = Arc::new(Traffic::default());
+            service::ready();
+            let result =
+                client::start(addrs, options, manual, sender, traffic, Shutdown::listen()).await;
+            service::stopped(result.is_ok());
+            result?;
+        }
+        Some(Commands::Monitor {
+            poll_interval,
+            watch_mode,
+            image_codec,
+            compress,
+            compression,
+            text_only,
+            clipboard_backend,
+        }) => {
+            let file = config::load(cli.config.as_deref())?.connect;
+            let defaults = ClientOptions::default();
+            let options = ClientOptions {
+                poll_interval: poll_interval
+                    .or(file.poll_interval)
+                    .unwrap_or(defaults.poll_interval),
+                watch_mode: watch_mode
+                    .or(file.watch_mode)
+                    .unwrap_or(defaults.watch_mode),
+                image_codec: image_codec
+                    .or(file.image_codec)
+                    .unwrap_or(defaults.image_codec),
+                compress: compress.or(file.compress).unwrap_or(defaults.compress),
+                compression: compression
+                    .or(file.compression)
+                    .unwrap_or(defaults.compression),
+                text_only: text_only || file.text_only.unwrap_or(false),
+                clipboard_backend: clipboard_backend
+                    .or(file.clipboard_backend)
+                    .unwrap_or(defaults.clipboard_backend),
+                ..defaults
+            };
+            client::monitor(options, Shutdown::listen()).await
+        }
+        Some(Commands::Push { args }) => {
+            let file = config::load(cli.config.as_deref())?.connect;
+            let (addr, options) = once_options(args, file)?;
+            client::push(addr, options).await?
+        }
+        Some(Commands::SendText { args, text }) => {
+            let file = config::load(cli.config.as_deref())?.connect;
+            let (addr, options) = once_options(args, file)?;
+            let text = match text {
+                Some(text) => text,
+                None => io::read_to_string(io::stdin())?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string(),
+            };
+            if text.is_empty() {
+                return Err(invalid_input("nothing to send, the text is empty"));
+            }
+            client::push_text(addr, text, options).await?
+        }
+        Some(Commands::Pull { args }) => {
+            let file = config::load(cli.config.as_deref())?.connect;
+            let (addr, options) = once_options(args, file)?;
+            client::pull(addr, options).await?
+        }
+        Some(Commands::Doctor { args }) => {
+            let file = config::load(cli.config.as_deref())?.connect;
+            let notify = !file.no_notify.unwrap_or(false);
+            let (addr, options) = once_settings(args, file)?;
+            let options = ClientOptions { notify, ..options };
+            if !doctor::run(addr, options).await {
+                return Err(io::Error::other("some checks failed"));
+            }
+        }
+        Some(Commands::Discover) => {
+            for found in discover().await? {
+                println!("{}: {}", found.name, found.addr);
+            }
+        }
+        Some(Commands::Snapshot {
+            action,
+            key,
+            clipboard_backend,
+        }) => {
+            let file = config::load(cli.config.as_deref())?.connect;
+            let defaults = ClientOptions::default();
+            let options = ClientOptions {
+                key: key.or(file.key),
+                clipboard_backend: clipboard_backend
+                    .or(file.clipboard_backend)
+                    .unwrap_or(defaults.clipboard_backend),
+                image_codec: file.image_codec.unwrap_or(defaults.image_codec),
+                text_only: file.text_only.unwrap_or(false),
+                // restoring isn't news
+                notify: false,
+                ..defaults
+            };
+            match action {
+                SnapshotAction::Save { file } => client::save_snapshot(&file, options).await?,
+                SnapshotAction::Restore { file } => {
+                    client::restore_snapshot(&file, options).await?
+                }
+            }
+        }
+        Some(Commands::History { history_socket }) => {
+            let entries = history::fetch(&history_socket).await?;
+            for (index, entry) in entries.iter().enumerate() {
+                println!("{}: {}", index + 1, entry);
+            }
+        }
+        Some(Commands::Inbox {
+            paste: Some(index),
+            inbox_socket,
+        }) => inbox::paste(&inbox_socket, index as usize).await?,
+        Some(Commands::Inbox {
+            paste: None,
+            inbox_socket,
+        }) => {
+            let entries = inbox::list(&inbox_socket).await?;
+            for (index, entry) in entries.iter().enumerate() {
+                println!("{}: {}", index + 1, entry);
+            }
+        }
+        Some(Commands::Pause { control_socket }) => control::pause(&control_socket).await?,
+        Some(Commands::Resume { control_socket }) => control::resume(&control_socket).await?,
+        Some(Commands::Stop { pid_file }) => return daemon::stop(&pid_file),
         None => {}
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_levels_stop_at_9() {
+        let connect = |level: &str| {
+            Cli::try_parse_from([
+                "copy-sync",
+                "connect",
+                "--addr",
+                "ws://host",
+                "--compression",
+                level,
+            ])
+        };
+        assert!(connect("0").is_ok());
+        assert!(connect("9").is_ok());
+        assert!(connect("10").is_err());
+        assert!(connect("-1").is_err());
+    }
+
+    #[test]
+    fn monitor_watches_the_chosen_clipboard() {
+        let cli = Cli::try_parse_from([
+            "copy-sync",
+            "monitor",
+            "--clipboard-backend",
+            "file:/tmp/clipboard",
+        ])
+        .unwrap();
+        let Some(Commands::Monitor {
+            clipboard_backend, ..
+        }) = cli.command
+        else {
+            panic!("not the monitor command");
+        };
+        assert!(clipboard_backend == Some(ClipboardBackend::File(PathBuf::from("/tmp/clipboard"))));
+    }
+}
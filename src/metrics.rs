@@ -0,0 +1,122 @@
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+
+static MESSAGES_BROADCAST: AtomicU64 = AtomicU64::new(0);
+
+static BYTES_RELAYED: AtomicU64 = AtomicU64::new(0);
+
+const MAX_REQUEST_BYTES: usize = 8 << 10;
+
+const REQUEST_TIMEOUT_IN_SECONDS: u64 = 5;
+
+/// Counts one message relayed as `bytes` bytes to each of `recipients` peers.
+pub fn record_broadcast(bytes: usize, recipients: usize) {
+    MESSAGES_BROADCAST.fetch_add(1, Ordering::Relaxed);
+    BYTES_RELAYED.fetch_add((bytes * recipients) as u64, Ordering::Relaxed);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders the counters and the per-room peer counts in the Prometheus text
+/// format.
+fn render(rooms: &[(String, usize)]) -> String {
+    let mut body = String::new();
+    let peers: usize = rooms.iter().map(|(_, peers)| peers).sum();
+    let _ = writeln!(body, "# HELP copy_sync_peers Connected peers.");
+    let _ = writeln!(body, "# TYPE copy_sync_peers gauge");
+    let _ = writeln!(body, "copy_sync_peers {}", peers);
+    let _ = writeln!(
+        body,
+        "# HELP copy_sync_room_peers Connected peers per room."
+    );
+    let _ = writeln!(body, "# TYPE copy_sync_room_peers gauge");
+    for (room, peers) in rooms {
+        let _ = writeln!(
+            body,
+            "copy_sync_room_peers{{room=\"{}\"}} {}",
+            escape_label(room),
+            peers
+        );
+    }
+    let _ = writeln!(
+        body,
+        "# HELP copy_sync_messages_broadcast_total Messages relayed to a room."
+    );
+    let _ = writeln!(body, "# TYPE copy_sync_messages_broadcast_total counter");
+    let _ = writeln!(
+        body,
+        "copy_sync_messages_broadcast_total {}",
+        MESSAGES_BROADCAST.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        body,
+        "# HELP copy_sync_relayed_bytes_total Bytes queued for delivery to peers."
+    );
+    let _ = writeln!(body, "# TYPE copy_sync_relayed_bytes_total counter");
+    let _ = writeln!(
+        body,
+        "copy_sync_relayed_bytes_total {}",
+        BYTES_RELAYED.load(Ordering::Relaxed)
+    );
+    body
+}
+
+/// Reads the request head, whatever it asks for, and answers with the metrics.
+async fn respond(mut stream: TcpStream, body: String) {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    let read_head = async {
+        while !request.windows(4).any(|window| window == b"\r\n\r\n")
+            && request.len() < MAX_REQUEST_BYTES
+        {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(len) => request.extend_from_slice(&buffer[..len]),
+            }
+        }
+    };
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT_IN_SECONDS);
+    if tokio::time::timeout(timeout, read_head).await.is_err() {
+        return;
+    }
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Serves the metrics over plain HTTP on `addr`. `rooms` is asked for the
+/// current peer count of every room on each scrape.
+pub async fn serve<F>(addr: SocketAddr, rooms: F)
+where
+    F: Fn() -> Vec<(String, usize)>,
+{
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("Metrics unavailable on {}: {:?}", addr, err);
+            return;
+        }
+    };
+    info!("Metrics on http://{}/metrics", addr);
+    while let Ok((stream, peer)) = listener.accept().await {
+        debug!("Metrics scrape from {}", peer);
+        tokio::spawn(respond(stream, render(&rooms())));
+    }
+}
@@ -1,9 +1,21 @@
-use notify_rust::Notification;
+use notify_rust::{error::Error, Notification};
 
-pub fn notify(message: &str) {
+const PREVIEW_CHARS: usize = 80;
+
+pub fn notify(summary: &str, message: &str) -> Result<(), Error> {
     Notification::new()
-        .summary("Received image from copy-sync")
+        .summary(summary)
         .body(message)
         .show()
-        .unwrap();
+        .map(|_| ())
+}
+
+/// First line of `text`, cut to [`PREVIEW_CHARS`] characters.
+pub fn preview(text: &str) -> String {
+    let line = text.lines().next().unwrap_or_default();
+    let mut preview: String = line.chars().take(PREVIEW_CHARS).collect();
+    if preview.len() < text.len() {
+        preview.push('…');
+    }
+    preview
 }
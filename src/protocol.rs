@@ -0,0 +1,585 @@
+//! The wire format shared by the client and the server. Everything but the
+//! frame helpers at the end builds for `wasm32`, for browser clients.
+
+#[cfg(not(target_arch = "wasm32"))]
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::ValueEnum;
+#[cfg(not(target_arch = "wasm32"))]
+use futures_channel::mpsc::UnboundedSender;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tungstenite::Message;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::compact;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::crypto::Signer;
+
+/// Bumped whenever a peer on the previous version could misread the new frames.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Bumped when peers on the previous major can't be talked to at all, which
+/// the handshake then refuses. [`PROTOCOL_VERSION`] counts the changes within
+/// a major, which peers get along despite.
+pub const PROTOCOL_MAJOR: u32 = 1;
+
+/// Peers that predate majors all speak the first.
+fn first_major() -> u32 {
+    1
+}
+
+/// Whether a peer speaking `major` is understood.
+pub fn is_compatible(major: u32) -> bool {
+    major == PROTOCOL_MAJOR
+}
+
+/// First frame a client sends once connected.
+#[derive(Serialize, Deserialize)]
+pub struct Handshake {
+    /// missing from clients that predate versioning, read as 0
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default = "first_major")]
+    pub major: u32,
+    pub token: Option<String>,
+    /// room to join, for transports that have no URL to carry it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// label for this machine, passed on to peers with everything it sends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// absent from clients that predate roles, read as [`Role::Peer`]
+    #[serde(default)]
+    pub role: Role,
+    /// how the client would like the frames after the handshake encoded
+    #[serde(default, skip_serializing_if = "WireFormat::is_json")]
+    pub wire_format: WireFormat,
+}
+
+/// How a connection's text frames travel once the handshake is done, which
+/// only matters to its two ends.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// JSON text frames, what every peer understands
+    #[default]
+    Json,
+    /// the same JSON in a smaller binary form, see `compact`
+    Compact,
+}
+
+impl WireFormat {
+    fn is_json(&self) -> bool {
+        *self == WireFormat::Json
+    }
+}
+
+/// Which way a client syncs, held to by the server.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// only sends, nothing is delivered to it
+    Source,
+    /// only receives, nothing it sends is relayed
+    Sink,
+    #[default]
+    Peer,
+}
+
+/// The server's answer to an accepted [`Handshake`].
+#[derive(Serialize, Deserialize)]
+pub struct HandshakeAck {
+    pub version: u32,
+    #[serde(default = "first_major")]
+    pub major: u32,
+    /// whether the server answers frames carrying a `seq` with an [`Ack`],
+    /// false from servers that predate it
+    #[serde(default)]
+    pub acks: bool,
+    /// the [`Handshake::wire_format`] the server agreed to, JSON from
+    /// servers that predate it
+    #[serde(default)]
+    pub wire_format: WireFormat,
+}
+
+/// The server's receipt for a frame that carried a `seq`, sent back to its
+/// sender only.
+#[derive(Serialize, Deserialize)]
+pub struct Ack {
+    pub ack: u64,
+}
+
+/// Compares tokens without short-circuiting on the first differing byte.
+pub fn token_matches(expected: &str, actual: &str) -> bool {
+    let expected = expected.as_bytes();
+    let actual = actual.as_bytes();
+    if expected.len() != actual.len() {
+        return false;
+    }
+    expected
+        .iter()
+        .zip(actual)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+const HEADER_LEN_BYTES: usize = 4;
+
+/// Packs a JSON header and a binary body into a single frame:
+/// a big-endian `u32` header length, the header, then the body.
+pub fn pack_frame<H: Serialize>(header: &H, body: &[u8]) -> Vec<u8> {
+    let header = serde_json::to_vec(header).unwrap();
+    let mut frame = Vec::with_capacity(HEADER_LEN_BYTES + header.len() + body.len());
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(body);
+    frame
+}
+
+pub fn unpack_frame<H: DeserializeOwned>(frame: &[u8]) -> Option<(H, &[u8])> {
+    let len_bytes = frame.get(..HEADER_LEN_BYTES)?.try_into().ok()?;
+    let header_len = u32::from_be_bytes(len_bytes) as usize;
+    let rest = &frame[HEADER_LEN_BYTES..];
+    if rest.len() < header_len {
+        return None;
+    }
+    let (header, body) = rest.split_at(header_len);
+    let header = serde_json::from_slice(header).ok()?;
+    Some((header, body))
+}
+
+/// How the RGBA pixels of an image frame are compressed.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageCodec {
+    /// the raw pixels, compressed with `--compress`
+    // called zlib before `--compress` existed, which is still its wire name
+    #[default]
+    #[serde(rename = "zlib", alias = "raw")]
+    #[value(alias = "zlib")]
+    Raw,
+    /// PNG, much smaller for screenshots
+    Png,
+}
+
+/// General-purpose compression for raw pixels and file contents.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// what every version understands
+    #[default]
+    Zlib,
+    /// faster and smaller than zlib
+    Zstd,
+    /// send the bytes as they are
+    None,
+}
+
+/// How the bytes of a [`ClipboardMessageRawText`] are encoded, as far as the
+/// sender could tell.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextEncoding {
+    Utf16Le,
+    Utf16Be,
+    /// no byte order mark, most likely a legacy 8-bit code page
+    Unknown,
+}
+
+impl TextEncoding {
+    /// Tells UTF-16 apart by its byte order mark.
+    pub fn detect(bytes: &[u8]) -> TextEncoding {
+        match bytes {
+            [0xff, 0xfe, ..] => TextEncoding::Utf16Le,
+            [0xfe, 0xff, ..] => TextEncoding::Utf16Be,
+            _ => TextEncoding::Unknown,
+        }
+    }
+
+    /// A lossy UTF-8 rendering, for clipboards that only hold UTF-8.
+    pub fn decode(self, bytes: &[u8]) -> String {
+        let units = |from: fn([u8; 2]) -> u16| -> Vec<u16> {
+            bytes
+                .get(2..)
+                .unwrap_or_default()
+                .chunks_exact(2)
+                .map(|pair| from([pair[0], pair[1]]))
+                .collect()
+        };
+        match self {
+            TextEncoding::Utf16Le => String::from_utf16_lossy(&units(u16::from_le_bytes)),
+            TextEncoding::Utf16Be => String::from_utf16_lossy(&units(u16::from_be_bytes)),
+            TextEncoding::Unknown => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+}
+
+/// Header of a binary image frame, the compressed pixels follow it. The
+/// pixels are straight-alpha RGBA, row by row, whatever the sending platform.
+///
+/// Every frame carries its own dimensions, so images from different peers
+/// can interleave without picking up each other's size. The header stays
+/// readable by the server even when the pixels are encrypted.
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageImage {
+    pub width: usize,
+    pub height: usize,
+    /// absent from peers that predate `--image-codec`, which always send raw pixels
+    #[serde(default)]
+    pub codec: ImageCodec,
+    /// how raw pixels are compressed, absent from peers that predate `--compress`
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<u64>,
+    /// see [`ClipboardMessage::name`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// see [`ClipboardMessage::timestamp`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// set when the image is split across several frames
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<ClipboardMessageChunk>,
+    /// see [`ClipboardMessage::seq`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+/// Position of a frame within an image split across several. The receiver
+/// joins the bodies of all `total` frames in `index` order.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct ClipboardMessageChunk {
+    /// unique per sender, tells apart the chunks of overlapping images
+    pub id: u64,
+    pub index: usize,
+    pub total: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageText {
+    pub content: String,
+}
+
+/// Text that isn't valid UTF-8, sent as it is so a receiver that can hold
+/// the bytes gets them unchanged.
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageRawText {
+    /// base64 encoded bytes
+    pub data: String,
+    pub encoding: TextEncoding,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageHtml {
+    pub html: String,
+    /// plain text fallback for apps that can't paste HTML
+    pub alt_text: Option<String>,
+}
+
+/// Rich text as copied from word processors, kept apart from HTML since
+/// they often offer both and paste RTF more faithfully.
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageRtf {
+    pub rtf: String,
+    /// plain text fallback for receivers that can't hold RTF
+    pub alt_text: Option<String>,
+}
+
+/// An image and the text the clipboard offered along with it, such as its
+/// file URL, for receivers to pick from. Unlike image frames it isn't
+/// chunked and is sealed whole with `--key`.
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageImageText {
+    pub width: usize,
+    pub height: usize,
+    pub codec: ImageCodec,
+    pub compression: Compression,
+    /// base64 encoded pixels, encoded as in an image frame
+    pub data: String,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageFile {
+    pub name: String,
+    /// compressed, base64 encoded file content
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageFiles {
+    pub files: Vec<ClipboardMessageFile>,
+    /// absent from peers that predate `--compress`, which always use zlib
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessageSealed {
+    /// base64 encoded nonce and ciphertext of another payload
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ClipboardMessagePayload {
+    Text(ClipboardMessageText),
+    RawText(ClipboardMessageRawText),
+    Html(ClipboardMessageHtml),
+    Rtf(ClipboardMessageRtf),
+    ImageText(ClipboardMessageImageText),
+    Files(ClipboardMessageFiles),
+    Sealed(ClipboardMessageSealed),
+    /// the sender's clipboard was emptied
+    Clear,
+}
+
+/// The X11 or Wayland selection a text update belongs to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Selection {
+    /// what copy and paste use
+    #[default]
+    Clipboard,
+    /// the text last selected, pasted with the middle button
+    Primary,
+}
+
+/// A text frame, what clients send for everything but images.
+#[derive(Serialize, Deserialize)]
+pub struct ClipboardMessage {
+    pub payload: ClipboardMessagePayload,
+    /// id the server assigned to the sending peer, absent when relayed by older servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<u64>,
+    /// `--name` of the sending peer, added by the server like `peer`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// milliseconds since the Unix epoch when the content was copied, by the
+    /// sender's clock or, with `--server-time`, the server's on receipt.
+    /// Absent from peers that predate it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// numbers the frames of a client with `--retransmit` for the server's
+    /// [`Ack`], which removes it before relaying
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// absent for the clipboard. Peers that predate it put every update on
+    /// the clipboard.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<Selection>,
+}
+
+/// Keys a server stamps into frames it relays or removes from them, so a
+/// signature can't cover them.
+#[cfg(not(target_arch = "wasm32"))]
+const UNSIGNED_KEYS: [&str; 5] = ["peer", "name", "timestamp", "seq", "signature"];
+
+/// What the signature of a frame covers: its JSON, or the JSON header of a
+/// binary frame, without [`UNSIGNED_KEYS`] and with sorted keys, followed by
+/// the body. `None` for a frame that isn't a JSON object.
+#[cfg(not(target_arch = "wasm32"))]
+fn signed_bytes(msg: &Message) -> Option<(serde_json::Map<String, serde_json::Value>, Vec<u8>)> {
+    let (value, body) = match msg {
+        Message::Text(text) => (serde_json::from_str(text).ok()?, &[][..]),
+        Message::Binary(binary) => unpack_frame::<serde_json::Value>(binary)?,
+        _ => return None,
+    };
+    let serde_json::Value::Object(object) = value else {
+        return None;
+    };
+    let mut covered = object.clone();
+    for key in UNSIGNED_KEYS {
+        covered.remove(key);
+    }
+    let mut bytes = serde_json::to_vec(&covered).unwrap();
+    bytes.extend_from_slice(body);
+    Some((object, bytes))
+}
+
+/// What a frame carries however it was sent: the bytes its signature
+/// covers, without the fields that differ between two sends of the same
+/// content.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn content_bytes(msg: &Message) -> Option<Vec<u8>> {
+    signed_bytes(msg).map(|(_, bytes)| bytes)
+}
+
+/// Adds a `signature` to a text frame or to the header of a binary frame,
+/// leaving frames it can't parse untouched.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn sign_frame(msg: Message, signer: &Signer) -> Message {
+    let Some((mut object, bytes)) = signed_bytes(&msg) else {
+        return msg;
+    };
+    let signature = BASE64.encode(signer.sign(&bytes));
+    object.insert("signature".to_string(), signature.into());
+    match msg {
+        Message::Binary(binary) => {
+            let (_, body) = unpack_frame::<serde_json::Value>(&binary).unwrap();
+            Message::Binary(pack_frame(&object, body))
+        }
+        _ => Message::Text(serde_json::Value::Object(object).to_string()),
+    }
+}
+
+/// Whether the frame carries a valid `signature` under `signer`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn verify_frame(msg: &Message, signer: &Signer) -> bool {
+    let Some((object, bytes)) = signed_bytes(msg) else {
+        return false;
+    };
+    object
+        .get("signature")
+        .and_then(|signature| BASE64.decode(signature.as_str()?).ok())
+        .is_some_and(|signature| signer.verify(&bytes, &signature))
+}
+
+/// Applies `edit` to the JSON of a text frame or to the JSON header of a
+/// binary frame. The frame is only rebuilt when `edit` returns `true`, and
+/// frames it can't parse are left untouched.
+#[cfg(not(target_arch = "wasm32"))]
+fn edit_frame(
+    msg: Message,
+    edit: impl FnOnce(&mut serde_json::Map<String, serde_json::Value>) -> bool,
+) -> Message {
+    match msg {
+        Message::Text(text) => match serde_json::from_str(&text) {
+            Ok(serde_json::Value::Object(mut object)) => {
+                if edit(&mut object) {
+                    Message::Text(serde_json::Value::Object(object).to_string())
+                } else {
+                    Message::Text(text)
+                }
+            }
+            _ => Message::Text(text),
+        },
+        Message::Binary(binary) => {
+            let edited = match unpack_frame(&binary) {
+                Some((serde_json::Value::Object(mut object), body)) => {
+                    edit(&mut object).then(|| pack_frame(&object, body))
+                }
+                _ => None,
+            };
+            Message::Binary(edited.unwrap_or(binary))
+        }
+        msg => msg,
+    }
+}
+
+/// Turns a text frame into `format` for sending, leaving it as it is when
+/// that's JSON or it isn't JSON at all.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn to_wire(msg: Message, format: WireFormat) -> Message {
+    match (msg, format) {
+        (Message::Text(text), WireFormat::Compact) => {
+            match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(value) => Message::Binary(compact::to_vec(&value)),
+                Err(_) => Message::Text(text),
+            }
+        }
+        (msg, _) => msg,
+    }
+}
+
+/// Reverses [`to_wire`] for a frame received, so whatever handles it next
+/// sees the JSON text frame it was made from.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn from_wire(msg: Message) -> Message {
+    match msg {
+        Message::Binary(binary) if compact::is_compact(&binary) => {
+            match compact::from_slice(&binary) {
+                Some(value) => Message::Text(value.to_string()),
+                None => Message::Binary(binary),
+            }
+        }
+        msg => msg,
+    }
+}
+
+/// Numbers a text or binary frame for the server's [`Ack`].
+#[cfg(not(target_arch = "wasm32"))]
+pub fn with_seq(msg: Message, seq: u64) -> Message {
+    edit_frame(msg, |object| {
+        object.insert("seq".to_string(), seq.into());
+        true
+    })
+}
+
+/// Removes the `seq` of a frame, returning it.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn take_seq(msg: Message) -> (Message, Option<u64>) {
+    let mut seq = None;
+    let msg = match msg {
+        // spares parsing the frames of clients that don't number them
+        Message::Text(text) if !text.contains("\"seq\"") => Message::Text(text),
+        msg => edit_frame(msg, |object| {
+            seq = object.remove("seq").and_then(|seq| seq.as_u64());
+            seq.is_some()
+        }),
+    };
+    (msg, seq)
+}
+
+/// Pings the peer behind `sender` three times per `timeout`, so a live peer
+/// always answers before the other side gives up on it. Returns once the
+/// connection's outgoing queue is closed.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn heartbeat(sender: UnboundedSender<Message>, timeout: Duration) {
+    let mut interval = tokio::time::interval(timeout / 3);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if sender.unbounded_send(Message::Ping(Vec::new())).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_handshake_from_before_majors_is_compatible() {
+        let older: Handshake = serde_json::from_str(r#"{"version":3,"token":null}"#).unwrap();
+        assert!(is_compatible(older.major));
+        let older: HandshakeAck = serde_json::from_str(r#"{"version":3}"#).unwrap();
+        assert!(is_compatible(older.major));
+    }
+
+    #[test]
+    fn a_compact_frame_comes_back_as_the_same_json() {
+        let text = r#"{"payload":{"Text":{"content":"copied"}},"timestamp":1}"#;
+        let Message::Binary(compact) =
+            to_wire(Message::Text(text.to_string()), WireFormat::Compact)
+        else {
+            panic!("not sent compact");
+        };
+        assert!(compact.len() < text.len());
+        assert_eq!(
+            from_wire(Message::Binary(compact)),
+            Message::Text(text.to_string())
+        );
+    }
+
+    #[test]
+    fn other_frames_go_as_they_are() {
+        let text = Message::Text("{}".to_string());
+        assert_eq!(to_wire(text.clone(), WireFormat::Json), text);
+        let image = Message::Binary(pack_frame(&serde_json::json!({}), b"pixels"));
+        assert_eq!(to_wire(image.clone(), WireFormat::Compact), image.clone());
+        assert_eq!(from_wire(image.clone()), image);
+        let garbled = Message::Text("{".to_string());
+        assert_eq!(to_wire(garbled.clone(), WireFormat::Compact), garbled);
+    }
+
+    #[test]
+    fn another_major_isnt_compatible() {
+        assert!(is_compatible(PROTOCOL_MAJOR));
+        assert!(!is_compatible(PROTOCOL_MAJOR + 1));
+        assert!(!is_compatible(0));
+    }
+}
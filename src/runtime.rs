@@ -0,0 +1,173 @@
+//! The per-user directory for the PID file and the local sockets, out of
+//! reach of other users on the machine: they can't read, replace or squat
+//! anything in it.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// `copy-sync` in `$XDG_RUNTIME_DIR` where there is one, else a directory
+/// named after this user under the temp dir. [`prepare`] creates it.
+pub fn dir() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("copy-sync"),
+        None => fallback(),
+    }
+}
+
+#[cfg(unix)]
+fn fallback() -> PathBuf {
+    std::env::temp_dir().join(format!("copy-sync-{}", unsafe { libc::getuid() }))
+}
+
+#[cfg(windows)]
+fn fallback() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("copy-sync")
+}
+
+/// Makes sure the directory of `path` is there before something is created
+/// in it. [`dir`] is created private to this user, and checked to still be
+/// since anyone could have made it first under the temp dir. A directory
+/// given on the command line is left as it is.
+pub fn prepare(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if parent == dir() => create_private(parent),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn create_private(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    // not followed if it's a link, wherever that points
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir()
+        || metadata.uid() != unsafe { libc::getuid() }
+        || metadata.mode() & 0o077 != 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} isn't a directory only this user can use", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Local app data is the user's own already.
+#[cfg(windows)]
+fn create_private(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+/// Listens on a Unix socket at `path` that only this user can connect to.
+/// A socket a client that didn't exit cleanly left there is replaced, but
+/// not one a running client still answers on, nor anything that isn't a
+/// socket.
+#[cfg(unix)]
+pub fn bind(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    prepare(path)?;
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another client is listening on it",
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "something other than a socket is in the way",
+            ))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Whether the other end of `stream` runs as this user, which a socket
+/// given on the command line may not be guarded enough to ensure.
+#[cfg(unix)]
+pub fn is_own(stream: &tokio::net::UnixStream) -> bool {
+    stream
+        .peer_cred()
+        .is_ok_and(|cred| cred.uid() == unsafe { libc::getuid() })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn binds_a_socket_only_this_user_can_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+        let listener = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        assert!(is_own(&server));
+        drop(client);
+        // still answered on, so not taken over
+        assert!(bind(&path).is_err());
+        drop(listener);
+        bind(&path).unwrap();
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+        fs::write(&path, "keep").unwrap();
+        assert!(bind(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep");
+    }
+
+    #[test]
+    fn creates_a_private_dir() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("copy-sync");
+        create_private(&dir).unwrap();
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        // and accepts it the next time
+        create_private(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_dir_others_can_use() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("copy-sync");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(create_private(&dir).is_err());
+    }
+
+    #[test]
+    fn rejects_a_link() {
+        let parent = tempfile::tempdir().unwrap();
+        let target = parent.path().join("elsewhere");
+        create_private(&target).unwrap();
+        let dir = parent.path().join("copy-sync");
+        std::os::unix::fs::symlink(&target, &dir).unwrap();
+        assert!(create_private(&dir).is_err());
+    }
+}
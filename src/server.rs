@@ -1,67 +1,1619 @@
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use futures_channel::mpsc::UnboundedSender;
-use futures_util::{StreamExt, TryStreamExt};
-use tokio::net::TcpStream;
-use tokio_tungstenite::accept_async_with_config;
+use clap::ValueEnum;
+use futures_channel::mpsc::{Sender, UnboundedReceiver, UnboundedSender};
+use futures_channel::oneshot;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Deserializer};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, Instant};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tracing::{debug, info, warn};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig};
 use tungstenite::Message;
 
-use crate::config::WEB_SOCKET_CONFIG;
+use crate::config::{
+    web_socket_config, DEFAULT_BIND, DEFAULT_HEARTBEAT_TIMEOUT_IN_SECONDS, DEFAULT_PEER_QUEUE_SIZE,
+    DEFAULT_PORT, HANDSHAKE_TIMEOUT_IN_SECONDS, MAX_MESSAGE_SIZE_IN_BYTES, MAX_QUOTA_VIOLATIONS,
+    MAX_REPLAY_BYTES, MAX_ROOMS, PEER_DUMP_INTERVAL_IN_SECONDS, ROOM_EXPIRY_IN_SECONDS,
+    SHUTDOWN_TIMEOUT_IN_SECONDS,
+};
+use crate::crypto::Signer;
+use crate::discovery;
+use crate::longpoll::{self, Rewind, Sessions};
+use crate::metrics;
+use crate::protocol::{
+    content_bytes, heartbeat, is_compatible, pack_frame, take_seq, token_matches, unpack_frame,
+    verify_frame, Ack, Handshake, HandshakeAck, Role, PROTOCOL_MAJOR, PROTOCOL_VERSION,
+};
+use crate::service;
+use crate::shutdown::{Handle, Shutdown};
+use crate::tls::load_acceptor;
+use crate::transport::{framed, with_wire_format, FrameSink, FrameStream, Transport};
+
+/// Which addresses the server listens on.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    /// IPv4 only, 0.0.0.0 unless --bind says otherwise
+    #[default]
+    V4,
+    /// IPv6 only, [::] unless --bind says otherwise
+    V6,
+    /// [::] accepting IPv4 as well, where the OS allows it
+    Dual,
+}
+
+impl IpVersion {
+    /// The address that listens on every interface.
+    pub fn unspecified(self) -> IpAddr {
+        match self {
+            IpVersion::V4 => DEFAULT_BIND,
+            IpVersion::V6 | IpVersion::Dual => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    }
+}
+
+/// A block of addresses for `--allow` and `--deny`, e.g. `10.0.0.0/8`. A bare
+/// address stands for itself alone.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network: IpAddr = network
+            .parse()
+            .map_err(|_| format!("invalid address {}", network))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => match prefix.parse() {
+                Ok(prefix) if prefix <= bits => prefix,
+                _ => return Err(format!("prefix must be between 0 and {}", bits)),
+            },
+        };
+        // compared the way `contains` sees clients, as IPv4
+        match network {
+            IpAddr::V6(v6) if prefix >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => Ok(Cidr {
+                    network: IpAddr::V4(v4),
+                    prefix: prefix - 96,
+                }),
+                None => Ok(Cidr { network, prefix }),
+            },
+            _ => Ok(Cidr { network, prefix }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // a dual-stack listener sees IPv4 clients as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `--allow` and `--deny` let `ip` connect. A denied address stays
+/// out even when an allowed block contains it, and without `--allow` every
+/// address not denied may connect.
+fn admitted(ip: IpAddr, options: &ServerOptions) -> bool {
+    !options.deny.iter().any(|cidr| cidr.contains(ip))
+        && (options.allow.is_empty() || options.allow.iter().any(|cidr| cidr.contains(ip)))
+}
+
+pub struct ServerOptions {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub token: Option<String>,
+    pub heartbeat_timeout: u64,
+    pub metrics_port: Option<u16>,
+    pub max_clients: Option<usize>,
+    /// only accept connections from these addresses
+    pub allow: Vec<Cidr>,
+    /// never accept connections from these addresses, whatever `allow` says
+    pub deny: Vec<Cidr>,
+    /// frames per second a peer may send, images counting once however many chunks they take
+    pub per_peer_rate: Option<u64>,
+    /// bytes per second a peer may send
+    pub per_peer_bytes: Option<u64>,
+    /// frames relayed to a peer that may wait to be written before it is
+    /// disconnected as too slow
+    pub peer_queue_size: usize,
+    pub verbose: bool,
+    pub transport: Transport,
+    pub server_time: bool,
+    /// whether an IPv6 address also accepts IPv4
+    pub ip_version: IpVersion,
+    /// don't relay a frame identical to the one before it in the room
+    pub dedup: bool,
+    /// answer mDNS queries so `connect --auto` finds the server
+    pub advertise: bool,
+    pub web_socket: WebSocketConfig,
+    /// relay only frames signed with this key
+    pub sign_key: Option<String>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            cert: None,
+            key: None,
+            token: None,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT_IN_SECONDS,
+            metrics_port: None,
+            max_clients: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            per_peer_rate: None,
+            per_peer_bytes: None,
+            peer_queue_size: DEFAULT_PEER_QUEUE_SIZE as usize,
+            verbose: false,
+            transport: Transport::default(),
+            server_time: false,
+            ip_version: IpVersion::default(),
+            dedup: false,
+            advertise: false,
+            web_socket: web_socket_config(None, None, None, false),
+            sign_key: None,
+        }
+    }
+}
+
+/// Embeds the server in another program:
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// let server = copy_sync::Server::builder().token("secret").spawn().await?;
+/// // ...
+/// server.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            addr: SocketAddr::new(DEFAULT_BIND, DEFAULT_PORT),
+            options: ServerOptions::default(),
+        }
+    }
+}
+
+pub struct ServerBuilder {
+    addr: SocketAddr,
+    options: ServerOptions,
+}
+
+impl ServerBuilder {
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.options.token = Some(token.into());
+        self
+    }
+
+    /// Serves `wss://` with the PEM certificate chain and private key.
+    pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.options.cert = Some(cert.into());
+        self.options.key = Some(key.into());
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.options.transport = transport;
+        self
+    }
+
+    /// Replaces everything set so far except the address.
+    pub fn options(mut self, options: ServerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Binds the address and serves on the current runtime until the
+    /// returned handle is shut down.
+    pub async fn spawn(self) -> io::Result<Handle> {
+        let acceptor = acceptor(&self.options)?;
+        let listener = bind(self.addr, self.options.ip_version)?;
+        Ok(Handle::spawn(move |shutdown| {
+            serve(listener, acceptor, self.options, shutdown)
+        }))
+    }
+}
 
 type UnboundedMessage = UnboundedSender<Message>;
 
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, UnboundedMessage>>>;
+type Room = String;
 
-pub async fn handle_connection(map: PeerMap, raw_stream: TcpStream, addr: SocketAddr) {
-    let ws = accept_async_with_config(raw_stream, Some(WEB_SOCKET_CONFIG))
-        .await
-        .expect("whoops");
+pub struct Peer {
+    /// acks, pings and the close frame, which the server makes itself
+    control: UnboundedMessage,
+    /// frames relayed from other peers, up to `--peer-queue-size` of them
+    frames: Sender<Message>,
+    /// dropped with the peer, which ends a connection still running
+    _left: oneshot::Sender<()>,
+    id: u64,
+    connected_at: Instant,
+    bytes_sent: u64,
+    bytes_received: u64,
+    quota: Quota,
+    role: Role,
+}
 
-    let (tx, rx) = futures_channel::mpsc::unbounded();
+/// Token bucket refilled at `rate` per second, up to a second's worth.
+struct Bucket {
+    tokens: f64,
+    rate: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            tokens: rate as f64,
+            rate: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Whether `amount` is available. More than a second's worth goes through
+    /// on a full bucket and leaves it in debt, so big images aren't refused
+    /// outright.
+    fn admits(&mut self, amount: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        self.tokens >= amount.min(self.rate)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+/// `--per-peer-rate` and `--per-peer-bytes` for one peer.
+struct Quota {
+    frames: Option<Bucket>,
+    bytes: Option<Bucket>,
+    /// frames dropped in a row
+    violations: u32,
+}
+
+enum Admission {
+    Accept,
+    Drop,
+    Disconnect,
+}
+
+impl Quota {
+    fn new(options: &ServerOptions) -> Quota {
+        Quota {
+            frames: options.per_peer_rate.map(Bucket::new),
+            bytes: options.per_peer_bytes.map(Bucket::new),
+            violations: 0,
+        }
+    }
+
+    fn admit(&mut self, msg: &Message) -> Admission {
+        // an image counts as one frame however many chunks it takes
+        let frames = if continues_image(msg) { 0.0 } else { 1.0 };
+        let bytes = msg.len() as f64;
+        let admitted = self
+            .frames
+            .as_mut()
+            .is_none_or(|bucket| bucket.admits(frames))
+            && self
+                .bytes
+                .as_mut()
+                .is_none_or(|bucket| bucket.admits(bytes));
+        if admitted {
+            self.frames
+                .iter_mut()
+                .for_each(|bucket| bucket.take(frames));
+            self.bytes.iter_mut().for_each(|bucket| bucket.take(bytes));
+            self.violations = 0;
+            return Admission::Accept;
+        }
+        self.violations += 1;
+        if self.violations >= MAX_QUOTA_VIOLATIONS {
+            Admission::Disconnect
+        } else {
+            Admission::Drop
+        }
+    }
+}
 
-    map.lock().unwrap().insert(addr, tx);
+/// What a peer sent over its connection, logged when it leaves. Frames are
+/// counted as they arrive, whether or not they are relayed.
+#[derive(Default)]
+struct Sent {
+    texts: u64,
+    images: u64,
+    /// HTML, RTF, files, clears, and encrypted items of any kind
+    other: u64,
+    bytes: u64,
+}
+
+impl Sent {
+    fn count(&mut self, msg: &Message) {
+        self.bytes += msg.len() as u64;
+        let counter = match msg {
+            // chunks after the first belong to an image already counted
+            Message::Binary(_) if continues_image(msg) => return,
+            Message::Binary(_) => &mut self.images,
+            Message::Text(text) => match payload_kind(text).as_deref() {
+                Some("Text" | "RawText") => &mut self.texts,
+                Some("ImageText") => &mut self.images,
+                _ => &mut self.other,
+            },
+            _ => return,
+        };
+        *counter += 1;
+    }
+}
 
-    let (outgoing, incoming) = ws.split();
+/// The `ClipboardMessagePayload` variant of a text frame.
+fn payload_kind(text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    match value.get("payload")? {
+        serde_json::Value::Object(payload) => payload.keys().next().cloned(),
+        serde_json::Value::String(unit) => Some(unit.clone()),
+        _ => None,
+    }
+}
 
-    let broadcast_incoming = incoming.try_for_each(|msg| {
-        match msg {
-            Message::Close(_) => {}
-            _ => {
-                let peers = map.lock().unwrap();
+#[derive(Default)]
+pub struct RoomState {
+    peers: HashMap<SocketAddr, Peer>,
+    // replayed to peers that join later, all frames of it if it came in chunks
+    last: Vec<Message>,
+    // bytes in `last`
+    last_bytes: usize,
+    // hash of the last frame as a peer sent it, for --dedup
+    last_hash: Option<u64>,
+    // when the last peer left, `None` while any is there
+    emptied: Option<Instant>,
+}
 
-                let broadcast_recipients = peers
-                    .iter()
-                    .filter(|(peer_addr, _)| peer_addr != &&addr)
-                    .map(|(_, ws_sink)| ws_sink);
+impl RoomState {
+    fn forget_last(&mut self) {
+        self.last.clear();
+        self.last_bytes = 0;
+    }
 
-                for rec in broadcast_recipients {
-                    rec.unbounded_send(msg.clone()).unwrap();
+    fn remember(&mut self, msg: &Message) {
+        if !continues_image(msg) {
+            self.forget_last();
+        }
+        self.last.push(msg.clone());
+        self.last_bytes += msg.len();
+    }
+}
+
+type PeerMap = Arc<Mutex<HashMap<Room, RoomState>>>;
+
+/// The empty room that has been empty the longest.
+fn longest_empty(rooms: &HashMap<Room, RoomState>) -> Option<Room> {
+    rooms
+        .iter()
+        .filter_map(|(room, state)| Some((room, state.emptied?)))
+        .min_by_key(|(_, emptied)| *emptied)
+        .map(|(room, _)| room.clone())
+}
+
+/// Makes sure `room` is there to join with at most `max_rooms` rooms
+/// around, dropping the room that has been empty the longest to make space.
+/// `false` when every room has peers.
+fn make_room_for(rooms: &mut HashMap<Room, RoomState>, room: &str, max_rooms: usize) -> bool {
+    if !rooms.contains_key(room) && rooms.len() >= max_rooms {
+        let Some(empty) = longest_empty(rooms) else {
+            return false;
+        };
+        debug!("Too many rooms, forgetting empty room {}", empty);
+        rooms.remove(&empty);
+    }
+    rooms.entry(room.to_string()).or_default().emptied = None;
+    true
+}
+
+/// Keeps the last updates of all rooms within `max_bytes`, forgetting
+/// those of the rooms empty the longest first, and what `room` just got
+/// if that isn't enough.
+fn limit_replays(rooms: &mut HashMap<Room, RoomState>, room: &str, max_bytes: usize) {
+    let mut total: usize = rooms.values().map(|state| state.last_bytes).sum();
+    while total > max_bytes {
+        let Some(empty) = longest_empty(rooms) else {
+            break;
+        };
+        if let Some(state) = rooms.remove(&empty) {
+            total -= state.last_bytes;
+        }
+    }
+    if total > max_bytes {
+        if let Some(state) = rooms.get_mut(room) {
+            debug!("Last update of room {} too large to keep for replay", room);
+            state.forget_last();
+        }
+    }
+}
+
+/// Drops the rooms that have been empty for longer than `expiry`.
+fn expire_rooms(rooms: &mut HashMap<Room, RoomState>, expiry: Duration) {
+    rooms.retain(|room, state| {
+        let expired = state
+            .emptied
+            .is_some_and(|emptied| emptied.elapsed() > expiry);
+        if expired {
+            debug!(
+                "Nobody joined room {} for {:?}, forgetting it",
+                room, expiry
+            );
+        }
+        !expired
+    });
+}
+
+/// Runs [`expire_rooms`] every so often.
+async fn expire_rooms_periodically(map: PeerMap) {
+    let expiry = Duration::from_secs(ROOM_EXPIRY_IN_SECONDS);
+    let mut interval = tokio::time::interval(expiry / 60);
+    loop {
+        interval.tick().await;
+        expire_rooms(&mut map.lock().unwrap(), expiry);
+    }
+}
+
+/// Removes a peer from its room when dropped, so its sender doesn't linger in
+/// the map however the connection ends, panics included.
+struct Joined {
+    map: PeerMap,
+    room: Room,
+    addr: SocketAddr,
+}
+
+impl Drop for Joined {
+    fn drop(&mut self) {
+        // a panic elsewhere may have poisoned the lock, the map is still usable
+        let mut rooms = self.map.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(state) = rooms.get_mut(&self.room) {
+            state.peers.remove(&self.addr);
+            if state.peers.is_empty() {
+                if state.last.is_empty() {
+                    rooms.remove(&self.room);
+                } else {
+                    state.emptied = Some(Instant::now());
                 }
             }
         }
+        info!("Peer left: {} (room {})", self.addr, self.room);
+    }
+}
 
-        futures_util::future::ok(())
+const DEFAULT_ROOM: &str = "default";
+
+fn room_from_query(query: Option<&str>) -> Room {
+    query
+        .and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "room")
+                .map(|(_, room)| room.into_owned())
+        })
+        .filter(|room| !room.is_empty())
+        .unwrap_or_else(|| DEFAULT_ROOM.to_string())
+}
+
+static NEXT_PEER_ID: AtomicU64 = AtomicU64::new(1);
+
+fn with_peer(
+    value: serde_json::Value,
+    peer_id: u64,
+    name: Option<&str>,
+    received: Option<u64>,
+) -> Option<serde_json::Value> {
+    let serde_json::Value::Object(mut object) = value else {
+        return None;
+    };
+    object.insert("peer".to_string(), peer_id.into());
+    if let Some(name) = name {
+        object.insert("name".to_string(), name.into());
+    }
+    if let Some(received) = received {
+        object.insert("timestamp".to_string(), received.into());
+    }
+    Some(serde_json::Value::Object(object))
+}
+
+/// Hash of the content of a text or binary frame, the same for two copies
+/// of it however far apart they were sent: see [`content_bytes`]. `None`
+/// for a chunk of an image, which only means something with the others.
+fn frame_hash(msg: &Message) -> Option<u64> {
+    if let Message::Binary(binary) = msg {
+        let chunked = unpack_frame::<serde_json::Value>(binary)
+            .is_some_and(|(header, _)| header.get("chunk").is_some_and(|chunk| !chunk.is_null()));
+        if chunked {
+            return None;
+        }
+    }
+    let mut hasher = DefaultHasher::new();
+    msg.is_text().hash(&mut hasher);
+    match content_bytes(msg) {
+        Some(content) => content.hash(&mut hasher),
+        // not JSON, only byte-identical frames are the same
+        None => msg.clone().into_data().hash(&mut hasher),
+    }
+    Some(hasher.finish())
+}
+
+/// Stamps the sender's id and name, and with `received` the time the server
+/// got it, into the JSON of a text frame or the JSON header of a binary frame,
+/// leaving frames it can't parse untouched.
+fn tag_peer(msg: Message, peer_id: u64, name: Option<&str>, received: Option<u64>) -> Message {
+    match msg {
+        Message::Text(text) => serde_json::from_str(&text)
+            .ok()
+            .and_then(|value| with_peer(value, peer_id, name, received))
+            .map_or(Message::Text(text), |value| {
+                Message::Text(value.to_string())
+            }),
+        Message::Binary(binary) => unpack_frame(&binary)
+            .and_then(|(header, body)| Some((with_peer(header, peer_id, name, received)?, body)))
+            .map(|(header, body)| pack_frame(&header, body))
+            .map_or(Message::Binary(binary), Message::Binary),
+        msg => msg,
+    }
+}
+
+/// Whether `msg` is a chunk after the first of an image split across frames.
+fn continues_image(msg: &Message) -> bool {
+    let Message::Binary(binary) = msg else {
+        return false;
+    };
+    unpack_frame::<serde_json::Value>(binary)
+        .and_then(|(header, _)| header.get("chunk")?.get("index")?.as_u64())
+        .is_some_and(|index| index > 0)
+}
+
+/// Sends `msg` to every peer except `from` and the sources, and drops the
+/// peers whose receiver is already gone or whose queue is full, which it
+/// returns.
+fn broadcast(
+    peers: &mut HashMap<SocketAddr, Peer>,
+    from: SocketAddr,
+    msg: &Message,
+) -> Vec<SocketAddr> {
+    let mut stale = Vec::new();
+    for (peer_addr, peer) in peers.iter_mut() {
+        if *peer_addr == from || peer.role == Role::Source {
+            continue;
+        }
+        match peer.frames.try_send(msg.clone()) {
+            Ok(()) => peer.bytes_sent += msg.len() as u64,
+            Err(err) if err.is_full() => {
+                warn!("Peer {} isn't keeping up, disconnecting", peer_addr);
+                stale.push(*peer_addr);
+            }
+            Err(_) => stale.push(*peer_addr),
+        }
+    }
+    for peer_addr in &stale {
+        debug!("Dropping stale peer {}", peer_addr);
+        peers.remove(peer_addr);
+    }
+    stale
+}
+
+/// The handshake in `message`, or the close frame that turns the peer away:
+/// `unauthorized` for a missing or wrong token, which clients give up on.
+fn authorize(
+    options: &ServerOptions,
+    message: Option<Message>,
+) -> Result<Handshake, CloseFrame<'static>> {
+    let handshake = match message {
+        Some(Message::Text(text)) => serde_json::from_str::<Handshake>(&text).ok(),
+        _ => None,
+    };
+    let Some(handshake) = handshake else {
+        return Err(CloseFrame {
+            code: CloseCode::Protocol,
+            reason: "invalid handshake".into(),
+        });
+    };
+    let authorized = match (&options.token, &handshake.token) {
+        (None, _) => true,
+        (Some(expected), Some(token)) => token_matches(expected, token),
+        (Some(_), None) => false,
+    };
+    if !authorized {
+        return Err(CloseFrame {
+            code: CloseCode::Policy,
+            reason: "unauthorized".into(),
+        });
+    }
+    if !is_compatible(handshake.major) {
+        let reason = format!(
+            "the client speaks protocol major {}, this server speaks {}",
+            handshake.major, PROTOCOL_MAJOR
+        );
+        return Err(CloseFrame {
+            code: CloseCode::Unsupported,
+            reason: reason.into(),
+        });
+    }
+    Ok(handshake)
+}
+
+/// The clients `--max-clients` lets in, each counted from the moment it is
+/// let in until its connection ends.
+#[derive(Clone)]
+pub struct Slots {
+    taken: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+/// Holds one of the [`Slots`] until dropped.
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Slots {
+    pub fn new(max: Option<usize>) -> Self {
+        Slots {
+            taken: Arc::default(),
+            max,
+        }
+    }
+
+    /// A slot for `addr`, `None` when all are taken, which it warns about.
+    fn take(&self, addr: SocketAddr) -> Option<Slot> {
+        let taken = self
+            .taken
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |taken| {
+                let free = self.max.is_none_or(|max| taken < max);
+                free.then_some(taken + 1)
+            });
+        match taken {
+            Ok(_) => Some(Slot(self.taken.clone())),
+            Err(taken) => {
+                warn!("Rejected {}: {} clients already connected", addr, taken);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub async fn handle_connection<S>(
+    map: PeerMap,
+    options: Arc<ServerOptions>,
+    sessions: Sessions,
+    slots: Slots,
+    mut raw_stream: S,
+    addr: SocketAddr,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // --max-message-size holds on every transport, not only WebSocket
+    let max_message_size = options
+        .web_socket
+        .max_message_size
+        .unwrap_or(MAX_MESSAGE_SIZE_IN_BYTES);
+    match options.transport {
+        Transport::Ws => {
+            let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT_IN_SECONDS);
+            let Some(head) = longpoll::read_head(&mut raw_stream, timeout).await else {
+                return;
+            };
+            if !longpoll::is_upgrade(&head) {
+                let open = |query: Option<&str>, outgoing, incoming| {
+                    let Some(slot) = slots.take(addr) else {
+                        return false;
+                    };
+                    let room = room_from_query(query);
+                    let peer = serve_peer(
+                        map.clone(),
+                        options.clone(),
+                        slot,
+                        outgoing,
+                        incoming,
+                        room,
+                        addr,
+                    );
+                    tokio::spawn(peer);
+                    true
+                };
+                return longpoll::serve(head, raw_stream, &sessions, max_message_size, open).await;
+            }
+            let Some(slot) = slots.take(addr) else {
+                return;
+            };
+            let mut room = DEFAULT_ROOM.to_string();
+            // the callback signature is fixed by tungstenite
+            #[allow(clippy::result_large_err)]
+            let read_room =
+                |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+                    room = room_from_query(request.uri().query());
+                    Ok(response)
+                };
+            let raw_stream = Rewind::new(head, raw_stream);
+            let ws =
+                match accept_hdr_async_with_config(raw_stream, read_room, Some(options.web_socket))
+                    .await
+                {
+                    Ok(ws) => ws,
+                    Err(err) => {
+                        warn!("WebSocket handshake with {} failed: {:?}", addr, err);
+                        return;
+                    }
+                };
+            let (outgoing, incoming) = ws.split();
+            serve_peer(
+                map,
+                options,
+                slot,
+                Box::pin(outgoing),
+                Box::pin(incoming),
+                room,
+                addr,
+            )
+            .await
+        }
+        Transport::Tcp => {
+            let Some(slot) = slots.take(addr) else {
+                return;
+            };
+            let (outgoing, incoming) = framed(raw_stream, max_message_size);
+            let room = DEFAULT_ROOM.to_string();
+            serve_peer(map, options, slot, outgoing, incoming, room, addr).await
+        }
+    }
+}
+
+/// Runs a connection, whatever carries it, from the handshake to the end,
+/// holding `_slot` all along.
+async fn serve_peer(
+    map: PeerMap,
+    options: Arc<ServerOptions>,
+    _slot: Slot,
+    mut outgoing: FrameSink,
+    mut incoming: FrameStream,
+    mut room: Room,
+    addr: SocketAddr,
+) {
+    let handshake = tokio::time::timeout(
+        Duration::from_secs(HANDSHAKE_TIMEOUT_IN_SECONDS),
+        incoming.next(),
+    )
+    .await
+    .ok()
+    .flatten()
+    .and_then(Result::ok);
+
+    let handshake = match authorize(&options, handshake) {
+        Ok(handshake) => handshake,
+        Err(frame) => {
+            warn!("Rejected {}: {}", addr, frame.reason);
+            let _ = outgoing.send(Message::Close(Some(frame))).await;
+            return;
+        }
+    };
+    if handshake.version != PROTOCOL_VERSION {
+        warn!(
+            "Peer {} speaks protocol version {}, server speaks {}",
+            addr, handshake.version, PROTOCOL_VERSION
+        );
+    }
+    if let Some(joined) = handshake.room.filter(|room| !room.is_empty()) {
+        room = joined;
+    }
+    let name = handshake.name.filter(|name| !name.is_empty());
+    let ack = HandshakeAck {
+        version: PROTOCOL_VERSION,
+        major: PROTOCOL_MAJOR,
+        acks: true,
+        wire_format: handshake.wire_format,
+    };
+    let ack = serde_json::to_string(&ack).unwrap();
+    if outgoing.send(Message::Text(ack)).await.is_err() {
+        return;
+    }
+    let (outgoing, mut incoming) = with_wire_format((outgoing, incoming), handshake.wire_format);
+
+    let (tx, rx) = futures_channel::mpsc::unbounded();
+    // a sender adds a slot of its own to the channel's buffer
+    let (mut frames, frames_rx) =
+        futures_channel::mpsc::channel(options.peer_queue_size.saturating_sub(1));
+    let (left, left_rx) = oneshot::channel();
+
+    let peer_id = NEXT_PEER_ID.fetch_add(1, Ordering::Relaxed);
+    if !make_room_for(&mut map.lock().unwrap(), &room, MAX_ROOMS) {
+        warn!("Rejected {}: {} rooms in use", addr, MAX_ROOMS);
+        let frame = CloseFrame {
+            code: CloseCode::Again,
+            reason: "too many rooms".into(),
+        };
+        let _ = tx.unbounded_send(Message::Close(Some(frame)));
+        drop(tx);
+        let _ = rx.map(Ok).forward(outgoing).await;
+        return;
+    }
+    {
+        let mut rooms = map.lock().unwrap();
+        let state = rooms.entry(room.clone()).or_default();
+        let replay = if handshake.role == Role::Source {
+            &[][..]
+        } else {
+            &state.last[..]
+        };
+        let mut bytes_sent = 0;
+        for last in replay {
+            debug!("Replay {} bytes to {}", last.len(), addr);
+            if frames.try_send(last.clone()).is_err() {
+                warn!("The last frames don't fit in the queue of {}", addr);
+                break;
+            }
+            bytes_sent += last.len() as u64;
+        }
+        let peer = Peer {
+            control: tx.clone(),
+            frames,
+            _left: left,
+            id: peer_id,
+            connected_at: Instant::now(),
+            bytes_sent,
+            bytes_received: 0,
+            quota: Quota::new(&options),
+            role: handshake.role,
+        };
+        state.peers.insert(addr, peer);
+    }
+    let _joined = Joined {
+        map: map.clone(),
+        room: room.clone(),
+        addr,
+    };
+    info!("Peer joined: {} (id {}, room {})", addr, peer_id, room);
+
+    let heartbeat_timeout = Duration::from_secs(options.heartbeat_timeout);
+    let signer = options.sign_key.as_deref().map(Signer::new);
+    let mut sent = Sent::default();
+    let broadcast_incoming = async {
+        loop {
+            let msg = match tokio::time::timeout(heartbeat_timeout, incoming.next()).await {
+                Ok(Some(Ok(Message::Close(frame)))) => {
+                    debug!("Peer {} closed the connection: {:?}", addr, frame);
+                    return;
+                }
+                Ok(Some(Ok(msg))) => msg,
+                Ok(Some(Err(tungstenite::Error::Capacity(err)))) => {
+                    warn!("Peer {} exceeded a limit: {}", addr, err);
+                    return;
+                }
+                Ok(Some(Err(_))) | Ok(None) => return,
+                Err(_) => {
+                    warn!("Peer {} missed its heartbeat", addr);
+                    return;
+                }
+            };
+            if !(msg.is_text() || msg.is_binary()) {
+                continue;
+            }
+            // acknowledged on receipt, whether or not it is relayed: resending
+            // a frame the server dropped wouldn't get it through
+            let (msg, seq) = take_seq(msg);
+            if let Some(seq) = seq {
+                let ack = serde_json::to_string(&Ack { ack: seq }).unwrap();
+                let _ = tx.unbounded_send(Message::Text(ack));
+            }
+            sent.count(&msg);
+            if signer
+                .as_ref()
+                .is_some_and(|signer| !verify_frame(&msg, signer))
+            {
+                warn!("Dropping unsigned frame from {}", addr);
+                continue;
+            }
+            if handshake.role == Role::Sink {
+                debug!("Dropping a frame from {}, which joined as a sink", addr);
+                continue;
+            }
+            let hash = options.dedup.then(|| frame_hash(&msg)).flatten();
+
+            let received = options.server_time.then(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis() as u64)
+            });
+            let msg = tag_peer(msg, peer_id, name.as_deref(), received);
+            let mut rooms = map.lock().unwrap();
+            let Some(state) = rooms.get_mut(&room) else {
+                continue;
+            };
+            if let Some(peer) = state.peers.get_mut(&addr) {
+                match peer.quota.admit(&msg) {
+                    Admission::Accept => {}
+                    Admission::Drop if peer.quota.violations == 1 => {
+                        warn!("Peer {} is over its quota, dropping frames", addr);
+                        continue;
+                    }
+                    Admission::Drop => {
+                        debug!("Dropping a frame over the quota from {}", addr);
+                        continue;
+                    }
+                    Admission::Disconnect => {
+                        warn!(
+                            "Peer {} stayed over its quota for {} frames, disconnecting",
+                            addr, MAX_QUOTA_VIOLATIONS
+                        );
+                        return;
+                    }
+                }
+            }
+            if options.dedup {
+                if hash.is_some() && state.last_hash == hash {
+                    debug!("Dropping duplicate of the last frame from {}", addr);
+                    continue;
+                }
+                state.last_hash = hash;
+            }
+            state.remember(&msg);
+            if let Some(peer) = state.peers.get_mut(&addr) {
+                peer.bytes_received += msg.len() as u64;
+            }
+            let recipients = state
+                .peers
+                .iter()
+                .filter(|(peer_addr, peer)| **peer_addr != addr && peer.role != Role::Source)
+                .count();
+            debug!(
+                "Broadcast {} bytes from {} to {} peers in room {}",
+                msg.len(),
+                addr,
+                recipients,
+                room
+            );
+
+            let stale = broadcast(&mut state.peers, addr, &msg);
+            metrics::record_broadcast(msg.len(), recipients - stale.len());
+            limit_replays(&mut rooms, &room, MAX_REPLAY_BYTES);
+        }
+    };
+
+    let receive_from_others = futures_util::stream::select(rx, frames_rx)
+        .map(Ok)
+        .forward(outgoing);
+
+    tokio::select! {
+        _ = broadcast_incoming => {}
+        _ = receive_from_others => {}
+        _ = left_rx => {}
+        _ = heartbeat(tx.clone(), heartbeat_timeout) => {}
+    }
+    info!(
+        "Peer {} sent {} texts, {} images and {} other items, {} bytes in all",
+        addr, sent.texts, sent.images, sent.other, sent.bytes
+    );
+}
+
+async fn handle_tls_connection(
+    map: PeerMap,
+    options: Arc<ServerOptions>,
+    sessions: Sessions,
+    slots: Slots,
+    acceptor: TlsAcceptor,
+    raw_stream: TcpStream,
+    addr: SocketAddr,
+) {
+    match acceptor.accept(raw_stream).await {
+        Ok(stream) => handle_connection(map, options, sessions, slots, stream, addr).await,
+        Err(err) => warn!("TLS handshake with {} failed: {:?}", addr, err),
+    }
+}
+
+fn dump_peers(map: &PeerMap) {
+    let rooms = map.lock().unwrap();
+    let count: usize = rooms.values().map(|state| state.peers.len()).sum();
+    info!("{} peers connected", count);
+    for (room, state) in rooms.iter() {
+        for (addr, peer) in &state.peers {
+            info!(
+                "  {} (id {}, room {}): connected {}s, {} bytes sent, {} bytes received",
+                addr,
+                peer.id,
+                room,
+                peer.connected_at.elapsed().as_secs(),
+                peer.bytes_sent,
+                peer.bytes_received
+            );
+        }
+    }
+}
+
+/// Yields once per SIGUSR1. Never yields on platforms without it.
+fn peer_dump_requests() -> UnboundedReceiver<()> {
+    let (tx, rx) = futures_channel::mpsc::unbounded();
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut user_signal) = signal(SignalKind::user_defined1()) else {
+            return;
+        };
+        while user_signal.recv().await.is_some() {
+            if tx.unbounded_send(()).is_err() {
+                return;
+            }
+        }
     });
+    #[cfg(not(unix))]
+    drop(tx);
+    rx
+}
+
+/// Logs every connected peer on SIGUSR1, and with `--verbose` also every
+/// [`PEER_DUMP_INTERVAL_IN_SECONDS`].
+async fn dump_peers_on_request(map: PeerMap, verbose: bool) {
+    let mut requests = peer_dump_requests();
+    let mut interval = tokio::time::interval(Duration::from_secs(PEER_DUMP_INTERVAL_IN_SECONDS));
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick(), if verbose => {}
+            Some(()) = requests.next() => {}
+            else => return,
+        }
+        dump_peers(&map);
+    }
+}
+
+fn room_counts(map: &PeerMap) -> Vec<(Room, usize)> {
+    map.lock()
+        .unwrap()
+        .iter()
+        .map(|(room, state)| (room.clone(), state.peers.len()))
+        .collect()
+}
 
-    let receive_from_others = rx.map(Ok).forward(outgoing);
+/// Asks every peer to disconnect, then waits for their connections to wind
+/// down, giving up after [`SHUTDOWN_TIMEOUT_IN_SECONDS`].
+async fn drain(map: &PeerMap) {
+    for state in map.lock().unwrap().values() {
+        for peer in state.peers.values() {
+            let frame = CloseFrame {
+                code: CloseCode::Away,
+                reason: "server shutting down".into(),
+            };
+            let _ = peer.control.unbounded_send(Message::Close(Some(frame)));
+        }
+    }
+    let drained = async {
+        while map
+            .lock()
+            .unwrap()
+            .values()
+            .any(|state| !state.peers.is_empty())
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT_IN_SECONDS);
+    if tokio::time::timeout(timeout, drained).await.is_err() {
+        warn!("Peers still connected after {:?}, exiting anyway", timeout);
+    }
+}
 
-    futures_util::pin_mut!(broadcast_incoming, receive_from_others);
-    futures_util::future::select(broadcast_incoming, receive_from_others).await;
+fn acceptor(options: &ServerOptions) -> io::Result<Option<TlsAcceptor>> {
+    match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => load_acceptor(cert, key).map(Some),
+        _ => Ok(None),
+    }
+}
 
-    map.lock().unwrap().remove(&addr);
+/// Binds `addr` like `TcpListener::bind`, except that an IPv6 address also
+/// accepts IPv4 in dual mode. If the OS refuses, it stays IPv6 only.
+fn bind(addr: SocketAddr, ip_version: IpVersion) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        let only_v6 = ip_version != IpVersion::Dual;
+        if let Err(err) = socket.set_only_v6(only_v6) {
+            warn!("Can't accept IPv4 on {}, IPv6 only: {:?}", addr, err);
+        }
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
 }
 
-pub async fn start(port: u16) {
-    let addr = format!("0.0.0.0:{}", port);
+/// Loads the certificate and binds `addr` as [`start`] would, then lets go of
+/// both, so a server about to go to the background fails where it is seen.
+pub fn check(addr: SocketAddr, options: &ServerOptions) -> io::Result<()> {
+    acceptor(options)?;
+    bind(addr, options.ip_version)?;
+    Ok(())
+}
+
+pub async fn start(addr: SocketAddr, options: ServerOptions, shutdown: Shutdown) -> io::Result<()> {
+    let acceptor = acceptor(&options)?;
+    let listener = bind(addr, options.ip_version)?;
+    service::ready();
+    serve(listener, acceptor, options, shutdown).await;
+    Ok(())
+}
+
+async fn serve(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    options: ServerOptions,
+    mut shutdown: Shutdown,
+) {
+    let addr = listener.local_addr().expect("Failed to create server");
+    let options = Arc::new(options);
     let state = PeerMap::new(Mutex::new(HashMap::new()));
-    let server = tokio::net::TcpListener::bind(addr).await;
-    let listener = server.expect("Failed to create server");
+    let sessions = Sessions::default();
+    let slots = Slots::new(options.max_clients);
+    info!("Listening on {}", addr);
+    // stopped with the server, so an embedding program gets its ports back
+    let mut serving = vec![
+        tokio::spawn(dump_peers_on_request(state.clone(), options.verbose)),
+        tokio::spawn(expire_rooms_periodically(state.clone())),
+    ];
+    if options.advertise {
+        let scheme = match (options.transport, &acceptor) {
+            (Transport::Tcp, _) => "tcp",
+            (Transport::Ws, Some(_)) => "wss",
+            (Transport::Ws, None) => "ws",
+        };
+        serving.push(tokio::spawn(discovery::advertise(addr, scheme)));
+    }
+    if let Some(port) = options.metrics_port {
+        let peers = state.clone();
+        serving.push(tokio::spawn(metrics::serve(
+            SocketAddr::new(addr.ip(), port),
+            move || room_counts(&peers),
+        )));
+    }
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = shutdown.wait() => break,
+        };
+        if !admitted(addr.ip(), &options) {
+            warn!("Rejected {}: not allowed by --allow or --deny", addr);
+            drop(stream);
+            continue;
+        }
+        match &acceptor {
+            Some(acceptor) => {
+                tokio::spawn(handle_tls_connection(
+                    state.clone(),
+                    options.clone(),
+                    sessions.clone(),
+                    slots.clone(),
+                    acceptor.clone(),
+                    stream,
+                    addr,
+                ));
+            }
+            None => {
+                tokio::spawn(handle_connection(
+                    state.clone(),
+                    options.clone(),
+                    sessions.clone(),
+                    slots.clone(),
+                    stream,
+                    addr,
+                ));
+            }
+        }
+    }
+
+    for task in serving {
+        task.abort();
+        let _ = task.await;
+    }
+    drain(&state).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    use super::*;
+    use crate::compact;
+    use crate::protocol::{
+        from_wire, to_wire, ClipboardMessage, ClipboardMessagePayload, ClipboardMessageText,
+        WireFormat,
+    };
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn start(options: ServerOptions) -> (SocketAddr, Handle) {
+        let listener = bind(([127, 0, 0, 1], 0).into(), IpVersion::V4).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = Handle::spawn(move |shutdown| serve(listener, None, options, shutdown));
+        (addr, handle)
+    }
+
+    fn handshake(token: Option<&str>) -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            major: PROTOCOL_MAJOR,
+            token: token.map(str::to_string),
+            room: None,
+            name: None,
+            role: Role::Peer,
+            wire_format: WireFormat::Json,
+        }
+    }
+
+    async fn join(addr: SocketAddr, token: Option<&str>) -> Result<Client, String> {
+        join_with(addr, handshake(token)).await
+    }
+
+    /// Connects and shakes hands, `Err` with the reason the server gave for
+    /// turning the client away.
+    async fn join_with(addr: SocketAddr, handshake: Handshake) -> Result<Client, String> {
+        let (mut ws, _) = connect_async(format!("ws://{}", addr))
+            .await
+            .map_err(|err| err.to_string())?;
+        let handshake = serde_json::to_string(&handshake).unwrap();
+        ws.send(Message::Text(handshake)).await.unwrap();
+        match ws.next().await {
+            Some(Ok(Message::Text(ack))) if serde_json::from_str::<HandshakeAck>(&ack).is_ok() => {
+                Ok(ws)
+            }
+            Some(Ok(Message::Close(frame))) => Err(frame
+                .map(|frame| frame.reason.into_owned())
+                .unwrap_or_default()),
+            other => Err(format!("unexpected answer: {:?}", other)),
+        }
+    }
+
+    /// The next clipboard frame, `None` if none comes in a while.
+    async fn next_frame(ws: &mut Client) -> Option<Message> {
+        let wait = Duration::from_millis(300);
+        loop {
+            match tokio::time::timeout(wait, ws.next()).await.ok()?? {
+                Ok(Message::Ping(_) | Message::Pong(_)) => {}
+                Ok(msg @ (Message::Text(_) | Message::Binary(_))) => return Some(msg),
+                _ => return None,
+            }
+        }
+    }
+
+    fn text_frame(content: &str, timestamp: u64) -> Message {
+        let message = ClipboardMessage {
+            payload: ClipboardMessagePayload::Text(ClipboardMessageText {
+                content: content.to_string(),
+            }),
+            peer: None,
+            name: None,
+            timestamp: Some(timestamp),
+            seq: None,
+            selection: None,
+        };
+        Message::Text(serde_json::to_string(&message).unwrap())
+    }
+
+    #[tokio::test]
+    async fn dedup_drops_a_consecutive_duplicate() {
+        let (addr, server) = start(ServerOptions {
+            dedup: true,
+            ..ServerOptions::default()
+        })
+        .await;
+        let mut sender = join(addr, None).await.unwrap();
+        let mut receiver = join(addr, None).await.unwrap();
+        // copied twice, so sent with different timestamps
+        sender.send(text_frame("same", 1)).await.unwrap();
+        sender.send(text_frame("same", 2)).await.unwrap();
+        sender.send(text_frame("other", 3)).await.unwrap();
+        sender.send(text_frame("same", 4)).await.unwrap();
+        let mut relayed = Vec::new();
+        while let Some(frame) = next_frame(&mut receiver).await {
+            let message: ClipboardMessage = serde_json::from_str(frame.to_text().unwrap()).unwrap();
+            relayed.push(message.timestamp);
+        }
+        assert_eq!(relayed, [Some(1), Some(3), Some(4)]);
+        drop((sender, receiver));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn max_clients_turns_away_one_more() {
+        let (addr, server) = start(ServerOptions {
+            max_clients: Some(2),
+            ..ServerOptions::default()
+        })
+        .await;
+        let first = join(addr, None).await.unwrap();
+        // counted before shaking hands, so a burst can't slip past the limit
+        let (second, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        assert!(join(addr, None).await.is_err());
+
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let third = join(addr, None).await.unwrap();
+        drop((second, third));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn the_token_decides_who_joins() {
+        let (addr, server) = start(ServerOptions {
+            token: Some("secret".to_string()),
+            ..ServerOptions::default()
+        })
+        .await;
+        assert_eq!(join(addr, None).await.unwrap_err(), "unauthorized");
+        assert_eq!(join(addr, Some("wrong")).await.unwrap_err(), "unauthorized");
+        let joined = join(addr, Some("secret")).await.unwrap();
+        drop(joined);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn compact_and_json_peers_share_a_room() {
+        let (addr, server) = start(ServerOptions::default()).await;
+        let compact = Handshake {
+            wire_format: WireFormat::Compact,
+            ..handshake(None)
+        };
+        let mut compact = join_with(addr, compact).await.unwrap();
+        let mut json = join(addr, None).await.unwrap();
+
+        compact
+            .send(to_wire(text_frame("from compact", 1), WireFormat::Compact))
+            .await
+            .unwrap();
+        let Some(Message::Text(relayed)) = next_frame(&mut json).await else {
+            panic!("the JSON peer got no text frame");
+        };
+        assert!(relayed.contains("from compact"), "{}", relayed);
+
+        json.send(text_frame("from json", 2)).await.unwrap();
+        let Some(Message::Binary(relayed)) = next_frame(&mut compact).await else {
+            panic!("the compact peer got no binary frame");
+        };
+        assert!(compact::is_compact(&relayed));
+        let Message::Text(relayed) = from_wire(Message::Binary(relayed)) else {
+            panic!("the compact frame didn't decode");
+        };
+        assert!(relayed.contains("from json"), "{}", relayed);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn only_the_same_major_joins() {
+        let (addr, server) = start(ServerOptions::default()).await;
+        let older = Handshake {
+            version: 0,
+            ..handshake(None)
+        };
+        let older = join_with(addr, older).await.unwrap();
+        let newer = Handshake {
+            version: PROTOCOL_VERSION + 1,
+            major: PROTOCOL_MAJOR + 1,
+            ..handshake(None)
+        };
+        let err = join_with(addr, newer).await.unwrap_err();
+        assert!(err.contains("protocol major 2"), "{}", err);
+        drop(older);
+        server.shutdown().await;
+    }
+
+    /// A peer whose queue holds `queue` frames, with the receiving end of the
+    /// queue and what its connection waits on to end.
+    fn peer(
+        queue: usize,
+    ) -> (
+        Peer,
+        futures_channel::mpsc::Receiver<Message>,
+        oneshot::Receiver<()>,
+    ) {
+        let (control, _) = futures_channel::mpsc::unbounded();
+        let (frames, frames_rx) = futures_channel::mpsc::channel(queue.saturating_sub(1));
+        let (left, left_rx) = oneshot::channel();
+        let peer = Peer {
+            control,
+            frames,
+            _left: left,
+            id: 0,
+            connected_at: Instant::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            quota: Quota::new(&ServerOptions::default()),
+            role: Role::Peer,
+        };
+        (peer, frames_rx, left_rx)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn broadcast_reports_a_peer_that_left() {
+        let mut peers = HashMap::new();
+        let (gone, gone_rx, _) = peer(4);
+        drop(gone_rx);
+        let (staying, mut staying_rx, _) = peer(4);
+        peers.insert(addr(1), gone);
+        peers.insert(addr(2), staying);
 
-    while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(state.clone(), stream, addr));
+        let msg = Message::Text("copied".to_string());
+        assert_eq!(broadcast(&mut peers, addr(3), &msg), [addr(1)]);
+        assert!(!peers.contains_key(&addr(1)));
+        assert_eq!(staying_rx.try_next().unwrap(), Some(msg));
+        assert_eq!(peers[&addr(2)].bytes_sent, 6);
+    }
+
+    #[test]
+    fn a_peer_that_stops_reading_is_disconnected() {
+        let mut peers = HashMap::new();
+        let (stalled, _stalled_rx, mut stalled_left) = peer(2);
+        let (reading, mut reading_rx, _) = peer(2);
+        peers.insert(addr(1), stalled);
+        peers.insert(addr(2), reading);
+
+        for n in 0..5 {
+            let msg = Message::Text(n.to_string());
+            let stale = broadcast(&mut peers, addr(3), &msg);
+            // the queue of the stalled peer held the first two
+            assert_eq!(stale, if n == 2 { vec![addr(1)] } else { vec![] });
+            assert_eq!(reading_rx.try_next().unwrap(), Some(msg));
+        }
+        assert!(!peers.contains_key(&addr(1)));
+        // which ends its connection
+        assert!(stalled_left.try_recv().is_err());
+    }
+
+    /// A room nobody is in, emptied `ago`, holding `bytes` to replay.
+    fn empty_room(ago: Duration, bytes: usize) -> RoomState {
+        let mut state = RoomState {
+            emptied: Some(Instant::now() - ago),
+            ..RoomState::default()
+        };
+        state.remember(&Message::Binary(vec![0; bytes]));
+        state
+    }
+
+    #[test]
+    fn a_new_room_replaces_the_longest_empty_one() {
+        let mut rooms = HashMap::new();
+        rooms.insert("old".to_string(), empty_room(Duration::from_secs(2), 1));
+        rooms.insert("recent".to_string(), empty_room(Duration::from_secs(1), 1));
+        rooms.insert("busy".to_string(), RoomState::default());
+        assert!(make_room_for(&mut rooms, "new", 3));
+        let mut left: Vec<_> = rooms.keys().map(String::as_str).collect();
+        left.sort();
+        assert_eq!(left, ["busy", "new", "recent"]);
+    }
+
+    #[test]
+    fn a_new_room_is_refused_when_all_have_peers() {
+        let mut rooms = HashMap::new();
+        rooms.insert("a".to_string(), RoomState::default());
+        rooms.insert("b".to_string(), RoomState::default());
+        assert!(!make_room_for(&mut rooms, "c", 2));
+        assert!(make_room_for(&mut rooms, "a", 2));
+        assert_eq!(rooms.len(), 2);
+    }
+
+    #[test]
+    fn replays_stay_within_the_limit() {
+        let mut rooms = HashMap::new();
+        rooms.insert("old".to_string(), empty_room(Duration::from_secs(2), 40));
+        rooms.insert("recent".to_string(), empty_room(Duration::from_secs(1), 40));
+        let mut busy = RoomState::default();
+        busy.remember(&Message::Binary(vec![0; 40]));
+        rooms.insert("busy".to_string(), busy);
+        limit_replays(&mut rooms, "busy", 100);
+        assert!(!rooms.contains_key("old"));
+        assert_eq!(rooms["recent"].last_bytes, 40);
+        assert_eq!(rooms["busy"].last_bytes, 40);
+
+        // nothing empty left to forget, so the update just sent isn't kept
+        rooms.remove("recent");
+        rooms
+            .get_mut("busy")
+            .unwrap()
+            .remember(&Message::Binary(vec![0; 200]));
+        limit_replays(&mut rooms, "busy", 100);
+        assert!(rooms["busy"].last.is_empty());
+    }
+
+    #[test]
+    fn rooms_empty_for_long_are_forgotten() {
+        let mut rooms = HashMap::new();
+        rooms.insert("old".to_string(), empty_room(Duration::from_secs(2), 1));
+        rooms.insert("recent".to_string(), empty_room(Duration::ZERO, 1));
+        rooms.insert("busy".to_string(), RoomState::default());
+        expire_rooms(&mut rooms, Duration::from_secs(1));
+        let mut left: Vec<_> = rooms.keys().map(String::as_str).collect();
+        left.sort();
+        assert_eq!(left, ["busy", "recent"]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_the_metrics_port() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (_, server) = start(ServerOptions {
+            metrics_port: Some(port),
+            ..ServerOptions::default()
+        })
+        .await;
+        let listening = async {
+            while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), listening)
+            .await
+            .unwrap();
+        server.shutdown().await;
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
     }
 }
@@ -0,0 +1,229 @@
+//! Running under a service manager: systemd units of `Type=notify` on Linux,
+//! the service control manager on Windows. Nothing here does anything unless
+//! [`detect`] found one.
+
+#[cfg(windows)]
+pub use self::scm::{detect, ready, stop_requested, stopped, stopping};
+#[cfg(unix)]
+pub use self::systemd::{detect, ready, stop_requested, stopped, stopping};
+
+#[cfg(unix)]
+mod systemd {
+    use std::{ffi::OsString, os::unix::net::UnixDatagram, sync::OnceLock};
+
+    use tracing::{debug, warn};
+
+    static NOTIFY_SOCKET: OnceLock<OsString> = OnceLock::new();
+
+    /// Whether systemd waits to hear from this process, which it says with
+    /// `NOTIFY_SOCKET`.
+    pub fn detect() -> bool {
+        match std::env::var_os("NOTIFY_SOCKET") {
+            Some(path) if !path.is_empty() => {
+                debug!("started by systemd, notifying {:?}", path);
+                NOTIFY_SOCKET.set(path).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// The `sd_notify` protocol: one datagram of `KEY=value` lines, to a
+    /// path or, starting with `@`, an abstract socket.
+    fn notify(state: &str) {
+        let Some(path) = NOTIFY_SOCKET.get() else {
+            return;
+        };
+        let sent = UnixDatagram::unbound().and_then(|socket| {
+            #[cfg(target_os = "linux")]
+            if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                return socket.send_to_addr(state.as_bytes(), &addr);
+            }
+            socket.send_to(state.as_bytes(), path)
+        });
+        if let Err(err) = sent {
+            warn!("notify systemd of {} error: {:?}", state, err);
+        }
+    }
+
+    pub fn ready() {
+        notify("READY=1");
+    }
+
+    pub fn stopping() {
+        notify("STOPPING=1");
+    }
+
+    /// systemd sees the process exit, there is nothing to tell.
+    pub fn stopped(_ok: bool) {}
+
+    /// systemd stops a unit with SIGTERM, which [`crate::shutdown`] hears.
+    pub async fn stop_requested() {
+        std::future::pending().await
+    }
+}
+
+#[cfg(windows)]
+mod scm {
+    use std::{
+        ffi::c_void,
+        sync::{
+            atomic::{AtomicU32, AtomicUsize, Ordering},
+            mpsc, Mutex, OnceLock,
+        },
+        thread,
+    };
+
+    use tokio::sync::watch;
+    use tracing::{debug, warn};
+    use windows_sys::Win32::Foundation::{
+        ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+    };
+    use windows_sys::Win32::System::Services::{
+        RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+        SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+        SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_RUNNING, SERVICE_START_PENDING,
+        SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+        SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+    };
+
+    /// The handle from `RegisterServiceCtrlHandlerExW`, 0 until then.
+    static STATUS_HANDLE: AtomicUsize = AtomicUsize::new(0);
+    /// Counts the reports of a pending state, so the SCM sees progress.
+    static CHECKPOINT: AtomicU32 = AtomicU32::new(0);
+    /// Tells [`detect`] whether `service_main` was called.
+    static STARTED: OnceLock<Mutex<mpsc::Sender<bool>>> = OnceLock::new();
+    static STOP: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+    fn stop() -> &'static watch::Sender<bool> {
+        STOP.get_or_init(|| watch::channel(false).0)
+    }
+
+    fn started(started: bool) {
+        if let Some(sender) = STARTED.get() {
+            let _ = sender.lock().unwrap().send(started);
+        }
+    }
+
+    /// Whether the SCM started this process. Only a service can connect to
+    /// it, anything else makes `StartServiceCtrlDispatcherW` fail at once.
+    /// The dispatcher keeps its thread until the service reports it stopped.
+    pub fn detect() -> bool {
+        let (sender, receiver) = mpsc::channel();
+        if STARTED.set(Mutex::new(sender)).is_err() {
+            return false;
+        }
+        thread::spawn(|| {
+            // own-process services get any name, the SCM doesn't check it
+            let mut name: Vec<u16> = "copy-sync\0".encode_utf16().collect();
+            let table = [
+                SERVICE_TABLE_ENTRYW {
+                    lpServiceName: name.as_mut_ptr(),
+                    lpServiceProc: Some(service_main),
+                },
+                SERVICE_TABLE_ENTRYW {
+                    lpServiceName: std::ptr::null_mut(),
+                    lpServiceProc: None,
+                },
+            ];
+            if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+                debug!(
+                    "not started as a service: {:?}",
+                    std::io::Error::last_os_error()
+                );
+                started(false);
+            }
+        });
+        receiver.recv().unwrap_or(false)
+    }
+
+    unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+        let name: Vec<u16> = "copy-sync\0".encode_utf16().collect();
+        let handle = unsafe {
+            RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(handler), std::ptr::null())
+        };
+        if handle.is_null() {
+            warn!(
+                "register service control handler error: {:?}",
+                std::io::Error::last_os_error()
+            );
+            started(false);
+            return;
+        }
+        STATUS_HANDLE.store(handle as usize, Ordering::Relaxed);
+        report(SERVICE_START_PENDING, NO_ERROR);
+        started(true);
+    }
+
+    unsafe extern "system" fn handler(
+        control: u32,
+        _event_type: u32,
+        _event_data: *mut c_void,
+        _context: *mut c_void,
+    ) -> u32 {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                report(SERVICE_STOP_PENDING, NO_ERROR);
+                stop().send_replace(true);
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    fn report(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+        let handle = STATUS_HANDLE.load(Ordering::Relaxed);
+        if handle == 0 {
+            return;
+        }
+        let pending = state == SERVICE_START_PENDING || state == SERVICE_STOP_PENDING;
+        let status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: state,
+            dwControlsAccepted: if state == SERVICE_RUNNING {
+                SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+            } else {
+                0
+            },
+            dwWin32ExitCode: exit_code,
+            dwServiceSpecificExitCode: (exit_code == ERROR_SERVICE_SPECIFIC_ERROR) as u32,
+            dwCheckPoint: if pending {
+                CHECKPOINT.fetch_add(1, Ordering::Relaxed) + 1
+            } else {
+                0
+            },
+            dwWaitHint: if pending { 10_000 } else { 0 },
+        };
+        if unsafe { SetServiceStatus(handle as *mut c_void, &status) } == 0 {
+            warn!(
+                "set service status error: {:?}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    pub fn ready() {
+        report(SERVICE_RUNNING, NO_ERROR);
+    }
+
+    pub fn stopping() {
+        report(SERVICE_STOP_PENDING, NO_ERROR);
+    }
+
+    /// Has to come before the process exits, or the SCM records a crash.
+    pub fn stopped(ok: bool) {
+        let exit_code = if ok {
+            NO_ERROR
+        } else {
+            ERROR_SERVICE_SPECIFIC_ERROR
+        };
+        report(SERVICE_STOPPED, exit_code);
+    }
+
+    /// Fires when the SCM asks the service to stop, or Windows shuts down.
+    pub async fn stop_requested() {
+        let _ = stop().subscribe().wait_for(|stop| *stop).await;
+    }
+}
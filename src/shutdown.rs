@@ -0,0 +1,81 @@
+use std::future::Future;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use crate::service;
+
+/// Fires once the process is asked to stop, so loops can close their
+/// connections instead of being killed mid-frame.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Starts listening for SIGINT and SIGTERM (Ctrl-C on Windows), and for
+    /// the service manager asking to stop.
+    pub fn listen() -> Shutdown {
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = signal() => {}
+                _ = service::stop_requested() => {}
+            }
+            info!("Shutting down");
+            service::stopping();
+            let _ = sender.send(true);
+        });
+        Shutdown(receiver)
+    }
+
+    pub async fn wait(&mut self) {
+        let _ = self.0.wait_for(|stop| *stop).await;
+    }
+}
+
+/// A client or server running in the background of an embedding program.
+pub struct Handle {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl Handle {
+    /// Spawns `run` with a [`Shutdown`] that fires on [`Handle::shutdown`]
+    /// instead of on a signal.
+    pub(crate) fn spawn<F, R>(run: F) -> Handle
+    where
+        F: FnOnce(Shutdown) -> R,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        let (stop, receiver) = watch::channel(false);
+        let task = tokio::spawn(run(Shutdown(receiver)));
+        Handle { stop, task }
+    }
+
+    /// Closes the connections as SIGTERM would and waits until they are.
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+    }
+
+    /// Whether it stopped on its own, e.g. because the listener failed.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
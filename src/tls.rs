@@ -0,0 +1,239 @@
+use std::{fs::File, io, io::BufReader, path::Path, sync::Arc, time::SystemTime};
+
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+use tokio_rustls::{
+    rustls::{
+        client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+        Certificate, ClientConfig, Error, OwnedTrustAnchor, PrivateKey, RootCertStore,
+        ServerConfig, ServerName,
+    },
+    TlsAcceptor,
+};
+use tokio_tungstenite::Connector;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+    Err(invalid_data(format!(
+        "no private key found in {}",
+        path.display()
+    )))
+}
+
+pub fn load_acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|err| invalid_data(err.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Trusts exactly the certificate with this SHA-256 fingerprint, after the
+/// usual chain validation when a CA is given as well.
+struct Pinned {
+    fingerprint: [u8; 32],
+    roots: Option<WebPkiVerifier>,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if let Some(roots) = &self.roots {
+            roots.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if Sha256::digest(&end_entity.0)[..] != self.fingerprint {
+            return Err(Error::General(
+                "certificate does not match the pinned fingerprint".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Reads a SHA-256 fingerprint as printed by `openssl x509 -fingerprint
+/// -sha256`, with or without the colons.
+fn parse_fingerprint(fingerprint: &str) -> io::Result<[u8; 32]> {
+    let digits: String = fingerprint
+        .trim()
+        .trim_start_matches("sha256:")
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+    let invalid = || invalid_data(format!("invalid SHA-256 fingerprint {}", fingerprint));
+    if digits.len() != 64 || !digits.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(digits.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// The rustls configuration behind `connector`, or one trusting the web
+/// roots like tungstenite's own, for `wss://` connections made without it.
+pub fn client_config(connector: Option<&Connector>) -> Arc<ClientConfig> {
+    if let Some(Connector::Rustls(config)) = connector {
+        return config.clone();
+    }
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+/// How `wss://` servers are verified when the default web roots won't do:
+/// against the CA in `ca_cert`, against a pinned certificate fingerprint, or
+/// both. `None` keeps the defaults.
+pub fn load_connector(ca_cert: Option<&Path>, pin: Option<&str>) -> io::Result<Option<Connector>> {
+    let roots = match ca_cert {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|err| invalid_data(format!("{}: {}", path.display(), err)))?;
+            }
+            Some(roots)
+        }
+        None => None,
+    };
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = match (roots, pin) {
+        (None, None) => return Ok(None),
+        (Some(roots), None) => builder.with_root_certificates(roots).with_no_client_auth(),
+        (roots, Some(pin)) => builder
+            .with_custom_certificate_verifier(Arc::new(Pinned {
+                fingerprint: parse_fingerprint(pin)?,
+                roots: roots.map(|roots| WebPkiVerifier::new(roots, None)),
+            }))
+            .with_no_client_auth(),
+    };
+    Ok(Some(Connector::Rustls(Arc::new(config))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// `openssl x509 -in testdata/localhost.pem -noout -fingerprint -sha256`
+    const FINGERPRINT: &str = "26:04:37:8D:8C:BE:FA:C3:27:AB:BD:87:0A:65:A3:10:E0:BE:00:FF:23:7F:59:71:70:4F:43:D8:D5:68:D3:E5";
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    fn connector_config(ca_cert: Option<&Path>, pin: Option<&str>) -> Arc<ClientConfig> {
+        match load_connector(ca_cert, pin).unwrap() {
+            Some(Connector::Rustls(config)) => config,
+            _ => panic!("no rustls connector"),
+        }
+    }
+
+    /// Whether a client on `config` accepts a server showing the self-signed
+    /// certificate for localhost in testdata.
+    async fn accepts(config: Arc<ClientConfig>) -> bool {
+        let acceptor =
+            load_acceptor(&testdata("localhost.pem"), &testdata("localhost-key.pem")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(stream).await;
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        TlsConnector::from(config)
+            .connect(name, stream)
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn trusts_the_pinned_certificate() {
+        assert!(accepts(connector_config(None, Some(FINGERPRINT))).await);
+        let bare = FINGERPRINT.replace(':', "").to_lowercase();
+        assert!(accepts(connector_config(None, Some(&bare))).await);
+    }
+
+    #[tokio::test]
+    async fn refuses_another_fingerprint() {
+        let other = "00".repeat(32);
+        assert!(!accepts(connector_config(None, Some(&other))).await);
+        let ca = testdata("localhost.pem");
+        assert!(!accepts(connector_config(Some(&ca), Some(&other))).await);
+    }
+
+    #[tokio::test]
+    async fn a_self_signed_certificate_needs_trusting() {
+        assert!(!accepts(client_config(None)).await);
+        let ca = testdata("localhost.pem");
+        assert!(accepts(connector_config(Some(&ca), None)).await);
+        assert!(accepts(connector_config(Some(&ca), Some(FINGERPRINT))).await);
+    }
+
+    #[test]
+    fn refuses_a_malformed_fingerprint() {
+        assert!(parse_fingerprint("26:04:37").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+        assert!(parse_fingerprint(&"é".repeat(32)).is_err());
+        assert!(load_connector(None, Some("26:04")).is_err());
+    }
+}
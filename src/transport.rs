@@ -0,0 +1,153 @@
+use std::{io, pin::Pin};
+
+use clap::ValueEnum;
+use futures_util::{future, sink, stream, Sink, SinkExt, Stream, TryStreamExt};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tungstenite::error::{CapacityError, ProtocolError};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tungstenite::{Error, Message};
+use url::Url;
+
+use crate::config::DEFAULT_PORT;
+use crate::protocol::{from_wire, to_wire, WireFormat};
+
+/// How frames travel between the server and its clients.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// WebSocket, reachable through HTTP proxies
+    #[default]
+    Ws,
+    /// length-prefixed frames over plain TCP, for peers without a WebSocket stack
+    Tcp,
+}
+
+pub type FrameSink = Pin<Box<dyn Sink<Message, Error = Error> + Send>>;
+
+pub type FrameStream = Pin<Box<dyn Stream<Item = Result<Message, Error>> + Send>>;
+
+/// Sends the text frames of a connection in `format` and reads those that
+/// come in that way back as JSON, so nothing past the handshake has to care.
+pub fn with_wire_format(
+    (sink, stream): (FrameSink, FrameStream),
+    format: WireFormat,
+) -> (FrameSink, FrameStream) {
+    if format == WireFormat::Json {
+        return (sink, stream);
+    }
+    let sink = sink.with(move |msg| future::ready(Ok::<_, Error>(to_wire(msg, format))));
+    let stream = stream.map_ok(from_wire);
+    (Box::pin(sink), Box::pin(stream))
+}
+
+const TEXT: u8 = 1;
+const BINARY: u8 = 2;
+const PING: u8 = 3;
+const PONG: u8 = 4;
+const CLOSE: u8 = 5;
+
+/// `host:port` to dial for a `tcp://` address, `None` for any other scheme.
+pub fn tcp_target(addr: &str) -> Option<String> {
+    let url = Url::parse(addr).ok()?;
+    if url.scheme() != "tcp" {
+        return None;
+    }
+    Some(format!(
+        "{}:{}",
+        url.host_str()?,
+        url.port().unwrap_or(DEFAULT_PORT)
+    ))
+}
+
+/// Lays out a message as a kind byte, a big-endian `u32` payload length and
+/// the payload. A close payload is the `u16` code followed by the reason.
+pub(crate) fn encode_frame(message: Message) -> io::Result<Vec<u8>> {
+    let (kind, payload) = match message {
+        Message::Text(text) => (TEXT, text.into_bytes()),
+        Message::Binary(binary) => (BINARY, binary),
+        Message::Ping(ping) => (PING, ping),
+        Message::Pong(pong) => (PONG, pong),
+        Message::Close(frame) => (
+            CLOSE,
+            frame.map_or_else(Vec::new, |frame| {
+                let mut payload = u16::from(frame.code).to_be_bytes().to_vec();
+                payload.extend_from_slice(frame.reason.as_bytes());
+                payload
+            }),
+        ),
+        Message::Frame(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "raw WebSocket frames can't be sent over tcp",
+            ))
+        }
+    };
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+fn decode_close(payload: &[u8]) -> Option<CloseFrame<'static>> {
+    let code = u16::from_be_bytes(payload.get(..2)?.try_into().ok()?);
+    Some(CloseFrame {
+        code: CloseCode::from(code),
+        reason: String::from_utf8_lossy(&payload[2..]).into_owned().into(),
+    })
+}
+
+/// Reads the next frame, `None` once the peer has closed the connection.
+/// One over `max_size` bytes fails as it would over a WebSocket.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Option<Message>, Error> {
+    let mut kind = [0];
+    if reader.read(&mut kind).await? == 0 {
+        return Ok(None);
+    }
+    let len = reader.read_u32().await? as usize;
+    if len > max_size {
+        return Err(Error::Capacity(CapacityError::MessageTooLong {
+            size: len,
+            max_size,
+        }));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    let message = match kind[0] {
+        TEXT => Message::Text(String::from_utf8(payload).map_err(|_| Error::Utf8)?),
+        BINARY => Message::Binary(payload),
+        PING => Message::Ping(payload),
+        PONG => Message::Pong(payload),
+        CLOSE => Message::Close(decode_close(&payload)),
+        kind => return Err(Error::Protocol(ProtocolError::InvalidOpcode(kind))),
+    };
+    Ok(Some(message))
+}
+
+/// Speaks the `tcp` transport over `stream`, taking frames of up to
+/// `max_size` bytes. Like a WebSocket, the stream half ends after yielding a
+/// close frame.
+pub fn framed<S>(stream: S, max_size: usize) -> (FrameSink, FrameStream)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let sink = sink::unfold(writer, |mut writer, message: Message| async move {
+        writer.write_all(&encode_frame(message)?).await?;
+        Ok::<_, Error>(writer)
+    });
+    let stream = stream::unfold(Some(reader), move |reader| async move {
+        let mut reader = reader?;
+        match read_frame(&mut reader, max_size).await {
+            Ok(Some(message @ Message::Close(_))) => Some((Ok(message), None)),
+            Ok(Some(message)) => Some((Ok(message), Some(reader))),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+    (Box::pin(sink), Box::pin(stream))
+}
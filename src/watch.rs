@@ -0,0 +1,48 @@
+use std::io;
+
+use clap::ValueEnum;
+use clipboard_master::{CallbackResult, ClipboardHandler, Master};
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use serde::Deserialize;
+use tracing::warn;
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    Poll,
+    Event,
+}
+
+struct Handler {
+    sender: UnboundedSender<()>,
+}
+
+impl ClipboardHandler for Handler {
+    fn on_clipboard_change(&mut self) -> CallbackResult {
+        match self.sender.unbounded_send(()) {
+            Ok(_) => CallbackResult::Next,
+            Err(_) => CallbackResult::Stop,
+        }
+    }
+
+    fn on_clipboard_error(&mut self, error: io::Error) -> CallbackResult {
+        CallbackResult::StopWithError(error)
+    }
+}
+
+/// Subscribes to native clipboard change events on a dedicated thread.
+///
+/// The returned stream yields once per change and ends when event
+/// subscription is unsupported or fails, so callers can fall back to polling.
+pub fn watch_clipboard() -> UnboundedReceiver<()> {
+    let (tx, rx) = futures_channel::mpsc::unbounded();
+
+    std::thread::spawn(move || {
+        let result = Master::new(Handler { sender: tx }).and_then(|mut master| master.run());
+        if let Err(err) = result {
+            warn!("watch clipboard error: {:?}", err);
+        }
+    });
+
+    rx
+}